@@ -69,7 +69,10 @@
                             #!${final.runtimeShell}
                             set -euo pipefail
 
-                            if [[ "''${DRY_ACTIVATE:-}" == "1" ]]
+                            if [[ "''${DRY_ACTIVATE:-}" == "1" && "''${BOOT:-}" == "1" ]]
+                            then
+                                ${customSelf.dryBoot or "echo ${final.writeScript "boot" (customSelf.boot or activate)}"}
+                            elif [[ "''${DRY_ACTIVATE:-}" == "1" ]]
                             then
                                 ${customSelf.dryActivate or "echo ${final.writeScript "activate" activate}"}
                             elif [[ "''${BOOT:-}" == "1" ]]
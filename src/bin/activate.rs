@@ -15,6 +15,7 @@ use tokio::time::timeout;
 
 use std::time::Duration;
 
+use std::collections::HashMap;
 use std::env;
 use std::path::{Path, PathBuf};
 
@@ -34,6 +35,9 @@ struct Opts {
     /// Directory to print logs to
     #[clap(long)]
     log_dir: Option<String>,
+    /// Format of the log lines
+    #[clap(long, arg_enum, default_value = "emoji")]
+    log_format: deploy::LogFormat,
 
     #[clap(subcommand)]
     subcmd: SubCommand,
@@ -44,6 +48,9 @@ enum SubCommand {
     Activate(ActivateOpts),
     Wait(WaitOpts),
     Revoke(RevokeOpts),
+    IsCurrent(IsCurrentOpts),
+    DiffClosures(DiffClosuresOpts),
+    VersionJson(VersionJsonOpts),
 }
 
 /// Activate a profile
@@ -71,6 +78,10 @@ struct ActivateOpts {
     #[clap(long)]
     confirm_timeout: u16,
 
+    /// Maximum time the activation script may run before it is killed (and rolled back if auto rollback is enabled), 240 seconds by default
+    #[clap(long)]
+    activation_timeout: Option<u16>,
+
     /// Wait for confirmation after deployment and rollback if not confirmed
     #[clap(long)]
     magic_rollback: bool,
@@ -79,7 +90,7 @@ struct ActivateOpts {
     #[clap(long)]
     auto_rollback: bool,
 
-    /// Show what will be activated on the machines
+    /// Show what will be activated on the machines (combine with --boot to preview a boot activation)
     #[clap(long)]
     dry_activate: bool,
 
@@ -90,6 +101,18 @@ struct ActivateOpts {
     /// Path for any temporary files that may be needed during activation
     #[clap(long)]
     temp_path: PathBuf,
+
+    /// Identifies this deployment in the name of the canary file, shared with `wait` and the confirmation
+    #[clap(long)]
+    lock_id: Option<String>,
+
+    /// How many times to retry a failing activation script (only without auto/magic rollback)
+    #[clap(long, default_value = "0")]
+    activation_retries: u16,
+
+    /// Read environment variables for the activation script as a JSON object from the first line of stdin
+    #[clap(long)]
+    secrets_from_stdin: bool,
 }
 
 /// Wait for profile activation
@@ -102,11 +125,19 @@ struct WaitOpts {
     #[clap(long)]
     temp_path: PathBuf,
 
+    /// Identifies the deployment in the name of the canary file, as given to `activate`
+    #[clap(long)]
+    lock_id: Option<String>,
+
     /// Timeout to wait for activation
     #[clap(long)]
     activation_timeout: Option<u16>,
 }
 
+/// Print the version of activate-rs and of its protocol with deploy as JSON
+#[derive(Clap, Debug)]
+struct VersionJsonOpts {}
+
 /// Revoke profile activation
 #[derive(Clap, Debug)]
 struct RevokeOpts {
@@ -121,6 +152,50 @@ struct RevokeOpts {
     profile_name: Option<String>,
 }
 
+/// Check whether a profile already points at the given closure
+#[derive(Clap, Debug)]
+#[clap(group(
+    clap::ArgGroup::new("profile")
+        .required(true)
+        .multiple(false)
+        .args(&["profile-path","profile-user"])
+))]
+struct IsCurrentOpts {
+    /// The closure to compare the profile against
+    closure: String,
+    /// The profile path to check
+    #[clap(long)]
+    profile_path: Option<String>,
+    /// The profile user if explicit profile path is not specified
+    #[clap(long, requires = "profile-name")]
+    profile_user: Option<String>,
+    /// The profile name
+    #[clap(long, requires = "profile-user")]
+    profile_name: Option<String>,
+}
+
+/// Show how the given closure differs from what a profile currently points at
+#[derive(Clap, Debug)]
+#[clap(group(
+    clap::ArgGroup::new("profile")
+        .required(true)
+        .multiple(false)
+        .args(&["profile-path","profile-user"])
+))]
+struct DiffClosuresOpts {
+    /// The closure to compare the profile with
+    closure: String,
+    /// The profile path to compare
+    #[clap(long)]
+    profile_path: Option<String>,
+    /// The profile user if explicit profile path is not specified
+    #[clap(long, requires = "profile-name")]
+    profile_user: Option<String>,
+    /// The profile name
+    #[clap(long, requires = "profile-user")]
+    profile_name: Option<String>,
+}
+
 #[derive(Error, Debug)]
 pub enum DeactivateError {
     #[error("Failed to execute the rollback command: {0}")]
@@ -141,14 +216,265 @@ pub enum DeactivateError {
     Reactivate(std::io::Error),
     #[error("Command for re-activating the last generation resulted in a bad exit code: {0:?}")]
     ReactivateExit(Option<i32>),
+    #[error("Failed to run command for switching to generation {0}: {1}")]
+    SwitchGen(String, std::io::Error),
+    #[error("Command for switching to generation {0} resulted in a bad exit code: {1:?}")]
+    SwitchGenExit(String, Option<i32>),
+}
+
+/// File next to the profile (so only whoever may change the profile can write it) in which
+/// `activate` records the generation that was current before it and the one it created
+fn make_generation_path(profile_path: &str) -> PathBuf {
+    let profile_path = Path::new(profile_path);
+    let name = profile_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    profile_path
+        .parent()
+        .unwrap_or_else(|| Path::new("/"))
+        .join(format!(".{}-deploy-rs-generation", name))
+}
+
+#[test]
+fn test_make_generation_path() {
+    assert_eq!(
+        make_generation_path("/nix/var/nix/profiles/system"),
+        PathBuf::from("/nix/var/nix/profiles/.system-deploy-rs-generation")
+    );
+}
+
+/// Finds the ID of the current generation in the output of `nix-env --list-generations`
+fn current_generation(generations_list: &str) -> Option<&str> {
+    generations_list
+        .lines()
+        .map(|l| l.split_whitespace().collect::<Vec<_>>())
+        .find(|g| g.last() == Some(&"(current)"))
+        .map(|g| g[0])
+}
+
+#[test]
+fn test_current_generation() {
+    let generations_list = "   1   2024-01-01 10:00:00   \n   2   2024-01-02 11:00:00   (current)\n   3   2024-01-03 12:00:00   \n";
+
+    assert_eq!(current_generation(generations_list), Some("2"));
+    assert_eq!(current_generation("   1   2024-01-01 10:00:00   \n"), None);
+    assert_eq!(current_generation(""), None);
+}
+
+/// Whether a record of the generation before an activation (`previous`) and the one it created
+/// (`activated`) still applies: the activated generation has to be the current one, otherwise the
+/// profile was changed since (e.g. by `nixos-rebuild`), and the previous one has to still exist
+fn record_applies(previous: &str, activated: &str, generations_list: &str) -> bool {
+    current_generation(generations_list) == Some(activated)
+        && generations_list
+            .lines()
+            .any(|l| l.split_whitespace().next() == Some(previous))
+}
+
+#[test]
+fn test_record_applies() {
+    let generations_list = "   1   2024-01-01 10:00:00   \n   2   2024-01-02 11:00:00   \n   3   2024-01-03 12:00:00   (current)\n";
+
+    assert!(record_applies("1", "3", generations_list));
+    // Switched to another generation since
+    assert!(!record_applies("1", "2", generations_list));
+    // The previous generation was deleted
+    assert!(!record_applies("0", "3", generations_list));
+}
+
+async fn list_generations(profile_path: &str) -> Result<String, DeactivateError> {
+    let nix_env_list_generations_out = Command::new("nix-env")
+        .arg("-p")
+        .arg(profile_path)
+        .arg("--list-generations")
+        .output()
+        .await
+        .map_err(DeactivateError::ListGen)?;
+
+    match nix_env_list_generations_out.status.code() {
+        Some(0) => (),
+        a => return Err(DeactivateError::ListGenExit(a)),
+    };
+
+    String::from_utf8(nix_env_list_generations_out.stdout)
+        .map_err(DeactivateError::DecodeListGenUtf8)
+}
+
+/// Records the generation that was current before the activation (`previous`) and the one that
+/// replaced it. Failing to do so isn't fatal, rolling back then falls back to `nix-env --rollback`.
+async fn record_generation(profile_path: &str, previous: Option<String>) {
+    use tokio::io::AsyncWriteExt;
+
+    let generation_path = make_generation_path(profile_path);
+
+    let record = match previous {
+        Some(previous) => match list_generations(profile_path).await {
+            Ok(generations_list) => current_generation(&generations_list)
+                .map(|activated| format!("{} {}\n", previous, activated)),
+            Err(e) => {
+                warn!(
+                    "Could not find the new generation of {}: {}",
+                    profile_path, e
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
+    // An older record must not point at unrelated generations, a fresh profile has none at all
+    let result = match fs::remove_file(&generation_path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    };
+
+    let result = match (result, record) {
+        (Ok(()), Some(record)) => {
+            debug!(
+                "Recording generations `{}` of {}",
+                record.trim(),
+                profile_path
+            );
+            // Never follow a link planted in place of the record
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o644)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&generation_path)
+                .await;
+            match file {
+                Ok(mut file) => file.write_all(record.as_bytes()).await,
+                Err(e) => Err(e),
+            }
+        }
+        (result, _) => result,
+    };
+
+    if let Err(e) = result {
+        warn!(
+            "Could not record the generation of {} in {}: {}",
+            profile_path,
+            generation_path.display(),
+            e
+        );
+    }
+}
+
+/// Reads the generations recorded by `record_generation`, if the record still applies to the profile
+async fn recorded_generation(
+    profile_path: &str,
+) -> Result<Option<(PathBuf, String, String)>, DeactivateError> {
+    use tokio::io::AsyncReadExt;
+
+    let generation_path = make_generation_path(profile_path);
+
+    let mut record = String::new();
+    let read = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&generation_path)
+        .await
+    {
+        Ok(mut file) => file.read_to_string(&mut record).await,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = read {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Could not read {}: {}", generation_path.display(), e);
+        }
+        return Ok(None);
+    }
+
+    let (previous, activated) = match record.split_once(' ') {
+        Some((previous, activated)) => (previous.trim().to_string(), activated.trim().to_string()),
+        None => {
+            warn!(
+                "Ignoring the malformed record {}",
+                generation_path.display()
+            );
+            return Ok(None);
+        }
+    };
+
+    if !record_applies(
+        &previous,
+        &activated,
+        &list_generations(profile_path).await?,
+    ) {
+        debug!(
+            "Ignoring the record of {}, the profile was changed since generation {} was activated",
+            profile_path, activated
+        );
+        return Ok(None);
+    }
+
+    Ok(Some((generation_path, previous, activated)))
+}
+
+/// Finds the ID and date of the generation before the current one in the output of `nix-env --list-generations`
+fn previous_generation(generations_list: &str) -> Option<(&str, String)> {
+    let generations: Vec<Vec<&str>> = generations_list
+        .lines()
+        .map(|l| l.split_whitespace().collect())
+        .filter(|g: &Vec<&str>| !g.is_empty())
+        .collect();
+
+    let current = generations
+        .iter()
+        .position(|g| g.last() == Some(&"(current)"))?;
+    let previous = generations.get(current.checked_sub(1)?)?;
+
+    Some((previous[0], previous[1..].join(" ")))
+}
+
+#[test]
+fn test_previous_generation() {
+    let generations_list = "   1   2024-01-01 10:00:00   \n   2   2024-01-02 11:00:00   \n   3   2024-01-03 12:00:00   (current)\n";
+
+    assert_eq!(
+        previous_generation(generations_list),
+        Some(("2", "2024-01-02 11:00:00".to_string()))
+    );
+    assert_eq!(
+        previous_generation("   1   2024-01-01 10:00:00   (current)\n"),
+        None
+    );
+    assert_eq!(previous_generation(""), None);
 }
 
 pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
     warn!("De-activating due to error");
 
+    if let Some((generation_path, previous, activated)) = recorded_generation(profile_path).await? {
+        return switch_to_generation(profile_path, &generation_path, &previous, &activated).await;
+    }
+
+    // Only informational, the rollback itself is up to `nix-env`
+    if let Ok(output) = Command::new("nix-env")
+        .arg("-p")
+        .arg(profile_path)
+        .arg("--list-generations")
+        .output()
+        .await
+    {
+        match previous_generation(&String::from_utf8_lossy(&output.stdout)) {
+            Some((id, date)) => info!(
+                "Rolling back {} to generation {} from {}",
+                profile_path, id, date
+            ),
+            None => warn!(
+                "Found no generation of {} before the current one to roll back to",
+                profile_path
+            ),
+        }
+    }
+
     let nix_env_rollback_exit_status = Command::new("nix-env")
         .arg("-p")
-        .arg(&profile_path)
+        .arg(profile_path)
         .arg("--rollback")
         .status()
         .await
@@ -161,21 +487,7 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
 
     debug!("Listing generations");
 
-    let nix_env_list_generations_out = Command::new("nix-env")
-        .arg("-p")
-        .arg(&profile_path)
-        .arg("--list-generations")
-        .output()
-        .await
-        .map_err(DeactivateError::ListGen)?;
-
-    match nix_env_list_generations_out.status.code() {
-        Some(0) => (),
-        a => return Err(DeactivateError::ListGenExit(a)),
-    };
-
-    let generations_list = String::from_utf8(nix_env_list_generations_out.stdout)
-        .map_err(DeactivateError::DecodeListGenUtf8)?;
+    let generations_list = list_generations(profile_path).await?;
 
     let last_generation_line = generations_list
         .lines()
@@ -188,27 +500,74 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
         .expect("Expected to get ID from generation entry");
 
     debug!("Removing generation entry {}", last_generation_line);
-    warn!("Removing generation by ID {}", last_generation_id);
+
+    delete_generation(profile_path, last_generation_id).await?;
+
+    reactivate(profile_path).await
+}
+
+/// Rolls back to the generation recorded before the activation, deleting the one the activation created
+async fn switch_to_generation(
+    profile_path: &str,
+    generation_path: &Path,
+    id: &str,
+    activated: &str,
+) -> Result<(), DeactivateError> {
+    info!(
+        "Rolling back {} to the recorded generation {}",
+        profile_path, id
+    );
+
+    let nix_env_switch_generation_exit_status = Command::new("nix-env")
+        .arg("-p")
+        .arg(profile_path)
+        .arg("--switch-generation")
+        .arg(id)
+        .status()
+        .await
+        .map_err(|e| DeactivateError::SwitchGen(id.to_string(), e))?;
+
+    match nix_env_switch_generation_exit_status.code() {
+        Some(0) => (),
+        a => return Err(DeactivateError::SwitchGenExit(id.to_string(), a)),
+    };
+
+    if activated != id {
+        delete_generation(profile_path, activated).await?;
+    }
+
+    // The next rollback needs a new record, this one is used up
+    if let Err(e) = fs::remove_file(generation_path).await {
+        warn!("Could not remove {}: {}", generation_path.display(), e);
+    }
+
+    reactivate(profile_path).await
+}
+
+async fn delete_generation(profile_path: &str, id: &str) -> Result<(), DeactivateError> {
+    warn!("Removing generation by ID {}", id);
 
     let nix_env_delete_generation_exit_status = Command::new("nix-env")
         .arg("-p")
-        .arg(&profile_path)
+        .arg(profile_path)
         .arg("--delete-generations")
-        .arg(last_generation_id)
+        .arg(id)
         .status()
         .await
         .map_err(DeactivateError::DeleteGen)?;
 
     match nix_env_delete_generation_exit_status.code() {
-        Some(0) => (),
-        a => return Err(DeactivateError::DeleteGenExit(a)),
-    };
+        Some(0) => Ok(()),
+        a => Err(DeactivateError::DeleteGenExit(a)),
+    }
+}
 
+async fn reactivate(profile_path: &str) -> Result<(), DeactivateError> {
     info!("Attempting to re-activate the last generation");
 
     let re_activate_exit_status = Command::new(format!("{}/deploy-rs-activate", profile_path))
-        .env("PROFILE", &profile_path)
-        .current_dir(&profile_path)
+        .env("PROFILE", profile_path)
+        .current_dir(profile_path)
         .status()
         .await
         .map_err(DeactivateError::Reactivate)?;
@@ -231,6 +590,42 @@ pub enum ActivationConfirmationError {
     Watcher(#[from] notify::Error),
     #[error("Error waiting for confirmation event: {0}")]
     WaitingError(#[from] DangerZoneError),
+    #[error("None of the temp paths for the activation confirmation file are writable: {0}")]
+    TempPathNotWritable(String),
+}
+
+/// Picks the first of the configured temp path and its fallbacks that is a writable directory
+async fn writable_temp_path(temp_path: &Path) -> Result<PathBuf, ActivationConfirmationError> {
+    let candidates = deploy::temp_path_candidates(temp_path);
+
+    for candidate in &candidates {
+        let probe = candidate.join(format!(".deploy-rs-probe-{}", std::process::id()));
+
+        let writable = fs::create_dir_all(candidate).await.is_ok()
+            && fs::File::create(&probe).await.is_ok()
+            && fs::remove_file(&probe).await.is_ok();
+
+        if writable {
+            if candidate != temp_path {
+                warn!(
+                    "{} is not writable, using {} for the activation confirmation file instead",
+                    temp_path.display(),
+                    candidate.display()
+                );
+            }
+            return Ok(candidate.clone());
+        }
+
+        debug!("{} is not writable", candidate.display());
+    }
+
+    Err(ActivationConfirmationError::TempPathNotWritable(
+        candidates
+            .iter()
+            .map(|c| c.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+    ))
 }
 
 #[derive(Error, Debug)]
@@ -261,8 +656,9 @@ pub async fn activation_confirmation(
     temp_path: PathBuf,
     confirm_timeout: u16,
     closure: String,
+    lock_id: Option<&str>,
 ) -> Result<(), ActivationConfirmationError> {
-    let lock_path = deploy::make_lock_path(&temp_path, &closure);
+    let lock_path = deploy::make_lock_path(&temp_path, &closure, lock_id);
 
     debug!("Ensuring parent directory exists for canary file");
 
@@ -305,7 +701,7 @@ pub async fn activation_confirmation(
 
     danger_zone(done, confirm_timeout)
         .await
-        .map_err(|err| ActivationConfirmationError::WaitingError(err))
+        .map_err(ActivationConfirmationError::WaitingError)
 }
 
 #[derive(Error, Debug)]
@@ -314,9 +710,20 @@ pub enum WaitError {
     Watcher(#[from] notify::Error),
     #[error("Error waiting for activation: {0}")]
     Waiting(#[from] DangerZoneError),
+    #[error("{0}")]
+    TempPath(#[from] ActivationConfirmationError),
+    #[error("Failed to create the directory of the canary file: {0}")]
+    CreateDir(std::io::Error),
 }
-pub async fn wait(temp_path: PathBuf, closure: String, activation_timeout: Option<u16>) -> Result<(), WaitError> {
-    let lock_path = deploy::make_lock_path(&temp_path, &closure);
+pub async fn wait(
+    temp_path: PathBuf,
+    closure: String,
+    lock_id: Option<&str>,
+    activation_timeout: Option<u16>,
+) -> Result<(), WaitError> {
+    // The same directory `activate` picks, as both run as the profile user
+    let temp_path = writable_temp_path(&temp_path).await?;
+    let lock_path = deploy::make_lock_path(&temp_path, &closure, lock_id);
 
     let (created, done) = mpsc::channel(1);
 
@@ -347,21 +754,132 @@ pub async fn wait(temp_path: PathBuf, closure: String, activation_timeout: Optio
         })?
     };
 
-    watcher.watch(&temp_path, RecursiveMode::NonRecursive)?;
+    // The directory the canary is created in has to exist to be watched
+    let lock_dir = lock_path.parent().unwrap_or(&temp_path);
+    fs::create_dir_all(lock_dir)
+        .await
+        .map_err(WaitError::CreateDir)?;
+
+    watcher.watch(lock_dir, RecursiveMode::NonRecursive)?;
 
     // Avoid a potential race condition by checking for existence after watcher creation
     if fs::metadata(&lock_path).await.is_ok() {
-        watcher.unwatch(&temp_path)?;
-        return Ok(());
-    }
+        watcher.unwatch(lock_dir)?;
+    } else {
+        danger_zone(
+            done,
+            activation_timeout.unwrap_or(deploy::DEFAULT_ACTIVATION_TIMEOUT),
+        )
+        .await?;
 
-    danger_zone(done, activation_timeout.unwrap_or(240)).await?;
+        info!("Found canary file, done waiting!");
+    }
 
-    info!("Found canary file, done waiting!");
+    // Tells `deploy` which file to remove to confirm, as the temp path may have been a fallback
+    println!("{}", lock_path.display());
 
     Ok(())
 }
 
+#[tokio::test]
+async fn test_wait_creates_missing_temp_path() {
+    let temp_path = env::temp_dir()
+        .join(format!("deploy-rs-test-wait-{}", std::process::id()))
+        .join("missing");
+    let closure = "/nix/store/blah-system".to_string();
+    let lock_path = deploy::make_lock_path(&temp_path, &closure, Some("system-0123abcd"));
+
+    // Neither the temp path nor its parent exist before waiting
+    let waiter = tokio::spawn(wait(
+        temp_path.clone(),
+        closure,
+        Some("system-0123abcd"),
+        Some(10),
+    ));
+
+    while fs::metadata(&temp_path).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // Whether the watch is set up yet or not, the waiter finds the canary: it checks for an
+    // existing one once watching
+    fs::File::create(&lock_path).await.unwrap();
+
+    let waited = waiter.await.unwrap();
+    fs::remove_dir_all(temp_path.parent().unwrap())
+        .await
+        .unwrap();
+    assert!(waited.is_ok(), "{:?}", waited);
+}
+
+/// Environment telling `deploy-rs-activate` which kind of activation to perform.
+///
+/// Both variables are set together for a dry activation of the boot path.
+fn activation_env(dry_activate: bool, boot: bool) -> [(&'static str, &'static str); 2] {
+    [
+        ("DRY_ACTIVATE", if dry_activate { "1" } else { "0" }),
+        ("BOOT", if boot { "1" } else { "0" }),
+    ]
+}
+
+#[test]
+fn test_activation_env() {
+    assert_eq!(
+        activation_env(false, false),
+        [("DRY_ACTIVATE", "0"), ("BOOT", "0")]
+    );
+    assert_eq!(
+        activation_env(true, false),
+        [("DRY_ACTIVATE", "1"), ("BOOT", "0")]
+    );
+    assert_eq!(
+        activation_env(false, true),
+        [("DRY_ACTIVATE", "0"), ("BOOT", "1")]
+    );
+    assert_eq!(
+        activation_env(true, true),
+        [("DRY_ACTIVATE", "1"), ("BOOT", "1")]
+    );
+}
+
+/// Runs `deploy-rs-activate`, killing it if it doesn't finish within `activation_timeout` seconds
+async fn run_activation_script(
+    activation_location: &str,
+    dry_activate: bool,
+    boot: bool,
+    activation_timeout: Option<u16>,
+    activation_secrets: &HashMap<String, String>,
+) -> Result<std::process::ExitStatus, ActivateError> {
+    let mut activate_child = Command::new(format!("{}/deploy-rs-activate", activation_location))
+        .env("PROFILE", activation_location)
+        .envs(activation_env(dry_activate, boot).iter().copied())
+        .envs(activation_secrets)
+        .current_dir(activation_location)
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(ActivateError::RunActivate)?;
+
+    // `deploy` leaves out the default
+    let activation_timeout = activation_timeout.unwrap_or(deploy::DEFAULT_ACTIVATION_TIMEOUT);
+
+    match timeout(
+        Duration::from_secs(activation_timeout.into()),
+        activate_child.wait(),
+    )
+    .await
+    {
+        Ok(status) => status.map_err(ActivateError::RunActivate),
+        Err(_) => {
+            // Only the script itself is killed, not processes it may have started in the background
+            activate_child
+                .kill()
+                .await
+                .map_err(ActivateError::RunActivate)?;
+
+            Err(ActivateError::RunActivateTimeout(activation_timeout))
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ActivateError {
     #[error("Failed to execute the command for setting profile: {0}")]
@@ -373,6 +891,13 @@ pub enum ActivateError {
     RunActivate(std::io::Error),
     #[error("The activation script resulted in a bad exit code: {0:?}")]
     RunActivateExit(Option<i32>),
+    #[error("The activation script didn't finish within {0} seconds and was killed")]
+    RunActivateTimeout(u16),
+
+    #[error("Failed to read the activation secrets from stdin: {0}")]
+    ReadSecrets(std::io::Error),
+    #[error("Failed to parse the activation secrets: {0}")]
+    ParseSecrets(serde_json::Error),
 
     #[error("There was an error de-activating after an error was encountered: {0}")]
     Deactivate(#[from] DeactivateError),
@@ -381,17 +906,54 @@ pub enum ActivateError {
     ActivationConfirmation(#[from] ActivationConfirmationError),
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn activate(
     profile_path: String,
     closure: String,
     auto_rollback: bool,
     temp_path: PathBuf,
+    lock_id: Option<&str>,
     confirm_timeout: u16,
+    activation_timeout: Option<u16>,
     magic_rollback: bool,
     dry_activate: bool,
     boot: bool,
+    activation_retries: u16,
+    secrets_from_stdin: bool,
 ) -> Result<(), ActivateError> {
+    // Read before anything else, the values are never logged
+    let activation_secrets: HashMap<String, String> = if secrets_from_stdin {
+        let mut line = String::new();
+        std::io::stdin()
+            .read_line(&mut line)
+            .map_err(ActivateError::ReadSecrets)?;
+
+        serde_json::from_str(&line).map_err(ActivateError::ParseSecrets)?
+    } else {
+        HashMap::new()
+    };
+
+    // Fail before touching the profile if the confirmation file can't be created afterwards
+    let temp_path = if magic_rollback && !boot && !dry_activate {
+        writable_temp_path(&temp_path)
+            .await
+            .map_err(ActivateError::ActivationConfirmation)?
+    } else {
+        temp_path
+    };
+
     if !dry_activate {
+        let previous_generation = match list_generations(&profile_path).await {
+            Ok(generations_list) => current_generation(&generations_list).map(str::to_string),
+            Err(e) => {
+                warn!(
+                    "Could not find the current generation of {}: {}",
+                    profile_path, e
+                );
+                None
+            }
+        };
+
         info!("Activating profile");
         let nix_env_set_exit_status = Command::new("nix-env")
             .arg("-p")
@@ -403,13 +965,12 @@ pub async fn activate(
             .map_err(ActivateError::SetProfile)?;
         match nix_env_set_exit_status.code() {
             Some(0) => (),
-            a => {
-                if auto_rollback && !dry_activate {
-                    deactivate(&profile_path).await?;
-                }
-                return Err(ActivateError::SetProfileExit(a));
-            }
+            // No new generation has been created if setting the profile failed, so there is
+            // nothing to roll back: `deactivate` would remove the current (good) generation
+            a => return Err(ActivateError::SetProfileExit(a)),
         };
+
+        record_generation(&profile_path, previous_generation).await;
     }
 
     debug!("Running activation script");
@@ -420,22 +981,48 @@ pub async fn activate(
         &profile_path
     };
 
-    let activate_status = match Command::new(format!("{}/deploy-rs-activate", activation_location))
-        .env("PROFILE", activation_location)
-        .env("DRY_ACTIVATE", if dry_activate { "1" } else { "0" })
-        .env("BOOT", if boot { "1" } else { "0" })
-        .current_dir(activation_location)
-        .status()
+    // Retrying makes no sense if the failed activation has already been rolled back
+    let activation_retries = if activation_retries > 0 && (auto_rollback || magic_rollback) {
+        warn!(
+            "Ignoring activation retries, they are only supported without auto and magic rollback"
+        );
+        0
+    } else {
+        activation_retries
+    };
+
+    let mut attempt = 0;
+
+    let activate_status = loop {
+        let activate_status = match run_activation_script(
+            activation_location,
+            dry_activate,
+            boot,
+            activation_timeout,
+            &activation_secrets,
+        )
         .await
-        .map_err(ActivateError::RunActivate)
-    {
-        Ok(x) => x,
-        Err(e) => {
-            if auto_rollback && !dry_activate {
-                deactivate(&profile_path).await?;
+        {
+            Ok(x) => x,
+            Err(e) => {
+                if auto_rollback && !dry_activate {
+                    deactivate(&profile_path).await?;
+                }
+                return Err(e);
             }
-            return Err(e);
+        };
+
+        if dry_activate || activate_status.success() || attempt >= activation_retries {
+            break activate_status;
         }
+
+        attempt += 1;
+        warn!(
+            "The activation script resulted in a bad exit code: {:?}, retrying ({}/{})",
+            activate_status.code(),
+            attempt,
+            activation_retries
+        );
     };
 
     if !dry_activate {
@@ -455,7 +1042,9 @@ pub async fn activate(
 
         if magic_rollback && !boot {
             info!("Magic rollback is enabled, setting up confirmation hook...");
-            if let Err(err) = activation_confirmation(temp_path, confirm_timeout, closure).await {
+            if let Err(err) =
+                activation_confirmation(temp_path, confirm_timeout, closure, lock_id).await
+            {
                 deactivate(&profile_path).await?;
                 return Err(ActivateError::ActivationConfirmation(err));
             }
@@ -470,6 +1059,73 @@ async fn revoke(profile_path: String) -> Result<(), DeactivateError> {
     Ok(())
 }
 
+#[derive(Error, Debug)]
+pub enum IsCurrentError {
+    #[error("Failed to resolve the profile path: {0}")]
+    ResolveProfile(std::io::Error),
+    #[error("Failed to resolve the closure path: {0}")]
+    ResolveClosure(std::io::Error),
+}
+
+async fn is_current(profile_path: String, closure: String) -> Result<(), IsCurrentError> {
+    let current = match fs::canonicalize(&profile_path).await {
+        Ok(target) => {
+            target
+                == fs::canonicalize(&closure)
+                    .await
+                    .map_err(IsCurrentError::ResolveClosure)?
+        }
+        // The profile doesn't exist yet, so it can't point anywhere
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => return Err(IsCurrentError::ResolveProfile(e)),
+    };
+
+    debug!(
+        "Profile {} points at {}: {}",
+        profile_path, closure, current
+    );
+
+    // The answer is printed to stdout, logs go to stderr
+    println!("{}", current);
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum DiffClosuresError {
+    #[error("Failed to resolve the profile path: {0}")]
+    ResolveProfile(std::io::Error),
+    #[error("Failed to run the diff-closures command: {0}")]
+    DiffClosures(std::io::Error),
+    #[error("The diff-closures command resulted in a bad exit code: {0:?}")]
+    DiffClosuresExit(Option<i32>),
+}
+
+async fn diff_closures(profile_path: String, closure: String) -> Result<(), DiffClosuresError> {
+    let current = fs::canonicalize(&profile_path)
+        .await
+        .map_err(DiffClosuresError::ResolveProfile)?;
+
+    debug!("Profile {} points at {}", profile_path, current.display());
+
+    // The differences go to stdout, as the answer
+    let diff_closures_exit_status = Command::new("nix")
+        .arg("--extra-experimental-features")
+        .arg("nix-command")
+        .arg("store")
+        .arg("diff-closures")
+        .arg(&current)
+        .arg(&closure)
+        .status()
+        .await
+        .map_err(DiffClosuresError::DiffClosures)?;
+
+    match diff_closures_exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(DiffClosuresError::DiffClosuresExit(a)),
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum GetProfilePathError {
     #[error("Failed to deduce HOME directory for user {0}")]
@@ -511,9 +1167,7 @@ fn get_profile_path(
                         // using 'dirs::state_dir()' directly.
                         let state_dir = env::var("XDG_STATE_HOME").or_else(|_| {
                             dirs::home_dir()
-                                .map(|h| {
-                                    format!("{}/.local/state", h.as_path().display().to_string())
-                                })
+                                .map(|h| format!("{}/.local/state", h.as_path().display()))
                                 .ok_or(GetProfilePathError::NoUserHome(profile_user))
                         })?;
                         Ok(format!("{}/nix/profiles/{}", state_dir, profile_name))
@@ -528,7 +1182,7 @@ fn get_profile_path(
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Ensure that this process stays alive after the SSH connection dies
-    let mut signals = Signals::new(&[SIGHUP])?;
+    let mut signals = Signals::new([SIGHUP])?;
     std::thread::spawn(move || {
         for _ in signals.forever() {
             println!("Received SIGHUP - ignoring...");
@@ -537,6 +1191,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let opts: Opts = Opts::parse();
 
+    // Only the JSON may be printed here, so this doesn't even set up logging
+    if let SubCommand::VersionJson(_) = opts.subcmd {
+        println!(
+            "{}",
+            serde_json::to_string(&deploy::ActivateRsVersion::current())?
+        );
+        return Ok(());
+    }
+
     deploy::init_logger(
         opts.debug_logs,
         opts.log_dir.as_deref(),
@@ -544,38 +1207,100 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             SubCommand::Activate(_) => deploy::LoggerType::Activate,
             SubCommand::Wait(_) => deploy::LoggerType::Wait,
             SubCommand::Revoke(_) => deploy::LoggerType::Revoke,
+            SubCommand::IsCurrent(_) => deploy::LoggerType::IsCurrent,
+            SubCommand::DiffClosures(_) => deploy::LoggerType::DiffClosures,
+            SubCommand::VersionJson(_) => unreachable!(),
         },
+        opts.log_format,
     )?;
 
+    // The node isn't known here, JSON lines only tell which profile they are about
+    let log_profile = match &opts.subcmd {
+        SubCommand::Activate(o) => o.profile_name.clone().or_else(|| o.profile_path.clone()),
+        SubCommand::Revoke(o) => o.profile_name.clone().or_else(|| o.profile_path.clone()),
+        SubCommand::IsCurrent(o) => o.profile_name.clone().or_else(|| o.profile_path.clone()),
+        SubCommand::DiffClosures(o) => o.profile_name.clone().or_else(|| o.profile_path.clone()),
+        SubCommand::Wait(_) | SubCommand::VersionJson(_) => None,
+    };
+
     let r = match opts.subcmd {
-        SubCommand::Activate(activate_opts) => activate(
-            get_profile_path(
-                activate_opts.profile_path,
-                activate_opts.profile_user,
-                activate_opts.profile_name,
-            )?,
-            activate_opts.closure,
-            activate_opts.auto_rollback,
-            activate_opts.temp_path,
-            activate_opts.confirm_timeout,
-            activate_opts.magic_rollback,
-            activate_opts.dry_activate,
-            activate_opts.boot,
+        SubCommand::Activate(activate_opts) => deploy::with_log_context(
+            None,
+            log_profile.as_deref(),
+            activate(
+                get_profile_path(
+                    activate_opts.profile_path,
+                    activate_opts.profile_user,
+                    activate_opts.profile_name,
+                )?,
+                activate_opts.closure,
+                activate_opts.auto_rollback,
+                activate_opts.temp_path,
+                activate_opts.lock_id.as_deref(),
+                activate_opts.confirm_timeout,
+                activate_opts.activation_timeout,
+                activate_opts.magic_rollback,
+                activate_opts.dry_activate,
+                activate_opts.boot,
+                activate_opts.activation_retries,
+                activate_opts.secrets_from_stdin,
+            ),
         )
         .await
         .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
 
-        SubCommand::Wait(wait_opts) => wait(wait_opts.temp_path, wait_opts.closure, wait_opts.activation_timeout)
-            .await
-            .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+        SubCommand::Wait(wait_opts) => wait(
+            wait_opts.temp_path,
+            wait_opts.closure,
+            wait_opts.lock_id.as_deref(),
+            wait_opts.activation_timeout,
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::Revoke(revoke_opts) => deploy::with_log_context(
+            None,
+            log_profile.as_deref(),
+            revoke(get_profile_path(
+                revoke_opts.profile_path,
+                revoke_opts.profile_user,
+                revoke_opts.profile_name,
+            )?),
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::IsCurrent(is_current_opts) => deploy::with_log_context(
+            None,
+            log_profile.as_deref(),
+            is_current(
+                get_profile_path(
+                    is_current_opts.profile_path,
+                    is_current_opts.profile_user,
+                    is_current_opts.profile_name,
+                )?,
+                is_current_opts.closure,
+            ),
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
 
-        SubCommand::Revoke(revoke_opts) => revoke(get_profile_path(
-            revoke_opts.profile_path,
-            revoke_opts.profile_user,
-            revoke_opts.profile_name,
-        )?)
+        SubCommand::DiffClosures(diff_closures_opts) => deploy::with_log_context(
+            None,
+            log_profile.as_deref(),
+            diff_closures(
+                get_profile_path(
+                    diff_closures_opts.profile_path,
+                    diff_closures_opts.profile_user,
+                    diff_closures_opts.profile_name,
+                )?,
+                diff_closures_opts.closure,
+            ),
+        )
         .await
         .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::VersionJson(_) => unreachable!(),
     };
 
     match r {
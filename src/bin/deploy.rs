@@ -3,16 +3,19 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
+use clap::Clap;
 use deploy::cli;
-use log::error;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    match cli::run(None).await {
+    let opts = cli::Opts::parse();
+    let output_format = opts.output_format();
+
+    match cli::run_opts(opts).await {
         Ok(()) => (),
         Err(err) => {
-            error!("{}", err);
-            std::process::exit(1);
+            cli::report_error(&err, output_format);
+            std::process::exit(err.exit_code());
         }
     }
 
@@ -3,39 +3,99 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{stdin, stdout, Write};
 
-use clap::{ArgMatches, Clap, FromArgMatches};
+use clap::{ArgEnum, ArgMatches, Clap, FromArgMatches};
 
 use crate as deploy;
 
 use self::deploy::{DeployFlake, ParseFlakeError};
-use futures_util::stream::{StreamExt, TryStreamExt};
+use futures_util::stream::StreamExt;
 use log::{debug, error, info, warn};
 use serde::Serialize;
-use std::path::PathBuf;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::process::Command;
 
+#[derive(ArgEnum, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Human,
+    Json,
+}
+
 /// Simple Rust rewrite of a simple Nix Flake deployment tool
-#[derive(Clap, Debug, Clone)]
+#[derive(Clap, Serialize, Debug, Clone)]
 #[clap(version = "1.0", author = "Serokell <https://serokell.io/>")]
 pub struct Opts {
     /// The flake to deploy
     #[clap(group = "deploy")]
     target: Option<String>,
 
-    /// A list of flakes to deploy alternatively
+    /// A list of flakes to deploy alternatively, `-` reads them from stdin like --targets-file
     #[clap(long, group = "deploy")]
     targets: Option<Vec<String>>,
+    /// Read flakes to deploy from a file (`-` for stdin), one per line or as a JSON list, in addition to --targets
+    #[clap(long, conflicts_with_all = &["target", "closure"])]
+    targets_file: Option<PathBuf>,
+    /// Deploy this pre-built store path, an output of `deploy-rs.lib.<system>.activate`, as the `system` profile of the node given as target, skipping evaluation and build (e.g. `deploy --closure /nix/store/...-activatable-nixos-system-my-node my-node --hostname my-node.example.com`)
+    #[clap(long, conflicts_with_all = &["targets", "remote-build", "require-tag"])]
+    closure: Option<String>,
+    /// Per-target overrides when using --targets, e.g. 'target=.#node sshUser=admin user=root tempPath=/run/deploy-rs'
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    target_opts: Vec<String>,
+    /// Collect garbage on the targets once all profiles are deployed and confirmed (unless disabled with `gcAfter = false`)
+    #[clap(long)]
+    gc_after: bool,
+    /// Keep building as much of a profile as possible when one of its derivations fails (only passed to builds, not evaluation)
+    #[clap(long)]
+    keep_going: bool,
+    /// Activate closures that were already copied to the nodes (e.g. by a deployment whose activation failed) without building or copying them again
+    #[clap(long)]
+    skip_push: bool,
+    /// Roll the profiles of the target back to their previous generation instead of deploying, without building or copying anything
+    #[clap(long, conflicts_with_all = &["closure", "skip-push", "dry-activate", "boot"])]
+    rollback: bool,
+    /// When interrupted with Ctrl-C, roll back the profiles that were already deployed instead of keeping them
+    #[clap(long)]
+    rollback_on_interrupt: bool,
+    /// How many nodes to copy to and activate at the same time (profiles of one node are always deployed in order)
+    #[clap(long, default_value = "1", parse(try_from_str = parse_parallel))]
+    parallel: usize,
+    /// Re-run a failing build or copy once with verbose logging and include its output in the error
+    #[clap(long)]
+    auto_verbose_on_failure: bool,
+    /// Activate the profiles of each node in the reverse of their usual order (e.g. for draining), not possible for profiles using `dependsOn`
+    #[clap(long)]
+    reverse_profiles: bool,
+    /// Show a desktop notification when the deployment finishes
+    #[clap(long)]
+    notify: bool,
+    /// Abort the deployment if building, copying, activating, confirming and the post-deploy steps take longer than this (e.g. 90s, 15m, 1h; plain numbers are seconds)
+    #[clap(long, parse(try_from_str = parse_time_limit))]
+    time_limit: Option<Duration>,
+    /// Only deploy these nodes of the targets, e.g. `--nodes web,db`
+    #[clap(long, use_delimiter = true)]
+    nodes: Vec<String>,
+    /// Node to leave out when deploying all nodes of a flake (can be given multiple times)
+    #[clap(long, multiple_occurrences = true, number_of_values = 1)]
+    exclude_node: Vec<String>,
     /// Check signatures when using `nix copy`
     #[clap(short, long)]
     checksigs: bool,
     /// Use the interactive prompt before deployment
     #[clap(short, long)]
     interactive: bool,
+    /// Show the interactive prompt, but answer it with "yes" right away (the answer is logged)
+    #[clap(long, conflicts_with = "interactive")]
+    yes: bool,
+    /// Allow impure evaluation of the flakes (e.g. reading environment variables), for their evaluation, checks and builds
+    #[clap(long)]
+    impure: bool,
     /// Extra arguments to be passed to nix build
     extra_build_args: Vec<String>,
 
@@ -45,8 +105,21 @@ pub struct Opts {
     /// Directory to print logs to (including the background activation process)
     #[clap(long)]
     log_dir: Option<String>,
+    /// Format of the log lines, `json` prints one object per line for log aggregators
+    #[clap(long, arg_enum, default_value = "emoji")]
+    log_format: deploy::LogFormat,
+
+    /// Nix binary (or wrapper) to run instead of `nix` from the PATH, also read from $DEPLOY_NIX
+    #[clap(long)]
+    nix_bin: Option<String>,
+    /// SSH binary to run instead of `ssh` from the PATH, also read from $DEPLOY_SSH
+    #[clap(long)]
+    ssh_bin: Option<String>,
+    /// Pass `-v` to every Nix command run locally (evaluation, builds, copies and the queries about them), also enabled by $DEPLOY_NIX_VERBOSE
+    #[clap(long)]
+    verbose_nix: bool,
 
-    /// Keep the build outputs of each built profile
+    /// Keep the build outputs of each built profile (remotely built ones are copied back for this)
     #[clap(short, long)]
     keep_result: bool,
     /// Location to keep outputs from built profiles in
@@ -56,6 +129,9 @@ pub struct Opts {
     /// Skip the automatic pre-build checks
     #[clap(short, long)]
     skip_checks: bool,
+    /// Run the checks of all systems, not only the ones of the system deploy runs on (for nodes of a different system)
+    #[clap(long)]
+    check_all_systems: bool,
 
     /// Build on remote host
     #[clap(long)]
@@ -67,9 +143,27 @@ pub struct Opts {
     /// Override the profile user with the given value
     #[clap(long)]
     profile_user: Option<String>,
-    /// Override the SSH options used
+    /// Override the SSH options used, split like a shell would (quote values containing spaces, e.g. `-o "ProxyCommand=ssh bastion nc %h %p"`)
     #[clap(long, allow_hyphen_values = true)]
     ssh_opts: Option<String>,
+    /// Timeout in seconds for establishing SSH connections, applies to every phase using SSH (copying, remote building, activation and confirmation)
+    #[clap(long)]
+    ssh_connect_timeout: Option<u16>,
+    /// Seconds between SSH keepalive messages, keeping long activations and the confirmation wait alive on idle connections (0 disables them, default 30)
+    #[clap(long)]
+    ssh_keepalive: Option<u16>,
+    /// Connect through an existing SSH control master socket at the given path instead of opening new connections
+    #[clap(long)]
+    ssh_control_path: Option<PathBuf>,
+    /// Open a single SSH connection per target and reuse it for copying, activation, confirmation and rollback
+    #[clap(long, conflicts_with = "ssh-control-path")]
+    ssh_multiplex: bool,
+    /// Seconds an idle connection of --ssh-multiplex stays open, instead of until the deployment finishes
+    #[clap(long, requires = "ssh-multiplex", parse(try_from_str = parse_control_persist))]
+    ssh_control_persist: Option<u16>,
+    /// Keep the SSH connections to the targets open if the deployment fails, for debugging over the same connections (implies --ssh-multiplex)
+    #[clap(long, conflicts_with = "ssh-control-path")]
+    keep_ssh_on_failure: bool,
     /// Override if the connecting to the target node should be considered fast
     #[clap(long)]
     fast_connection: Option<bool>,
@@ -88,31 +182,109 @@ pub struct Opts {
     /// How long we should wait for profile activation
     #[clap(long)]
     activation_timeout: Option<u16>,
+    /// How long building a profile may take before the build is killed
+    #[clap(long)]
+    build_timeout: Option<u16>,
+    /// How many times to retry a failing activation script (requires auto-rollback and magic-rollback to be disabled)
+    #[clap(long)]
+    activation_retries: Option<u16>,
+    /// How many times to retry a failing `nix copy` to the target, e.g. on transient network errors
+    #[clap(long)]
+    copy_retries: Option<u16>,
+    /// Seconds to wait before the first copy retry, doubling with every further retry (default 2)
+    #[clap(long)]
+    copy_retry_delay: Option<u16>,
+    /// Confirm magic-rollback by running this local command instead of over SSH, it has to remove the lock file given in `$DEPLOY_RS_LOCK_PATH` on the target
+    #[clap(long)]
+    confirm_via: Option<String>,
     /// Where to store temporary files (only used by magic-rollback)
     #[clap(long)]
     temp_path: Option<PathBuf>,
-    /// Show what will be activated on the machines
+    /// Show what will be activated on the machines (combine with --boot to preview a boot activation)
     #[clap(long)]
     dry_activate: bool,
+    /// With --dry-activate, show how the closure differs from what the profile currently points at on the node
+    #[clap(long, requires = "dry-activate")]
+    diff: bool,
     /// Don't activate, but update the boot loader to boot into the new profile
     #[clap(long)]
     boot: bool,
     /// Revoke all previously succeeded deploys when deploying multiple profiles
     #[clap(long)]
     rollback_succeeded: Option<bool>,
+    /// Activate all profiles first, then confirm them all after a single prompt; if that isn't confirmed in time, every magic-rollback profile rolls back
+    #[clap(long, conflicts_with_all = &["dry-activate", "boot"])]
+    copy_first_then_confirm_all: bool,
     /// Which sudo command to use. Must accept at least two arguments: user name to execute commands as and the rest is the command to execute
     #[clap(long)]
     sudo: Option<String>,
     /// Prompt for sudo password during activation.
     #[clap(long)]
     interactive_sudo: Option<bool>,
+    /// Prompt for a sudo password once before deploying, and use it on every node that needs sudo
+    #[clap(long)]
+    ask_sudo_password: bool,
+    /// Don't check that profiles with a `system` are deployed to nodes of that system, e.g. for nodes emulating it
+    #[clap(long)]
+    no_system_check: bool,
+    /// Check that every node is reachable over SSH before building, giving up on connecting after this many seconds
+    #[clap(long)]
+    timeout_connect: Option<u16>,
+    /// Write the raw JSON produced by evaluating the deploy output to the given file (for debugging), numbered before the extension if several evaluations are needed
+    #[clap(long)]
+    dump_nix_eval: Option<PathBuf>,
+    /// Evaluate every target separately, filtered down to its node and profile, instead of evaluating each repo once (for flakes too big to evaluate as a whole)
+    #[clap(long)]
+    eval_per_target: bool,
+    /// Write a JSON summary of the deployment (build, copy, activation, confirmation and rollback of every profile) to the given file
+    #[clap(long)]
+    report_file: Option<PathBuf>,
+    /// Write the effective configuration of the deployment (options, flake data, resolved profiles) as JSON to the given file
+    #[clap(long)]
+    save_manifest: Option<PathBuf>,
+    /// Skip activation of profiles that were already present on the node and are already active
+    #[clap(long)]
+    activate_only_if_push_changed: bool,
+    /// Print the ordered list of actions the deployment would perform, without performing them
+    #[clap(long)]
+    plan: bool,
+    /// Print the profiles that would be deployed and exit, without building or deploying anything
+    #[clap(long)]
+    plan_only: bool,
+    /// Format of the profiles printed before deploying, human (a TOML table) or JSON written to stdout instead of the log
+    #[clap(long, arg_enum, default_value = "human")]
+    plan_format: OutputFormat,
+    /// Refuse to deploy unless the flake's HEAD commit carries a git tag matching the given pattern
+    #[clap(long)]
+    require_tag: Option<String>,
+    /// Fail the deployment if evaluating the deploy output produces any warnings
+    #[clap(long)]
+    abort_on_warnings: bool,
+    /// Only evaluate and validate the deploy configuration, without building or connecting to any node
+    #[clap(long)]
+    check_config: bool,
+    /// List the nodes and profiles of the targets with their hostname, users and path, without deploying anything
+    #[clap(long, conflicts_with_all = &["interactive", "yes", "plan", "plan-only", "check-config", "build-only", "rollback"])]
+    list: bool,
+    /// Only evaluate and build the profiles locally, without connecting to any node (e.g. to validate changes on CI)
+    #[clap(long, conflicts_with_all = &["closure", "skip-push", "rollback", "check-config", "plan-only"])]
+    build_only: bool,
+    /// Format of the error reported on failure
+    #[clap(long, arg_enum, default_value = "human")]
+    output_format: OutputFormat,
+}
+
+impl Opts {
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format
+    }
 }
 
 /// Returns if the available Nix installation supports flakes
 async fn test_flake_support() -> Result<bool, std::io::Error> {
     debug!("Checking for flake support");
 
-    Ok(Command::new("nix")
+    Ok(deploy::nix_command("nix")
         .arg("eval")
         .arg("--expr")
         .arg("builtins.getFlake")
@@ -136,20 +308,31 @@ async fn check_deployment(
     supports_flakes: bool,
     repo: &str,
     extra_build_args: &[String],
+    all_systems: bool,
 ) -> Result<(), CheckDeploymentError> {
     info!("Running checks for flake in {}", repo);
 
     let mut check_command = match supports_flakes {
-        true => Command::new("nix"),
-        false => Command::new("nix-build"),
+        true => deploy::nix_command("nix"),
+        false => deploy::nix_command("nix-build"),
     };
 
     if supports_flakes {
         check_command.arg("flake").arg("check").arg(repo);
+
+        // By default checks of systems other than the current one are omitted, but those may be
+        // exactly the ones of the nodes being deployed
+        if all_systems {
+            check_command.arg("--all-systems");
+        }
     } else {
+        if all_systems {
+            warn!("Checking all systems is only supported with flakes, checking the current system only");
+        }
+
         check_command.arg("-E")
                 .arg("--no-out-link")
-                .arg(format!("let r = import {}/.; x = (if builtins.isFunction r then (r {{}}) else r); in if x ? checks then x.checks.${{builtins.currentSystem}} else {{}}", repo));
+                .arg(format!("let r = import {}; x = (if builtins.isFunction r then (r {{}}) else r); in if x ? checks then x.checks.${{builtins.currentSystem}} else {{}}", nix_import_source(repo)));
     }
 
     check_command.args(extra_build_args);
@@ -164,6 +347,139 @@ async fn check_deployment(
     Ok(())
 }
 
+#[derive(Error, Debug)]
+pub enum ClosureError {
+    #[error("--closure needs the name of the node to deploy to as target")]
+    NoNode,
+    #[error("`{0}` is not a path in the Nix store")]
+    NotStorePath(String),
+    #[error("`{0}` doesn't exist, copy the closure to this machine first")]
+    Missing(String),
+    #[error("`{0}` has no deploy-rs-activate or activate-rs, pass an output of `deploy-rs.lib.<system>.activate` instead")]
+    NotActivatable(String),
+    #[error("Failed to construct the deployment data: {0}")]
+    Data(#[from] serde_json::Error),
+}
+
+/// Makes the deployment data of a single `system` profile deploying a pre-built closure,
+/// with everything else coming from the command line
+fn closure_deployment_data(
+    closure: &str,
+    node_name: &str,
+    hostname: Option<&str>,
+) -> Result<deploy::data::Data, ClosureError> {
+    let store_dir = std::env::var("NIX_STORE_DIR").unwrap_or_else(|_| "/nix/store".to_string());
+
+    if !Path::new(closure).starts_with(&store_dir) || Path::new(closure) == Path::new(&store_dir) {
+        return Err(ClosureError::NotStorePath(closure.to_string()));
+    }
+
+    if !Path::new(closure).exists() {
+        return Err(ClosureError::Missing(closure.to_string()));
+    }
+
+    // Fails here instead of after copying it to the node
+    if !Path::new(closure).join("deploy-rs-activate").exists()
+        || !Path::new(closure).join("activate-rs").exists()
+    {
+        return Err(ClosureError::NotActivatable(closure.to_string()));
+    }
+
+    Ok(serde_json::from_value(serde_json::json!({
+        "nodes": {
+            node_name: {
+                "hostname": hostname.unwrap_or(node_name),
+                "profiles": {
+                    "system": { "path": closure }
+                }
+            }
+        }
+    }))?)
+}
+
+#[test]
+fn test_closure_deployment_data() {
+    assert!(matches!(
+        closure_deployment_data("/tmp", "node", None),
+        Err(ClosureError::NotStorePath(_))
+    ));
+    assert!(matches!(
+        closure_deployment_data("/nix/store", "node", None),
+        Err(ClosureError::NotStorePath(_))
+    ));
+    assert!(matches!(
+        closure_deployment_data(
+            "/nix/store/00000000000000000000000000000000-missing",
+            "node",
+            None
+        ),
+        Err(ClosureError::Missing(_))
+    ));
+
+    let store_dir =
+        std::env::temp_dir().join(format!("deploy-rs-test-store-{}", std::process::id()));
+    let closure = store_dir.join("00000000000000000000000000000000-activatable");
+    std::fs::create_dir_all(&closure).unwrap();
+    std::env::set_var("NIX_STORE_DIR", &store_dir);
+    assert!(matches!(
+        closure_deployment_data(closure.to_str().unwrap(), "node", None),
+        Err(ClosureError::NotActivatable(_))
+    ));
+    std::fs::File::create(closure.join("deploy-rs-activate")).unwrap();
+    std::fs::File::create(closure.join("activate-rs")).unwrap();
+    assert!(closure_deployment_data(closure.to_str().unwrap(), "node", None).is_ok());
+    std::env::remove_var("NIX_STORE_DIR");
+    std::fs::remove_dir_all(&store_dir).unwrap();
+}
+
+#[derive(Error, Debug)]
+pub enum RequireTagError {
+    #[error("Release tags can only be checked for flakes in a local directory, got `{0}`")]
+    NotLocal(String),
+    #[error("Failed to run git to look for a release tag: {0}")]
+    Git(std::io::Error),
+    #[error("Error converting git output to utf8: {0}")]
+    DecodeUtf8(#[from] std::string::FromUtf8Error),
+    #[error("HEAD of {0} is not tagged with a tag matching `{1}`")]
+    NotTagged(String, String),
+}
+
+/// Makes sure that HEAD of the git repository containing the flake carries a tag matching `pattern`
+async fn check_release_tag(repo: &str, pattern: &str) -> Result<(), RequireTagError> {
+    if !Path::new(repo).is_dir() {
+        return Err(RequireTagError::NotLocal(repo.to_string()));
+    }
+
+    let describe_output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .arg("describe")
+        .arg("--tags")
+        .arg("--exact-match")
+        .arg("--match")
+        .arg(pattern)
+        .arg("HEAD")
+        .stderr(Stdio::null())
+        .output()
+        .await
+        .map_err(RequireTagError::Git)?;
+
+    if !describe_output.status.success() {
+        return Err(RequireTagError::NotTagged(
+            repo.to_string(),
+            pattern.to_string(),
+        ));
+    }
+
+    info!(
+        "HEAD of {} is tagged as {}",
+        repo,
+        String::from_utf8(describe_output.stdout)?.trim()
+    );
+
+    Ok(())
+}
+
 #[derive(Error, Debug)]
 pub enum GetDeploymentDataError {
     #[error("Failed to execute nix eval command: {0}")]
@@ -176,24 +492,332 @@ pub enum GetDeploymentDataError {
     DecodeUtf8(#[from] std::string::FromUtf8Error),
     #[error("Error decoding the JSON from evaluation: {0}")]
     DecodeJson(#[from] serde_json::error::Error),
+    #[error("Failed to dump evaluation output to {0}: {1}")]
+    DumpNixEval(PathBuf, std::io::Error),
+    #[error("Evaluation produced warnings:\n{}", .0.join("\n"))]
+    EvalWarnings(Vec<String>),
+    #[error(
+        "The flake `{0}` has no `deploy` output.\n\
+             It has to define the nodes to deploy as `deploy.nodes.<node>.profiles.<profile>`, \
+             see https://github.com/serokell/deploy-rs/tree/master/examples for examples"
+    )]
+    NoDeployOutput(String),
     #[error("Impossible happened: profile is set but node is not")]
     ProfileNoNode,
 }
 
-/// Evaluates the Nix in the given `repo` and return the processed Data from it
+/// Escapes a value so that it can be embedded in a double-quoted Nix string
+fn escape_nix_string(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+}
+
+#[test]
+fn test_escape_nix_string() {
+    assert_eq!(escape_nix_string("example.com"), "example.com");
+    assert_eq!(escape_nix_string("my \"node\""), "my \\\"node\\\"");
+    assert_eq!(escape_nix_string("back\\slash"), "back\\\\slash");
+    assert_eq!(
+        escape_nix_string("${builtins.abort \"\"}"),
+        "\\${builtins.abort \\\"\\\"}"
+    );
+}
+
+/// Nix expression for the source to import a deployment from without flakes.
+///
+/// Local paths are imported directly, URLs (e.g. tarballs produced by CI) are fetched first.
+fn nix_import_source(repo: &str) -> String {
+    if repo.contains("://") {
+        format!("(builtins.fetchTarball \"{}\")", escape_nix_string(repo))
+    } else {
+        format!("{}/.", repo)
+    }
+}
+
+#[test]
+fn test_nix_import_source() {
+    assert_eq!(
+        nix_import_source("../deploy/examples/system"),
+        "../deploy/examples/system/."
+    );
+    assert_eq!(
+        nix_import_source("https://example.com/source.tar.gz"),
+        "(builtins.fetchTarball \"https://example.com/source.tar.gz\")"
+    );
+}
+
+/// Picks the warnings out of the stderr of a Nix evaluation
+fn eval_warnings(stderr: &str) -> Vec<String> {
+    stderr
+        .lines()
+        // Older Nix versions only print `lib.warn` messages as traces
+        .filter(|l| l.starts_with("evaluation warning:") || l.starts_with("trace: warning:"))
+        .map(|l| l.to_string())
+        .collect()
+}
+
+#[test]
+fn test_eval_warnings() {
+    let stderr = "evaluating...\n\
+                  evaluation warning: services.gitea.database.password will be stored as plaintext\n\
+                  trace: warning: the option `foo' has been renamed to `bar'\n\
+                  trace: something else\n";
+
+    assert_eq!(
+        eval_warnings(stderr),
+        vec![
+            "evaluation warning: services.gitea.database.password will be stored as plaintext",
+            "trace: warning: the option `foo' has been renamed to `bar'",
+        ]
+    );
+    assert!(eval_warnings("").is_empty());
+}
+
+/// Keeps only the nodes and profiles of `data` that `flake` refers to, like the `--apply` filter of a per-target evaluation
+fn slice_deployment_data(
+    data: &deploy::data::Data,
+    flake: &deploy::DeployFlake<'_>,
+) -> deploy::data::Data {
+    let mut data = data.clone();
+
+    if let Some(node_name) = &flake.node {
+        if deploy::is_node_glob(node_name) {
+            data.nodes
+                .retain(|name, _| deploy::node_matches_glob(node_name, name));
+        } else {
+            data.nodes.retain(|name, _| name == node_name);
+        }
+
+        if let Some(profile_name) = &flake.profile {
+            for node in data.nodes.values_mut() {
+                node.node_settings
+                    .profiles
+                    .retain(|name, _| name == profile_name);
+            }
+        }
+    }
+
+    data
+}
+
+#[test]
+fn test_slice_deployment_data() {
+    let data: deploy::data::Data = serde_json::from_value(serde_json::json!({
+        "nodes": {
+            "web-1": { "hostname": "web-1", "profiles": {
+                "system": { "path": "/nix/store/a" },
+                "app": { "path": "/nix/store/b" },
+            } },
+            "web-2": { "hostname": "web-2", "profiles": { "system": { "path": "/nix/store/c" } } },
+            "db": { "hostname": "db", "profiles": { "system": { "path": "/nix/store/d" } } },
+        },
+    }))
+    .unwrap();
+
+    let slice = |node: Option<&str>, profile: Option<&str>| {
+        let sliced = slice_deployment_data(
+            &data,
+            &deploy::DeployFlake {
+                repo: ".",
+                node: node.map(str::to_string),
+                profile: profile.map(str::to_string),
+            },
+        );
+        let mut profiles: Vec<String> = sliced
+            .nodes
+            .iter()
+            .flat_map(|(node, n)| {
+                n.node_settings
+                    .profiles
+                    .keys()
+                    .map(move |profile| format!("{}.{}", node, profile))
+            })
+            .collect();
+        profiles.sort();
+        profiles
+    };
+
+    assert_eq!(slice(None, None).len(), 4);
+    assert_eq!(slice(Some("web-1"), None), ["web-1.app", "web-1.system"]);
+    assert_eq!(slice(Some("web-1"), Some("app")), ["web-1.app"]);
+    assert_eq!(
+        slice(Some("web-*"), Some("system")),
+        ["web-1.system", "web-2.system"]
+    );
+    assert!(slice(Some("cache"), None).is_empty());
+}
+
+/// Whether evaluating the `deploy` output failed because there is no such output
+fn lacks_deploy_output(stderr: &str) -> bool {
+    stderr.contains("attribute 'deploy' missing")
+        || (stderr.contains("does not provide attribute") && stderr.contains("'deploy'"))
+}
+
+#[test]
+fn test_lacks_deploy_output() {
+    assert!(lacks_deploy_output(
+        "error: flake 'path:/home/user/fleet' does not provide attribute 'packages.x86_64-linux.deploy', \
+         'legacyPackages.x86_64-linux.deploy' or 'deploy'\n"
+    ));
+    assert!(lacks_deploy_output(
+        "error: attribute 'deploy' missing\n\n       at «string»:1:87:\n"
+    ));
+    assert!(!lacks_deploy_output("error: attribute 'web' missing\n"));
+}
+
+/// Prints the lines of a stream to stderr as they come, and returns all of them
+async fn tee_stderr<R: tokio::io::AsyncRead + Unpin>(stream: R) -> Result<String, std::io::Error> {
+    use tokio::io::AsyncBufReadExt;
+
+    let mut lines = tokio::io::BufReader::new(stream).lines();
+    let mut output = String::new();
+
+    while let Some(line) = lines.next_line().await? {
+        eprintln!("{}", line);
+        output.push_str(&line);
+        output.push('\n');
+    }
+
+    Ok(output)
+}
+
+/// Path to write the `index`th of `evaluations` evaluations to with `--dump-nix-eval`, numbered
+/// before the extension if there is more than one (`eval.json` becomes `eval.0.json`, `eval.1.json`, …)
+fn dump_path(path: &Path, index: usize, evaluations: usize) -> PathBuf {
+    if evaluations < 2 {
+        return path.to_path_buf();
+    }
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => path.with_file_name(format!(
+            "{}.{}.{}",
+            stem,
+            index,
+            extension.to_string_lossy()
+        )),
+        None => path.with_file_name(format!("{}.{}", stem, index)),
+    }
+}
+
+#[test]
+fn test_dump_path() {
+    assert_eq!(
+        dump_path(Path::new("/tmp/eval.json"), 0, 1),
+        Path::new("/tmp/eval.json")
+    );
+    assert_eq!(
+        dump_path(Path::new("/tmp/eval.json"), 1, 2),
+        Path::new("/tmp/eval.1.json")
+    );
+    assert_eq!(dump_path(Path::new("eval"), 0, 2), Path::new("eval.0"));
+}
+
+/// Evaluates the Nix in the `repo` of every flake and return the processed Data from it
+///
+/// Flakes sharing a repo are evaluated once as a whole and sliced per flake afterwards,
+/// unless `eval_per_target` asks for a separate, filtered evaluation of every flake.
 async fn get_deployment_data(
     supports_flakes: bool,
     flakes: &[deploy::DeployFlake<'_>],
     extra_build_args: &[String],
+    dump_nix_eval: Option<&Path>,
+    abort_on_warnings: bool,
+    eval_per_target: bool,
 ) -> Result<Vec<deploy::data::Data>, GetDeploymentDataError> {
-    futures_util::stream::iter(flakes).then(|flake| async move {
+    let mut repo_counts: HashMap<&str, usize> = HashMap::new();
+    for flake in flakes {
+        *repo_counts.entry(flake.repo).or_default() += 1;
+    }
 
-    info!("Evaluating flake in {}", flake.repo);
+    let evaluated_alone =
+        |flake: &deploy::DeployFlake| eval_per_target || repo_counts[flake.repo] < 2;
+    let evaluations = flakes.iter().filter(|f| evaluated_alone(f)).count()
+        + repo_counts
+            .values()
+            .filter(|count| !eval_per_target && **count > 1)
+            .count();
+    // Every evaluation gets a file of its own
+    let mut dump_paths =
+        (0..evaluations).map(|i| dump_nix_eval.map(|path| dump_path(path, i, evaluations)));
+
+    let mut cache: HashMap<&str, deploy::data::Data> = HashMap::new();
+    let mut datas = Vec::with_capacity(flakes.len());
 
+    for flake in flakes {
+        if evaluated_alone(flake) {
+            // A node glob is matched against the evaluated nodes, so it needs them all
+            if flake.node.as_deref().is_some_and(deploy::is_node_glob) {
+                let whole = deploy::DeployFlake {
+                    repo: flake.repo,
+                    node: None,
+                    profile: None,
+                };
+                let data = evaluate_flake(
+                    supports_flakes,
+                    &whole,
+                    extra_build_args,
+                    dump_paths.next().flatten().as_deref(),
+                    abort_on_warnings,
+                )
+                .await?;
+                datas.push(slice_deployment_data(&data, flake));
+                continue;
+            }
+
+            datas.push(
+                evaluate_flake(
+                    supports_flakes,
+                    flake,
+                    extra_build_args,
+                    dump_paths.next().flatten().as_deref(),
+                    abort_on_warnings,
+                )
+                .await?,
+            );
+            continue;
+        }
+
+        if flake.node.is_none() && flake.profile.is_some() {
+            return Err(GetDeploymentDataError::ProfileNoNode);
+        }
+
+        if !cache.contains_key(flake.repo) {
+            let whole = deploy::DeployFlake {
+                repo: flake.repo,
+                node: None,
+                profile: None,
+            };
+            let data = evaluate_flake(
+                supports_flakes,
+                &whole,
+                extra_build_args,
+                dump_paths.next().flatten().as_deref(),
+                abort_on_warnings,
+            )
+            .await?;
+            cache.insert(flake.repo, data);
+        } else {
+            debug!("Reusing the evaluation of {}", flake.repo);
+        }
+
+        datas.push(slice_deployment_data(&cache[flake.repo], flake));
+    }
+
+    Ok(datas)
+}
+
+/// Builds the command evaluating the Nix in the `repo` of a single flake
+fn eval_command(
+    supports_flakes: bool,
+    flake: &deploy::DeployFlake<'_>,
+    extra_build_args: &[String],
+) -> Result<Command, GetDeploymentDataError> {
     let mut c = if supports_flakes {
-        Command::new("nix")
+        deploy::nix_command("nix")
     } else {
-        Command::new("nix-instantiate")
+        deploy::nix_command("nix-instantiate")
     };
 
     if supports_flakes {
@@ -218,7 +842,8 @@ async fn get_deployment_data(
                         }};
                       }})
                      "#,
-                    node, profile
+                    escape_nix_string(node),
+                    escape_nix_string(profile)
                 ))
             }
             (Some(node), None) => {
@@ -232,7 +857,7 @@ async fn get_deployment_data(
                         }};
                       }})
                     "#,
-                    node
+                    escape_nix_string(node)
                 ))
             }
             (None, None) => {
@@ -242,36 +867,151 @@ async fn get_deployment_data(
             (None, Some(_)) => return Err(GetDeploymentDataError::ProfileNoNode),
         }
     } else {
-        c
-            .arg("--strict")
+        c.arg("--strict")
             .arg("--read-write-mode")
             .arg("--json")
             .arg("--eval")
             .arg("-E")
-            .arg(format!("let r = import {}/.; in if builtins.isFunction r then (r {{}}).deploy else r.deploy", flake.repo))
+            .arg(format!(
+                "let r = import {}; in if builtins.isFunction r then (r {{}}).deploy else r.deploy",
+                nix_import_source(flake.repo)
+            ))
     };
 
     c.args(extra_build_args);
 
-    let build_child = c
+    Ok(c)
+}
+
+/// Evaluates the Nix in the `repo` of a single flake, keeping only the nodes and profiles it refers to
+async fn evaluate_flake(
+    supports_flakes: bool,
+    flake: &deploy::DeployFlake<'_>,
+    extra_build_args: &[String],
+    dump_nix_eval: Option<&Path>,
+    abort_on_warnings: bool,
+) -> Result<deploy::data::Data, GetDeploymentDataError> {
+    info!("Evaluating flake in {}", flake.repo);
+
+    let mut c = eval_command(supports_flakes, flake, extra_build_args)?;
+
+    // Warnings and errors can only be inspected if we get hold of stderr, which is still shown as it comes
+    let mut build_child = c
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
         .map_err(GetDeploymentDataError::NixEval)?;
 
-    let build_output = build_child
-        .wait_with_output()
-        .await
-        .map_err(GetDeploymentDataError::NixEvalOut)?;
+    let stderr = build_child.stderr.take();
+    let read_stderr = async {
+        match stderr {
+            Some(stderr) => tee_stderr(stderr).await,
+            None => Ok(String::new()),
+        }
+    };
+
+    let (build_output, stderr) =
+        futures_util::try_join!(build_child.wait_with_output(), read_stderr)
+            .map_err(GetDeploymentDataError::NixEvalOut)?;
+
+    if abort_on_warnings {
+        let warnings = eval_warnings(&stderr);
+        if !warnings.is_empty() {
+            return Err(GetDeploymentDataError::EvalWarnings(warnings));
+        }
+    }
 
     match build_output.status.code() {
         Some(0) => (),
+        _ if lacks_deploy_output(&stderr) => {
+            return Err(GetDeploymentDataError::NoDeployOutput(
+                flake.repo.to_string(),
+            ))
+        }
         a => return Err(GetDeploymentDataError::NixEvalExit(a)),
     };
 
     let data_json = String::from_utf8(build_output.stdout)?;
 
+    if let Some(dump_path) = dump_nix_eval {
+        info!(
+            "Dumping the evaluation of {} to {}",
+            flake.repo,
+            dump_path.display()
+        );
+
+        tokio::fs::write(dump_path, &data_json)
+            .await
+            .map_err(|e| GetDeploymentDataError::DumpNixEval(dump_path.to_path_buf(), e))?;
+    }
+
     Ok(serde_json::from_str(&data_json)?)
-}).try_collect().await
+}
+
+/// Everything needed to reproduce a deployment, written by `--save-manifest`
+#[derive(Serialize)]
+struct Manifest<'a> {
+    opts: &'a serde_json::Value,
+    targets: Vec<ManifestTarget<'a>>,
+    profiles: Vec<ManifestProfile<'a>>,
+}
+
+#[derive(Serialize)]
+struct ManifestTarget<'a> {
+    flake: &'a deploy::DeployFlake<'a>,
+    data: &'a deploy::data::Data,
+}
+
+#[derive(Serialize)]
+struct ManifestProfile<'a> {
+    closure: &'a str,
+    deploy_data: &'a deploy::DeployData<'a>,
+    deploy_defs: &'a deploy::DeployDefs,
+}
+
+#[derive(Error, Debug)]
+pub enum SaveManifestError {
+    #[error("Failed to serialize the deployment manifest: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Failed to write the deployment manifest to {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+}
+
+async fn save_manifest(
+    path: &Path,
+    opts: &serde_json::Value,
+    deploy_flakes: &[deploy::DeployFlake<'_>],
+    data: &[deploy::data::Data],
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData<'_>,
+        deploy::DeployDefs,
+    )],
+) -> Result<(), SaveManifestError> {
+    let manifest = Manifest {
+        opts,
+        targets: deploy_flakes
+            .iter()
+            .zip(data)
+            .map(|(flake, data)| ManifestTarget { flake, data })
+            .collect(),
+        profiles: parts
+            .iter()
+            .map(|(_, deploy_data, deploy_defs)| ManifestProfile {
+                closure: &deploy_data.profile.profile_settings.path,
+                deploy_data,
+                deploy_defs,
+            })
+            .collect(),
+    };
+
+    tokio::fs::write(path, serde_json::to_string_pretty(&manifest)?)
+        .await
+        .map_err(|e| SaveManifestError::Write(path.to_path_buf(), e))?;
+
+    info!("Saved the deployment manifest to {}", path.display());
+
+    Ok(())
 }
 
 #[derive(Serialize)]
@@ -290,34 +1030,77 @@ fn print_deployment(
         deploy::DeployDefs,
     )],
 ) -> Result<(), toml::ser::Error> {
-    let mut part_map: HashMap<String, HashMap<String, PromptPart>> = HashMap::new();
+    let toml = toml::to_string(&deployment_part_map(parts))?;
+
+    info!("The following profiles are going to be deployed:\n{}", toml);
+
+    Ok(())
+}
+
+/// Prints the profiles that are going to be deployed as JSON to stdout, for scripts
+fn print_deployment_json(
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData,
+        deploy::DeployDefs,
+    )],
+) -> Result<(), serde_json::Error> {
+    println!("{}", serde_json::to_string(&deployment_part_map(parts))?);
+
+    Ok(())
+}
+
+/// Prints the nodes and profiles of the targets to stdout for `--list`, as TOML or JSON
+fn print_list(
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData,
+        deploy::DeployDefs,
+    )],
+    plan_format: OutputFormat,
+) -> Result<(), RunDeployError> {
+    match plan_format {
+        OutputFormat::Human => print!("{}", toml::to_string(&deployment_part_map(parts))?),
+        OutputFormat::Json => print_deployment_json(parts)?,
+    }
+
+    Ok(())
+}
+
+fn deployment_part_map<'a>(
+    parts: &'a [(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData,
+        deploy::DeployDefs,
+    )],
+) -> BTreeMap<String, BTreeMap<String, PromptPart<'a>>> {
+    // Sorted, so that the plans of two runs can be compared
+    let mut part_map: BTreeMap<String, BTreeMap<String, PromptPart>> = BTreeMap::new();
 
     for (_, data, defs) in parts {
         part_map
             .entry(data.node_name.to_string())
-            .or_insert_with(HashMap::new)
+            .or_default()
             .insert(
                 data.profile_name.to_string(),
                 PromptPart {
                     user: &defs.profile_user,
                     ssh_user: &defs.ssh_user,
                     path: &data.profile.profile_settings.path,
-                    hostname: &data.node.node_settings.hostname,
+                    hostname: data.hostname(),
                     ssh_opts: &data.merged_settings.ssh_opts,
                 },
             );
     }
 
-    let toml = toml::to_string(&part_map)?;
-
-    info!("The following profiles are going to be deployed:\n{}", toml);
-
-    Ok(())
+    part_map
 }
 #[derive(Error, Debug)]
 pub enum PromptDeploymentError {
     #[error("Failed to make printable TOML of deployment: {0}")]
     TomlFormat(#[from] toml::ser::Error),
+    #[error("Failed to make printable JSON of deployment: {0}")]
+    JsonFormat(#[from] serde_json::Error),
     #[error("Failed to flush stdout prior to query: {0}")]
     StdoutFlush(std::io::Error),
     #[error("Failed to read line from stdin: {0}")]
@@ -332,10 +1115,20 @@ fn prompt_deployment(
         deploy::DeployData,
         deploy::DeployDefs,
     )],
+    assume_yes: bool,
+    plan_format: OutputFormat,
 ) -> Result<(), PromptDeploymentError> {
-    print_deployment(parts)?;
+    match plan_format {
+        OutputFormat::Human => print_deployment(parts)?,
+        OutputFormat::Json => print_deployment_json(parts)?,
+    }
 
     info!("Are you sure you want to deploy these profiles?");
+
+    if assume_yes {
+        info!("> yes (confirmed automatically because of --yes)");
+        return Ok(());
+    }
     print!("> ");
 
     stdout()
@@ -378,161 +1171,2105 @@ fn prompt_deployment(
     Ok(())
 }
 
-#[derive(Error, Debug)]
-pub enum RunDeployError {
-    #[error("Failed to deploy profile to node {0}: {1}")]
-    DeployProfile(String, deploy::deploy::DeployProfileError),
-    #[error("Failed to build profile on node {0}: {0}")]
-    BuildProfile(String,  deploy::push::PushProfileError),
-    #[error("Failed to push profile to node {0}: {0}")]
-    PushProfile(String,  deploy::push::PushProfileError),
-    #[error("No profile named `{0}` was found")]
-    ProfileNotFound(String),
-    #[error("No node named `{0}` was found")]
-    NodeNotFound(String),
-    #[error("Profile was provided without a node name")]
-    ProfileWithoutNode,
-    #[error("Error processing deployment definitions: {0}")]
-    DeployDataDefs(#[from] deploy::DeployDataDefsError),
-    #[error("Failed to make printable TOML of deployment: {0}")]
-    TomlFormat(#[from] toml::ser::Error),
-    #[error("{0}")]
-    PromptDeployment(#[from] PromptDeploymentError),
-    #[error("Failed to revoke profile for node {0}: {1}")]
-    RevokeProfile(String, deploy::deploy::RevokeProfileError),
-    #[error("Deployment to node {0} failed, rolled back to previous generation")]
-    Rollback(String)
-}
+/// Asks once whether to confirm all pending deployments, giving up when `deadline` passes
+async fn prompt_confirm_all(
+    count: usize,
+    deadline: Instant,
+) -> Result<bool, PromptDeploymentError> {
+    let window = deadline.saturating_duration_since(Instant::now());
 
-type ToDeploy<'a> = Vec<(
-    &'a deploy::DeployFlake<'a>,
-    &'a deploy::data::Data,
-    (&'a str, &'a deploy::data::Node),
-    (&'a str, &'a deploy::data::Profile),
-)>;
+    info!(
+        "{} profile(s) are activated and waiting for confirmation. Confirm all of them within {}s?",
+        count,
+        window.as_secs()
+    );
+    print!("> ");
 
-async fn run_deploy(
-    deploy_flakes: Vec<deploy::DeployFlake<'_>>,
-    data: Vec<deploy::data::Data>,
-    supports_flakes: bool,
+    stdout()
+        .flush()
+        .map_err(PromptDeploymentError::StdoutFlush)?;
+
+    let read_line = tokio::task::spawn_blocking(|| {
+        let mut s = String::new();
+        stdin().read_line(&mut s).map(|_| s)
+    });
+
+    match tokio::time::timeout(window, read_line).await {
+        Ok(s) => {
+            let s = s
+                .map_err(|e| PromptDeploymentError::StdinRead(e.into()))?
+                .map_err(PromptDeploymentError::StdinRead)?;
+
+            Ok(yn::yes(&s))
+        }
+        Err(_) => {
+            println!();
+            warn!("The confirmation window has passed");
+
+            Ok(false)
+        }
+    }
+}
+
+/// Confirms the deployments left unconfirmed by `--copy-first-then-confirm-all` after a single prompt.
+///
+/// Unconfirmed deployments roll back on their own once their confirm timeout passes. If the prompt
+/// isn't answered with "yes" in time or one of the confirmations fails, the remaining deployments
+/// are left unconfirmed and the ones which are already activated for good (confirmed ones and ones
+/// without magic rollback) are revoked, so that the whole fleet goes back to its previous generation.
+///
+/// The deadline shown is counted from the start of each activation, so it ends no later than the
+/// confirm timeout on the node. Confirming still takes an SSH round trip per node though, so a "yes"
+/// in the last seconds can reach a node that already rolled back. Its confirmation then fails and
+/// the other deployments are revoked as well.
+async fn confirm_all_deployments(
+    succeeded: &[(&str, &deploy::DeployData<'_>, &deploy::DeployDefs)],
+    unconfirmed: &[(&str, &deploy::DeployData<'_>, &deploy::DeployDefs, Instant)],
+    time_limit: Option<(Duration, tokio::time::Instant)>,
+    report: &DeployReport,
+) -> Result<(), RunDeployError> {
+    let deadline = match unconfirmed
+        .iter()
+        .map(|(_, _, _, deadline)| *deadline)
+        .min()
+    {
+        Some(x) => x,
+        None => {
+            info!("No profile uses magic rollback, nothing to confirm");
+            return Ok(());
+        }
+    };
+    // The prompt doesn't outlast `--time-limit` either
+    let deadline = match time_limit {
+        Some((_, time_limit_deadline)) => deadline.min(time_limit_deadline.into_std()),
+        None => deadline,
+    };
+
+    let mut activated: Vec<(&str, &deploy::DeployData, &deploy::DeployDefs)> = succeeded.to_vec();
+
+    let failure = if !prompt_confirm_all(unconfirmed.len(), deadline).await? {
+        match time_limit {
+            Some((limit, time_limit_deadline))
+                if tokio::time::Instant::now() >= time_limit_deadline =>
+            {
+                Some(RunDeployError::TimeLimit(limit.as_secs()))
+            }
+            _ => Some(RunDeployError::NotConfirmed),
+        }
+    } else {
+        let mut failure = None;
+
+        for (repo, deploy_data, deploy_defs, _) in unconfirmed {
+            let confirmed = within_time_limit(
+                time_limit,
+                for_profile(
+                    deploy_data,
+                    deploy::deploy::confirm_deployment(deploy_data, deploy_defs),
+                ),
+            )
+            .await
+            .and_then(|x| {
+                x.map_err(|e| {
+                    RunDeployError::ConfirmProfile(
+                        deploy_data.node_name.to_string(),
+                        deploy_data.profile_name.to_string(),
+                        e,
+                    )
+                })
+            });
+
+            if let Err(e) = confirmed {
+                failure = Some(e);
+                break;
+            }
+
+            report.update(repo, deploy_data, |p| p.confirmed = true);
+
+            let checked = within_time_limit(
+                time_limit,
+                for_profile(
+                    deploy_data,
+                    deploy::deploy::run_health_check(deploy_data, deploy_defs),
+                ),
+            )
+            .await
+            .and_then(|x| {
+                x.map_err(|e| {
+                    RunDeployError::DeployProfile(
+                        deploy_data.node_name.to_string(),
+                        deploy_data.profile_name.to_string(),
+                        e,
+                    )
+                })
+            });
+
+            if let Err(e) = checked {
+                // A failed health check already deactivated the profile
+                report.update(repo, deploy_data, |p| {
+                    p.rolled_back = matches!(
+                        e,
+                        RunDeployError::DeployProfile(_, _, ref e) if e.deactivated_profile()
+                    )
+                });
+                failure = Some(e);
+                break;
+            }
+
+            activated.push((repo, deploy_data, deploy_defs));
+        }
+
+        failure
+    };
+
+    if let Some(err) = failure {
+        error!("{}", err);
+        info!("Unconfirmed deployments will roll back on their own, revoking the others");
+
+        revoke_all(&activated, report).await?;
+
+        return Err(RunDeployError::RolledBack(Box::new(err)));
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn print_plan(
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData<'_>,
+        deploy::DeployDefs,
+    )],
+    supports_flakes: bool,
     check_sigs: bool,
-    interactive: bool,
-    cmd_overrides: &deploy::CmdOverrides,
     keep_result: bool,
     result_path: Option<&str>,
     extra_build_args: &[String],
-    debug_logs: bool,
     dry_activate: bool,
     boot: bool,
-    log_dir: &Option<String>,
-    rollback_succeeded: bool,
+    activate_only_if_push_changed: bool,
+    reverse_profiles: bool,
+) -> Result<(), RunDeployError> {
+    let mut builds: Vec<String> = Vec::new();
+    let mut pushes: Vec<String> = Vec::new();
+    let mut activations: Vec<((&str, &str), String)> = Vec::new();
+
+    for (deploy_flake, deploy_data, deploy_defs) in parts {
+        let node_name = deploy_data.node_name;
+        let profile_name = deploy_data.profile_name;
+        let hostname = deploy_data.hostname();
+        let remote_build = deploy_data.merged_settings.remote_build.unwrap_or(false);
+
+        let present = deploy::push::profile_is_present(&deploy::push::PushProfileData {
+            supports_flakes,
+            check_sigs,
+            repo: deploy_flake.repo,
+            deploy_data,
+            deploy_defs,
+            keep_result,
+            result_path,
+            extra_build_args,
+            auto_verbose_on_failure: false,
+            keep_going: false,
+            check_present: true,
+        })
+        .await
+        .map_err(|e| {
+            RunDeployError::PushProfile(node_name.to_string(), profile_name.to_string(), e)
+        })?;
+
+        // The closure has to be present on the node to ask its activate-rs about the profile
+        let current = present
+            && for_profile(
+                deploy_data,
+                deploy::deploy::profile_is_current(deploy_data, deploy_defs),
+            )
+            .await
+            .map_err(|e| {
+                RunDeployError::CheckCurrentProfile(
+                    node_name.to_string(),
+                    profile_name.to_string(),
+                    e,
+                )
+            })?;
+
+        builds.push(if remote_build {
+            format!(
+                "Build profile `{}` for node `{}` on {}",
+                profile_name, node_name, hostname
+            )
+        } else if std::path::Path::new(&deploy_data.profile.profile_settings.path).exists() {
+            format!(
+                "Build profile `{}` for node `{}` locally (already built)",
+                profile_name, node_name
+            )
+        } else {
+            format!(
+                "Build profile `{}` for node `{}` locally",
+                profile_name, node_name
+            )
+        });
+
+        if !remote_build {
+            pushes.push(if present {
+                format!(
+                    "Copy profile `{}` to node `{}` (already present, skipped)",
+                    profile_name, node_name
+                )
+            } else {
+                format!(
+                    "Copy profile `{}` to node `{}` at {}",
+                    profile_name, node_name, hostname
+                )
+            });
+        }
+
+        let activation = if dry_activate {
+            "Dry-activate"
+        } else if boot {
+            "Activate for next boot"
+        } else {
+            "Activate"
+        };
+
+        let activation = match (current, activate_only_if_push_changed) {
+            (true, true) => format!(
+                "{} profile `{}` on node `{}` (already active, skipped)",
+                activation, profile_name, node_name
+            ),
+            (true, false) => format!(
+                "{} profile `{}` on node `{}` (already active)",
+                activation, profile_name, node_name
+            ),
+            (false, _) => format!(
+                "{} profile `{}` on node `{}`",
+                activation, profile_name, node_name
+            ),
+        };
+
+        activations.push(((deploy_flake.repo, node_name), activation));
+    }
+
+    if reverse_profiles {
+        activations = reverse_per_node(activations, |(node, _)| *node);
+    }
+
+    let activations: Vec<String> = activations.into_iter().map(|(_, a)| a).collect();
+
+    let plan = [builds, pushes, activations]
+        .concat()
+        .iter()
+        .enumerate()
+        .map(|(i, action)| format!("{}. {}", i + 1, action))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    info!(
+        "The deployment would perform the following actions:\n{}",
+        plan
+    );
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum SudoSecretError {
+    #[error("`sudoFile` is set to {0} but `sudoSecret` doesn't name the key holding the password")]
+    NoSecret(String),
+    #[error("Failed to run sops to decrypt {0}: {1}")]
+    Sops(String, std::io::Error),
+    #[error("sops exited with a bad status code decrypting {0}: {1:?}")]
+    SopsExit(String, Option<i32>),
+    #[error("Failed to parse the decrypted {0}: {1}")]
+    Parse(String, serde_json::Error),
+    #[error("The decrypted {0} has no string `{1}`")]
+    MissingKey(String, String),
+}
+
+/// Extracts the sudo password named `secret` from the JSON output of `sops -d`
+fn sudo_secret_from_json(
+    decrypted: &[u8],
+    file: &str,
+    secret: &str,
+) -> Result<zeroize::Zeroizing<String>, SudoSecretError> {
+    let value: serde_json::Value = serde_json::from_slice(decrypted)
+        .map_err(|e| SudoSecretError::Parse(file.to_string(), e))?;
+
+    match value.get(secret).and_then(|v| v.as_str()) {
+        Some(password) => Ok(zeroize::Zeroizing::new(password.to_string())),
+        None => Err(SudoSecretError::MissingKey(
+            file.to_string(),
+            secret.to_string(),
+        )),
+    }
+}
+
+#[test]
+fn test_sudo_secret_from_json() {
+    let decrypted = br#"{"sudo": "hunter2", "other": {"nested": true}}"#;
+
+    assert_eq!(
+        sudo_secret_from_json(decrypted, "secrets.yaml", "sudo")
+            .unwrap()
+            .as_str(),
+        "hunter2"
+    );
+    assert!(matches!(
+        sudo_secret_from_json(decrypted, "secrets.yaml", "other"),
+        Err(SudoSecretError::MissingKey(..))
+    ));
+    assert!(matches!(
+        sudo_secret_from_json(b"sudo: hunter2", "secrets.yaml", "sudo"),
+        Err(SudoSecretError::Parse(..))
+    ));
+}
+
+/// Decrypts `sudoFile` with `sops -d` and returns the password stored under `sudoSecret`
+async fn decrypt_sudo_secret(
+    file: &Path,
+    secret: &str,
+) -> Result<zeroize::Zeroizing<String>, SudoSecretError> {
+    let file_name = file.display().to_string();
+
+    info!("Decrypting the sudo password from {}", file_name);
+
+    // sops converts YAML, dotenv and INI files alike, so only JSON has to be handled here
+    let output = Command::new("sops")
+        .arg("--decrypt")
+        .arg("--output-type")
+        .arg("json")
+        .arg(file)
+        .output()
+        .await
+        .map_err(|e| SudoSecretError::Sops(file_name.clone(), e))?;
+
+    let decrypted = zeroize::Zeroizing::new(output.stdout);
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(SudoSecretError::SopsExit(file_name, a)),
+    };
+
+    sudo_secret_from_json(&decrypted, &file_name, secret)
+}
+
+#[derive(Error, Debug)]
+pub enum RunDeployError {
+    #[error("Failed to deploy profile `{1}` to node {0}: {2}")]
+    DeployProfile(String, String, deploy::deploy::DeployProfileError),
+    #[error("Failed to build profile `{1}` on node {0}: {2}")]
+    BuildProfile(String, String, deploy::push::PushProfileError),
+    #[error("Failed to push profile `{1}` to node {0}: {2}")]
+    PushProfile(String, String, deploy::push::PushProfileError),
+    #[error("No profile named `{0}` was found")]
+    ProfileNotFound(String),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+    #[error("Profile was provided without a node name")]
+    ProfileWithoutNode,
+    #[error("Error processing deployment definitions: {0}")]
+    DeployDataDefs(#[from] deploy::DeployDataDefsError),
+    #[error("Failed to make printable TOML of deployment: {0}")]
+    TomlFormat(#[from] toml::ser::Error),
+    #[error("Failed to make JSON of deployment: {0}")]
+    JsonFormat(#[from] serde_json::Error),
+    #[error("{0}")]
+    PromptDeployment(#[from] PromptDeploymentError),
+    #[error("Failed to revoke profile `{1}` for node {0}: {2}")]
+    RevokeProfile(String, String, deploy::deploy::RevokeProfileError),
+    #[error("Failed to check the current profile `{1}` on node {0}: {2}")]
+    CheckCurrentProfile(String, String, deploy::deploy::CheckCurrentProfileError),
+    #[error("{0}")]
+    SaveManifest(#[from] SaveManifestError),
+    #[error("The deployment exceeded its time limit of {0}s")]
+    TimeLimit(u64),
+    #[error("Failed to confirm profile `{1}` on node {0}: {2}")]
+    ConfirmProfile(String, String, deploy::deploy::ConfirmProfileError),
+    #[error("The deployment was not confirmed, unconfirmed profiles roll back")]
+    NotConfirmed,
+    #[error("Deployment of profile `{1}` to node {0} failed, rolled back to previous generation")]
+    Rollback(String, String),
+    #[error("{0}, rolled back the successful deploys")]
+    RolledBack(Box<RunDeployError>),
+    #[error("Can't skip copying profile `{1}` to node {0}: {2} was never built here, so the node can't have it. Deploy without --skip-push")]
+    SkipPushNotBuilt(String, String, String),
+    #[error("Failed to read the sudo password of node {0}: {1}")]
+    SudoSecret(String, SudoSecretError),
+    #[error("The deployment was interrupted")]
+    Interrupted,
+    #[error("Not deploying any further, the deployment of another node failed")]
+    OtherNodeFailed,
+    #[error("Profile `{1}` of node {0} depends on `{2}`, which is not a profile of the node")]
+    UnknownDependency(String, String, String),
+    #[error("The profiles {1:?} of node {0} depend on each other")]
+    DependencyCycle(String, Vec<String>),
+    #[error("Can't use --reverse-profiles, profile `{1}` of node {0} has to come after the profiles it `dependsOn`")]
+    ReverseDependencies(String, String),
+    #[error("Failed to read the sudo password: {0}")]
+    AskSudoPassword(std::io::Error),
+    #[error("Profile {1} of host {0} would get different closures from node `{2}` and node `{3}`")]
+    ConflictingProfiles(String, String, String, String),
+    #[error("The preDeploy hook of profile `{1}` for node {0} failed: {2}")]
+    PreDeploy(String, String, HookError),
+    #[error("Failed to resolve the hostname of node {0}: {1}")]
+    HostnameCommand(String, HostnameCommandError),
+    #[error("Some nodes are unreachable over SSH:\n{}", .0.join("\n"))]
+    Unreachable(Vec<String>),
+}
+
+impl RunDeployError {
+    /// Name of the variant, for machine-readable error output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RunDeployError::DeployProfile(..) => "DeployProfile",
+            RunDeployError::BuildProfile(..) => "BuildProfile",
+            RunDeployError::PushProfile(..) => "PushProfile",
+            RunDeployError::ProfileNotFound(_) => "ProfileNotFound",
+            RunDeployError::NodeNotFound(_) => "NodeNotFound",
+            RunDeployError::ProfileWithoutNode => "ProfileWithoutNode",
+            RunDeployError::DeployDataDefs(_) => "DeployDataDefs",
+            RunDeployError::TomlFormat(_) => "TomlFormat",
+            RunDeployError::JsonFormat(_) => "JsonFormat",
+            RunDeployError::PromptDeployment(_) => "PromptDeployment",
+            RunDeployError::RevokeProfile(..) => "RevokeProfile",
+            RunDeployError::CheckCurrentProfile(..) => "CheckCurrentProfile",
+            RunDeployError::SaveManifest(_) => "SaveManifest",
+            RunDeployError::TimeLimit(_) => "TimeLimit",
+            RunDeployError::ConfirmProfile(..) => "ConfirmProfile",
+            RunDeployError::NotConfirmed => "NotConfirmed",
+            RunDeployError::Rollback(..) => "Rollback",
+            RunDeployError::RolledBack(_) => "RolledBack",
+            RunDeployError::SkipPushNotBuilt(..) => "SkipPushNotBuilt",
+            RunDeployError::SudoSecret(..) => "SudoSecret",
+            RunDeployError::Interrupted => "Interrupted",
+            RunDeployError::OtherNodeFailed => "OtherNodeFailed",
+            RunDeployError::UnknownDependency(..) => "UnknownDependency",
+            RunDeployError::DependencyCycle(..) => "DependencyCycle",
+            RunDeployError::ReverseDependencies(..) => "ReverseDependencies",
+            RunDeployError::AskSudoPassword(_) => "AskSudoPassword",
+            RunDeployError::ConflictingProfiles(..) => "ConflictingProfiles",
+            RunDeployError::PreDeploy(..) => "PreDeploy",
+            RunDeployError::HostnameCommand(..) => "HostnameCommand",
+            RunDeployError::Unreachable(_) => "Unreachable",
+        }
+    }
+
+    /// Exit code of `deploy` for the error: 2 if the deployment failed but was rolled back,
+    /// 3 if rolling back failed as well, 130 if it was interrupted with Ctrl-C, 1 otherwise
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunDeployError::Rollback(..) | RunDeployError::RolledBack(_) => 2,
+            RunDeployError::RevokeProfile(..) => 3,
+            RunDeployError::Interrupted => 130,
+            _ => 1,
+        }
+    }
+
+    /// The node the error happened for, if it is specific to one
+    pub fn node(&self) -> Option<&str> {
+        match self {
+            RunDeployError::DeployProfile(node, ..)
+            | RunDeployError::BuildProfile(node, ..)
+            | RunDeployError::PushProfile(node, ..)
+            | RunDeployError::RevokeProfile(node, ..)
+            | RunDeployError::CheckCurrentProfile(node, ..)
+            | RunDeployError::ConfirmProfile(node, ..)
+            | RunDeployError::Rollback(node, _)
+            | RunDeployError::SkipPushNotBuilt(node, ..)
+            | RunDeployError::SudoSecret(node, _)
+            | RunDeployError::UnknownDependency(node, ..)
+            | RunDeployError::DependencyCycle(node, _)
+            | RunDeployError::ReverseDependencies(node, _)
+            | RunDeployError::PreDeploy(node, ..)
+            | RunDeployError::HostnameCommand(node, _)
+            | RunDeployError::NodeNotFound(node) => Some(node),
+            RunDeployError::RolledBack(e) => e.node(),
+            _ => None,
+        }
+    }
+
+    /// The profile the error happened for, if it is specific to one
+    pub fn profile(&self) -> Option<&str> {
+        match self {
+            RunDeployError::DeployProfile(_, profile, _)
+            | RunDeployError::BuildProfile(_, profile, _)
+            | RunDeployError::PushProfile(_, profile, _)
+            | RunDeployError::RevokeProfile(_, profile, _)
+            | RunDeployError::CheckCurrentProfile(_, profile, _)
+            | RunDeployError::ConfirmProfile(_, profile, _)
+            | RunDeployError::Rollback(_, profile)
+            | RunDeployError::SkipPushNotBuilt(_, profile, _)
+            | RunDeployError::PreDeploy(_, profile, _)
+            | RunDeployError::ReverseDependencies(_, profile)
+            | RunDeployError::ProfileNotFound(profile) => Some(profile),
+            RunDeployError::RolledBack(e) => e.profile(),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_exit_code() {
+    assert_eq!(RunDeployError::TimeLimit(60).exit_code(), 1);
+    assert_eq!(
+        RunDeployError::Rollback("node".to_string(), "system".to_string()).exit_code(),
+        2
+    );
+    assert_eq!(
+        RunDeployError::RolledBack(Box::new(RunDeployError::TimeLimit(60))).exit_code(),
+        2
+    );
+    assert_eq!(
+        RunDeployError::RevokeProfile(
+            "node".to_string(),
+            "system".to_string(),
+            deploy::deploy::RevokeProfileError::SSHRevokeExit(Some(1))
+        )
+        .exit_code(),
+        3
+    );
+    assert_eq!(RunDeployError::Interrupted.exit_code(), 130);
+}
+
+fn parse_time_limit(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid time limit `{}`", s))?;
+
+    let seconds = match unit {
+        "s" => Some(number),
+        "m" => number.checked_mul(60),
+        "h" => number.checked_mul(60 * 60),
+        _ => {
+            return Err(format!(
+                "Unknown unit `{}` in time limit, expected s, m or h",
+                unit
+            ))
+        }
+    }
+    .ok_or_else(|| format!("Time limit `{}` is too long", s))?;
+
+    Ok(Duration::from_secs(seconds))
+}
+
+#[test]
+fn test_parse_time_limit() {
+    assert_eq!(parse_time_limit("90"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_time_limit("90s"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_time_limit("15m"), Ok(Duration::from_secs(15 * 60)));
+    assert_eq!(parse_time_limit("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+    assert!(parse_time_limit("15 minutes").is_err());
+    assert!(parse_time_limit("m").is_err());
+    assert!(parse_time_limit("18446744073709551615h").is_err());
+}
+
+/// Rejects 0, which would deploy no node at all
+fn parse_parallel(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) => Err("At least one node has to be deployed at a time".to_string()),
+        Ok(parallel) => Ok(parallel),
+        Err(e) => Err(format!("Invalid number of nodes `{}`: {}", s, e)),
+    }
+}
+
+#[test]
+fn test_parse_parallel() {
+    assert_eq!(parse_parallel("4"), Ok(4));
+    assert!(parse_parallel("0").is_err());
+    assert!(parse_parallel("-1").is_err());
+}
+
+/// Rejects 0, which OpenSSH takes as keeping the connection open forever
+fn parse_control_persist(s: &str) -> Result<u16, String> {
+    match s.parse() {
+        Ok(0) => Err(
+            "0 would keep the connection open forever, leave --ssh-control-persist out for that"
+                .to_string(),
+        ),
+        Ok(seconds) => Ok(seconds),
+        Err(e) => Err(format!("Invalid number of seconds `{}`: {}", s, e)),
+    }
+}
+
+#[test]
+fn test_parse_control_persist() {
+    assert_eq!(parse_control_persist("60"), Ok(60));
+    assert!(parse_control_persist("0").is_err());
+    assert!(parse_control_persist("1m").is_err());
+}
+
+/// Set by the first Ctrl-C, after which no further profiles are built, copied or activated
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn check_interrupted() -> Result<(), RunDeployError> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        Err(RunDeployError::Interrupted)
+    } else {
+        Ok(())
+    }
+}
+
+/// Resolves on the second Ctrl-C, the first one only stops further deployments
+async fn second_interrupt() {
+    for _ in 0..2 {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return std::future::pending().await;
+        }
+
+        if !INTERRUPTED.swap(true, Ordering::SeqCst) {
+            warn!(
+                "Interrupted, not deploying anything else. Press Ctrl-C again to abort right away"
+            );
+        }
+    }
+}
+
+/// Runs `f`, failing with `RunDeployError::TimeLimit` if it isn't done by the deadline
+async fn within_time_limit<F: Future>(
+    time_limit: Option<(Duration, tokio::time::Instant)>,
+    f: F,
+) -> Result<F::Output, RunDeployError> {
+    match time_limit {
+        Some((limit, deadline)) => tokio::time::timeout_at(deadline, f)
+            .await
+            .map_err(|_| RunDeployError::TimeLimit(limit.as_secs())),
+        None => Ok(f.await),
+    }
+}
+
+/// Status of a step of deploying a profile in the `--report-file`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum StepStatus {
+    /// Not reached, e.g. because an earlier profile failed
+    Pending,
+    Skipped,
+    Succeeded,
+    Failed,
+}
+
+impl StepStatus {
+    fn of<T, E>(result: &Result<T, E>) -> Self {
+        match result {
+            Ok(_) => StepStatus::Succeeded,
+            Err(_) => StepStatus::Failed,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ProfileReport {
+    repo: String,
+    node: String,
+    profile: String,
+    build: StepStatus,
+    push: StepStatus,
+    activation: StepStatus,
+    confirmed: bool,
+    rolled_back: bool,
+    /// Seconds from building the profile until its last recorded step
+    elapsed_secs: f64,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+/// What happened to every profile of a deployment, written to the `--report-file`
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DeployReport {
+    success: bool,
+    error: Option<String>,
+    elapsed_secs: f64,
+    // Nodes deployed with `--parallel` record their steps concurrently
+    profiles: std::sync::Mutex<Vec<ProfileReport>>,
+}
+
+impl DeployReport {
+    /// Lists a profile that is going to be deployed, with all of its steps still pending
+    fn add(&self, repo: &str, deploy_data: &deploy::DeployData) {
+        self.profiles.lock().unwrap().push(ProfileReport {
+            repo: repo.to_string(),
+            node: deploy_data.node_name.to_string(),
+            profile: deploy_data.profile_name.to_string(),
+            build: StepStatus::Pending,
+            push: StepStatus::Pending,
+            activation: StepStatus::Pending,
+            confirmed: false,
+            rolled_back: false,
+            elapsed_secs: 0.0,
+            started: None,
+        });
+    }
+
+    /// Records the outcome of a step of the given profile, as it happens
+    fn update(
+        &self,
+        repo: &str,
+        deploy_data: &deploy::DeployData,
+        f: impl FnOnce(&mut ProfileReport),
+    ) {
+        let mut profiles = self.profiles.lock().unwrap();
+
+        let profile = match profiles.iter_mut().find(|p| {
+            p.repo == repo
+                && p.node == deploy_data.node_name
+                && p.profile == deploy_data.profile_name
+        }) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let started = *profile.started.get_or_insert_with(Instant::now);
+        f(profile);
+        profile.elapsed_secs = started.elapsed().as_secs_f64();
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum WriteReportError {
+    #[error("Failed to serialize the deployment report: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Failed to write the deployment report: {0}")]
+    Write(#[from] std::io::Error),
+}
+
+async fn write_report(
+    path: &Path,
+    mut report: DeployReport,
+    result: &Result<(), RunError>,
+    started: Instant,
+) -> Result<(), WriteReportError> {
+    report.success = result.is_ok();
+    report.error = result.as_ref().err().map(|e| e.to_string());
+    report.elapsed_secs = started.elapsed().as_secs_f64();
+
+    tokio::fs::write(path, serde_json::to_string_pretty(&report)?).await?;
+
+    Ok(())
+}
+
+/// Revokes the given deployments, except for profiles which have auto rollback disabled
+async fn revoke_all(
+    deployments: &[(&str, &deploy::DeployData<'_>, &deploy::DeployDefs)],
+    report: &DeployReport,
+) -> Result<(), RunDeployError> {
+    for (repo, deploy_data, deploy_defs) in deployments {
+        if deploy_data.merged_settings.auto_rollback.unwrap_or(true) {
+            for_profile(
+                deploy_data,
+                deploy::deploy::revoke(deploy_data, deploy_defs),
+            )
+            .await
+            .map_err(|e| {
+                RunDeployError::RevokeProfile(
+                    deploy_data.node_name.to_string(),
+                    deploy_data.profile_name.to_string(),
+                    e,
+                )
+            })?;
+
+            report.update(repo, deploy_data, |p| p.rolled_back = true);
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits items into runs of consecutive items belonging to the same node
+fn group_per_node<T, K: PartialEq>(items: Vec<T>, node_of: impl Fn(&T) -> K) -> Vec<Vec<T>> {
+    let mut groups: Vec<Vec<T>> = Vec::new();
+
+    for item in items {
+        match groups.last_mut() {
+            Some(group) if node_of(&group[0]) == node_of(&item) => group.push(item),
+            _ => groups.push(vec![item]),
+        }
+    }
+
+    groups
+}
+
+/// Reverses the order of consecutive items belonging to the same node, keeping the order of nodes
+fn reverse_per_node<T, K: PartialEq>(items: Vec<T>, node_of: impl Fn(&T) -> K) -> Vec<T> {
+    group_per_node(items, node_of)
+        .into_iter()
+        .flat_map(|group| group.into_iter().rev())
+        .collect()
+}
+
+#[test]
+fn test_reverse_per_node() {
+    assert_eq!(
+        reverse_per_node(
+            vec![("a", 1), ("a", 2), ("a", 3), ("b", 1), ("c", 1), ("c", 2)],
+            |(node, _)| *node
+        ),
+        vec![("a", 3), ("a", 2), ("a", 1), ("b", 1), ("c", 2), ("c", 1)]
+    );
+}
+
+#[test]
+fn test_group_per_node() {
+    assert_eq!(
+        group_per_node(vec![("a", 1), ("a", 2), ("b", 1), ("a", 3)], |(node, _)| {
+            *node
+        }),
+        vec![vec![("a", 1), ("a", 2)], vec![("b", 1)], vec![("a", 3)]]
+    );
+}
+
+/// What became of a group of profiles deployed one after another
+struct GroupOutcome<'a> {
+    succeeded: Vec<(&'a str, &'a deploy::DeployData<'a>, &'a deploy::DeployDefs)>,
+    /// Deployments waiting for the single confirmation of `--copy-first-then-confirm-all`,
+    /// with the time at which they roll back on their own
+    unconfirmed: Vec<(
+        &'a str,
+        &'a deploy::DeployData<'a>,
+        &'a deploy::DeployDefs,
+        Instant,
+    )>,
+    /// The profile whose activation failed, if that is how the group failed
+    failed: Option<(&'a str, &'a deploy::DeployData<'a>)>,
+    result: Result<(), RunDeployError>,
+}
+
+/// Deploys up to `parallel` groups at the same time. Once a group fails, `group_failed` is set so
+/// that no further profiles are deployed, only the nodes already in progress finish theirs
+async fn deploy_groups<'a, G, F: Future<Output = GroupOutcome<'a>>>(
+    groups: Vec<G>,
+    parallel: usize,
+    group_failed: &AtomicBool,
+    deploy_group: impl FnMut(G) -> F,
+) -> Vec<GroupOutcome<'a>> {
+    futures_util::stream::iter(groups)
+        .map(deploy_group)
+        .buffer_unordered(parallel)
+        .inspect(|outcome| {
+            if outcome.result.is_err() {
+                group_failed.store(true, Ordering::SeqCst);
+            }
+        })
+        .collect()
+        .await
+}
+
+#[tokio::test]
+async fn test_deploy_groups_stops_after_failure() {
+    let group_failed = AtomicBool::new(false);
+    let deployed = std::sync::Mutex::new(Vec::new());
+
+    let outcomes = deploy_groups(vec!["a", "b", "c"], 1, &group_failed, |node| {
+        let (group_failed, deployed) = (&group_failed, &deployed);
+        async move {
+            let result = if group_failed.load(Ordering::SeqCst) {
+                Err(RunDeployError::OtherNodeFailed)
+            } else {
+                deployed.lock().unwrap().push(node);
+                Err(RunDeployError::NodeNotFound(node.to_string()))
+            };
+
+            GroupOutcome {
+                succeeded: vec![],
+                unconfirmed: vec![],
+                failed: None,
+                result,
+            }
+        }
+    })
+    .await;
+
+    assert_eq!(*deployed.lock().unwrap(), vec!["a"]);
+    assert!(matches!(
+        outcomes[1].result,
+        Err(RunDeployError::OtherNodeFailed)
+    ));
+}
+
+/// Pushes and activates a group of profiles, keeping the deployments that went through even if a
+/// later one fails so that they can be rolled back
+#[allow(clippy::too_many_arguments)]
+async fn deploy_group<'a>(
+    group: Vec<deploy::push::PushProfileData<'a>>,
+    dry_activate: bool,
+    boot: bool,
+    confirm_all: bool,
+    activate_only_if_push_changed: bool,
+    time_limit: Option<(Duration, tokio::time::Instant)>,
+    reverse_profiles: bool,
+    skip_push: bool,
+    system_check: bool,
+    report: &DeployReport,
+    group_failed: &AtomicBool,
+) -> GroupOutcome<'a> {
+    let mut succeeded = vec![];
+    let mut unconfirmed = vec![];
+    let mut failed = None;
+
+    let result = async {
+        // Along with the store path of the smoke test to run after activation, if any
+        let mut activations = Vec::new();
+        // The system of every node is only looked up once
+        let mut node_systems: HashMap<String, String> = HashMap::new();
+
+        for data in group {
+            check_interrupted()?;
+            if group_failed.load(Ordering::SeqCst) {
+                return Err(RunDeployError::OtherNodeFailed);
+            }
+
+            let (repo, deploy_data, deploy_defs) = (data.repo, data.deploy_data, data.deploy_defs);
+            let node_name: String = deploy_data.node_name.to_string();
+            let profile_name: String = deploy_data.profile_name.to_string();
+
+            let pushed: Result<_, RunDeployError> = for_profile(deploy_data, async {
+                revive_ssh_master(deploy_data, deploy_defs).await;
+
+                if let (Some(ref pre_deploy), false) =
+                    (&deploy_data.merged_settings.pre_deploy, dry_activate)
+                {
+                    run_hook("preDeploy", pre_deploy, deploy_data)
+                        .await
+                        .map_err(|e| {
+                            RunDeployError::PreDeploy(node_name.clone(), profile_name.clone(), e)
+                        })?;
+                }
+
+                if system_check && deploy_data.profile.profile_settings.system.is_some() {
+                    let node_system = match node_systems.get(&node_name) {
+                        Some(x) => x.clone(),
+                        None => {
+                            let node_system =
+                                deploy::push::node_system(&data).await.map_err(|e| {
+                                    RunDeployError::PushProfile(
+                                        node_name.clone(),
+                                        profile_name.clone(),
+                                        e,
+                                    )
+                                })?;
+                            node_systems.insert(node_name.clone(), node_system.clone());
+                            node_system
+                        }
+                    };
+
+                    deploy::push::check_system(&data, &node_system).map_err(|e| {
+                        RunDeployError::PushProfile(node_name.clone(), profile_name.clone(), e)
+                    })?;
+                }
+
+                let smoke_test = match deploy_data.profile.profile_settings.smoke_test {
+                    Some(ref smoke_test) if !dry_activate => Some(
+                        within_time_limit(
+                            time_limit,
+                            deploy::push::push_smoke_test(&data, smoke_test),
+                        )
+                        .await?
+                        .map_err(|e| {
+                            RunDeployError::PushProfile(node_name.clone(), profile_name.clone(), e)
+                        })?,
+                    ),
+                    _ => None,
+                };
+
+                // The operator vouches for the closure already being on the node
+                if skip_push {
+                    return Ok((deploy::push::PushProfileOutcome::AlreadyPresent, smoke_test));
+                }
+
+                let push_outcome = within_time_limit(time_limit, deploy::push::push_profile(data))
+                    .await?
+                    .map_err(|e| RunDeployError::PushProfile(node_name, profile_name, e))?;
+
+                Ok((push_outcome, smoke_test))
+            })
+            .await;
+
+            report.update(repo, deploy_data, |p| {
+                p.push = match pushed {
+                    Ok(_) if skip_push => StepStatus::Skipped,
+                    _ => StepStatus::of(&pushed),
+                }
+            });
+            let (push_outcome, smoke_test) = pushed?;
+
+            activations.push((repo, deploy_data, deploy_defs, push_outcome, smoke_test));
+        }
+
+        if reverse_profiles {
+            activations = reverse_per_node(activations, |(repo, deploy_data, _, _, _)| {
+                (*repo, deploy_data.node_name)
+            });
+        }
+
+        for (repo, deploy_data, deploy_defs, push_outcome, smoke_test) in activations {
+            check_interrupted()?;
+
+            // A closure that was already present may still need activating if the profile
+            // currently points somewhere else
+            let current = if activate_only_if_push_changed
+                && push_outcome == deploy::push::PushProfileOutcome::AlreadyPresent
+            {
+                let current = for_profile(
+                    deploy_data,
+                    deploy::deploy::profile_is_current(deploy_data, deploy_defs),
+                )
+                .await
+                .map_err(|e| {
+                    RunDeployError::CheckCurrentProfile(
+                        deploy_data.node_name.to_string(),
+                        deploy_data.profile_name.to_string(),
+                        e,
+                    )
+                });
+
+                if current.is_err() {
+                    report.update(repo, deploy_data, |p| p.activation = StepStatus::Failed);
+                }
+                current?
+            } else {
+                false
+            };
+
+            if current {
+                info!(
+                    "Profile `{}` for node `{}` is already active, skipping activation",
+                    deploy_data.profile_name, deploy_data.node_name
+                );
+                report.update(repo, deploy_data, |p| p.activation = StepStatus::Skipped);
+                continue;
+            }
+
+            let defer_confirm =
+                confirm_all && deploy_data.merged_settings.magic_rollback.unwrap_or(true);
+
+            // The node starts counting down its confirm timeout at some point during activation,
+            // so the time left to confirm is counted from before it starts
+            let activation_started = Instant::now();
+
+            // An activation interrupted by the time limit carries on on its own, with magic
+            // rollback it rolls back as it doesn't get confirmed
+            let activated = within_time_limit(
+                time_limit,
+                for_profile(
+                    deploy_data,
+                    deploy::deploy::deploy_profile(
+                        deploy_data,
+                        deploy_defs,
+                        dry_activate,
+                        boot,
+                        !defer_confirm,
+                        smoke_test.as_deref(),
+                    ),
+                ),
+            )
+            .await
+            .and_then(|x| {
+                x.map_err(|e| {
+                    RunDeployError::DeployProfile(
+                        deploy_data.node_name.to_string(),
+                        deploy_data.profile_name.to_string(),
+                        e,
+                    )
+                })
+            });
+
+            // `deploy_profile` confirms right away unless the confirmation is deferred
+            let confirmed = activated.is_ok()
+                && !defer_confirm
+                && !dry_activate
+                && !boot
+                && deploy_data.merged_settings.magic_rollback.unwrap_or(true);
+
+            report.update(repo, deploy_data, |p| {
+                p.activation = StepStatus::of(&activated);
+                p.confirmed = confirmed;
+                // A failed health check deactivates the profile again
+                p.rolled_back = matches!(
+                    activated,
+                    Err(RunDeployError::DeployProfile(_, _, ref e)) if e.deactivated_profile()
+                );
+            });
+            if activated.is_err() {
+                failed = Some((repo, deploy_data));
+            }
+            activated?;
+
+            if defer_confirm {
+                let confirm_timeout = deploy_data.confirm_timeout();
+
+                unconfirmed.push((
+                    repo,
+                    deploy_data,
+                    deploy_defs,
+                    activation_started + Duration::from_secs(confirm_timeout.into()),
+                ));
+            } else {
+                succeeded.push((repo, deploy_data, deploy_defs))
+            }
+        }
+
+        Ok(())
+    }
+    .await;
+
+    GroupOutcome {
+        succeeded,
+        unconfirmed,
+        failed,
+        result,
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HookError {
+    #[error("Failed to run the hook: {0}")]
+    Run(std::io::Error),
+    #[error("The hook resulted in a bad exit code: {0:?}")]
+    Exit(Option<i32>),
+}
+
+/// Command running a `preDeploy` or `postDeploy` hook locally, told what is deployed where
+/// through the environment
+fn build_hook_command(
+    hook: &str,
+    node: &str,
+    profile: &str,
+    closure: &str,
+    hostname: &str,
+) -> std::process::Command {
+    let mut command = std::process::Command::new("bash");
+    command
+        .arg("-c")
+        .arg(hook)
+        .env("DEPLOY_NODE", node)
+        .env("DEPLOY_PROFILE", profile)
+        .env("DEPLOY_CLOSURE", closure)
+        .env("DEPLOY_HOSTNAME", hostname);
+    command
+}
+
+#[test]
+fn test_build_hook_command() {
+    let command = build_hook_command(
+        "notify \"$DEPLOY_NODE\"",
+        "web",
+        "system",
+        "/nix/store/blah-system",
+        "web.example.com",
+    );
+    assert_eq!(command.get_program(), "bash");
+    assert_eq!(
+        command.get_args().collect::<Vec<_>>(),
+        ["-c", "notify \"$DEPLOY_NODE\""]
+    );
+    assert_eq!(
+        command.get_envs().collect::<Vec<_>>(),
+        [
+            (
+                "DEPLOY_CLOSURE".as_ref(),
+                Some("/nix/store/blah-system".as_ref())
+            ),
+            ("DEPLOY_HOSTNAME".as_ref(), Some("web.example.com".as_ref())),
+            ("DEPLOY_NODE".as_ref(), Some("web".as_ref())),
+            ("DEPLOY_PROFILE".as_ref(), Some("system".as_ref())),
+        ]
+    );
+}
+
+#[derive(Error, Debug)]
+pub enum HostnameCommandError {
+    #[error("Failed to run the hostnameCommand: {0}")]
+    Run(std::io::Error),
+    #[error("The hostnameCommand resulted in a bad exit code: {0:?}")]
+    Exit(Option<i32>),
+    #[error("The output of the hostnameCommand contained an invalid UTF-8 sequence: {0}")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("The hostnameCommand printed no hostname")]
+    Empty,
+}
+
+/// Command running the `hostnameCommand` of a node locally, with the name of the node as `$1` and
+/// in `DEPLOY_NODE`
+fn build_hostname_command(hostname_command: &str, node: &str) -> std::process::Command {
+    let mut command = std::process::Command::new("bash");
+    command
+        .arg("-c")
+        .arg(hostname_command)
+        .arg("hostnameCommand")
+        .arg(node)
+        .env("DEPLOY_NODE", node);
+    command
+}
+
+#[test]
+fn test_build_hostname_command() {
+    let command =
+        build_hostname_command("tailscale ip -4 \"$1\" | head -n1 # first address", "web");
+    assert_eq!(command.get_program(), "bash");
+    assert_eq!(
+        command.get_args().collect::<Vec<_>>(),
+        [
+            "-c",
+            "tailscale ip -4 \"$1\" | head -n1 # first address",
+            "hostnameCommand",
+            "web"
+        ]
+    );
+    assert!(command
+        .get_envs()
+        .any(|(k, v)| k == "DEPLOY_NODE" && v == Some("web".as_ref())));
+}
+
+/// Runs the `hostnameCommand` of a node, returning the hostname it printed
+async fn resolve_hostname(
+    hostname_command: &str,
+    node: &str,
+) -> Result<String, HostnameCommandError> {
+    debug!(
+        "Resolving the hostname of node `{}` with `{}`",
+        node, hostname_command
+    );
+
+    let output = Command::from(build_hostname_command(hostname_command, node))
+        .stderr(Stdio::inherit())
+        .output()
+        .await
+        .map_err(HostnameCommandError::Run)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(HostnameCommandError::Exit(a)),
+    };
+
+    let hostname = String::from_utf8(output.stdout)?.trim().to_string();
+    if hostname.is_empty() {
+        return Err(HostnameCommandError::Empty);
+    }
+
+    info!("Resolved the hostname of node `{}` to {}", node, hostname);
+    Ok(hostname)
+}
+
+async fn run_hook(
+    name: &str,
+    hook: &str,
+    deploy_data: &deploy::DeployData<'_>,
+) -> Result<(), HookError> {
+    info!(
+        "Running the {} hook of profile `{}` for node `{}`",
+        name, deploy_data.profile_name, deploy_data.node_name
+    );
+
+    let hostname = deploy_data.hostname();
+
+    let exit_status = Command::from(build_hook_command(
+        hook,
+        deploy_data.node_name,
+        deploy_data.profile_name,
+        &deploy_data.profile.profile_settings.path,
+        hostname,
+    ))
+    .kill_on_drop(true)
+    .status()
+    .await
+    .map_err(HookError::Run)?;
+
+    match exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(HookError::Exit(a)),
+    }
+}
+
+/// Runs `f` with the node and profile of `deploy_data` attached to its JSON log lines
+async fn for_profile<F: std::future::Future>(
+    deploy_data: &deploy::DeployData<'_>,
+    f: F,
+) -> F::Output {
+    deploy::with_log_context(
+        Some(deploy_data.node_name),
+        Some(deploy_data.profile_name),
+        f,
+    )
+    .await
+}
+
+/// An SSH control master opened because of `--ssh-multiplex` or `--keep-ssh-on-failure`
+struct SshMaster {
+    ssh_addr: String,
+    ssh_opts: Vec<String>,
+}
+
+/// Closes the SSH masters once the deployment finishes, or tells how to use them if they are kept
+async fn finish_ssh_masters(ssh_masters: &[SshMaster], keep: bool) {
+    if keep {
+        info!(
+            "Keeping the SSH connections open, connect over them with:\n{}",
+            ssh_masters
+                .iter()
+                .map(|m| format!(
+                    "ssh -o ControlPath={} {}",
+                    deploy::ssh_master_control_path().display(),
+                    m.ssh_addr
+                ))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+        return;
+    }
+
+    for ssh_master in ssh_masters {
+        let exit_status = deploy::ssh_command()
+            .args(&ssh_master.ssh_opts)
+            .arg("-O")
+            .arg("exit")
+            .arg(&ssh_master.ssh_addr)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+
+        match exit_status {
+            Ok(s) if s.success() => (),
+            // There is no master if the deployment never connected to the node
+            Ok(s) => debug!(
+                "No SSH master to close for {}: {:?}",
+                ssh_master.ssh_addr,
+                s.code()
+            ),
+            Err(e) => debug!(
+                "Failed to close the SSH master for {}: {}",
+                ssh_master.ssh_addr, e
+            ),
+        }
+    }
+}
+
+/// How long `ssh -O check` may take before the SSH master is considered hung
+const SSH_MASTER_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What `ssh -O check` tells about the SSH master of a node
+#[derive(Debug, PartialEq)]
+enum SshMasterState {
+    Alive,
+    /// No master was opened yet, the next SSH invocation opens one
+    Missing,
+    /// The master is gone or doesn't answer anymore (e.g. after a network blip or the laptop sleeping)
+    Dead,
+}
+
+fn ssh_master_state(success: bool, stderr: &str) -> SshMasterState {
+    if success {
+        SshMasterState::Alive
+    } else if stderr.contains("No such file or directory") {
+        SshMasterState::Missing
+    } else {
+        SshMasterState::Dead
+    }
+}
+
+#[test]
+fn test_ssh_master_state() {
+    assert_eq!(
+        ssh_master_state(true, "Master running (pid=4242)\n"),
+        SshMasterState::Alive
+    );
+    assert_eq!(
+        ssh_master_state(
+            false,
+            "Control socket connect(/tmp/deploy-rs-abc): No such file or directory\n"
+        ),
+        SshMasterState::Missing
+    );
+    assert_eq!(
+        ssh_master_state(
+            false,
+            "Control socket connect(/tmp/deploy-rs-abc): Connection refused\n"
+        ),
+        SshMasterState::Dead
+    );
+}
+
+/// Makes sure the SSH master of the node is usable before deploying a profile over it. A dead one
+/// is closed, so that the next SSH invocation opens a new one instead of failing.
+async fn revive_ssh_master(deploy_data: &deploy::DeployData<'_>, deploy_defs: &deploy::DeployDefs) {
+    // Only the masters opened by `--ssh-multiplex` are ours to replace
+    if !deploy_data.cmd_overrides.ssh_multiplex
+        || deploy_data.cmd_overrides.ssh_control_path.is_some()
+        || deploy_data.merged_settings.local_deploy.unwrap_or(false)
+    {
+        return;
+    }
+
+    let hostname = deploy_data.hostname();
+    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+
+    let check = deploy::ssh_command()
+        .args(&deploy_data.merged_settings.ssh_opts)
+        .arg("-O")
+        .arg("check")
+        .arg(&ssh_addr)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output();
+
+    let state = match tokio::time::timeout(SSH_MASTER_CHECK_TIMEOUT, check).await {
+        Ok(Ok(output)) => ssh_master_state(
+            output.status.success(),
+            &String::from_utf8_lossy(&output.stderr),
+        ),
+        Ok(Err(e)) => {
+            debug!("Failed to check the SSH master for {}: {}", ssh_addr, e);
+            return;
+        }
+        Err(_) => SshMasterState::Dead,
+    };
+
+    if state != SshMasterState::Dead {
+        return;
+    }
+
+    warn!(
+        "The SSH connection to {} was lost, opening a new one",
+        ssh_addr
+    );
+
+    // A stale control socket is removed by the next SSH invocation when it becomes the master
+    let exit = deploy::ssh_command()
+        .args(&deploy_data.merged_settings.ssh_opts)
+        .arg("-O")
+        .arg("exit")
+        .arg(&ssh_addr)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+
+    if let Ok(Err(e)) = tokio::time::timeout(SSH_MASTER_CHECK_TIMEOUT, exit).await {
+        debug!("Failed to close the SSH master for {}: {}", ssh_addr, e);
+    }
+}
+
+/// Why a node couldn't be reached, from the output of the failed `ssh`
+fn unreachable_reason(stderr: &str, code: Option<i32>) -> String {
+    match stderr.lines().map(str::trim).rev().find(|l| !l.is_empty()) {
+        Some(line) => line.to_string(),
+        None => format!("ssh exited with {:?}", code),
+    }
+}
+
+#[test]
+fn test_unreachable_reason() {
+    assert_eq!(
+        unreachable_reason(
+            "Warning: Permanently added 'web' to the list of known hosts.\nssh: connect to host web port 22: Connection timed out\n",
+            Some(255)
+        ),
+        "ssh: connect to host web port 22: Connection timed out"
+    );
+    assert_eq!(
+        unreachable_reason("", Some(255)),
+        "ssh exited with Some(255)"
+    );
+}
+
+/// Connects to every node once before building, so that unreachable nodes are reported all at
+/// once instead of failing the deployment when it gets to them
+async fn check_reachable(
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData<'_>,
+        deploy::DeployDefs,
+    )],
+    timeout_connect: u16,
 ) -> Result<(), RunDeployError> {
-    let to_deploy: ToDeploy = deploy_flakes
+    // Nodes sharing a host and SSH user are only connected to once
+    let mut targets: Vec<(String, Vec<&str>, &Vec<String>)> = Vec::new();
+    for (_, deploy_data, deploy_defs) in parts {
+        // Nodes deployed locally aren't connected to
+        if deploy_data.merged_settings.local_deploy.unwrap_or(false) {
+            continue;
+        }
+
+        let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, deploy_data.hostname());
+        match targets.iter_mut().find(|(addr, _, _)| *addr == ssh_addr) {
+            Some((_, nodes, _)) => {
+                if !nodes.contains(&deploy_data.node_name) {
+                    nodes.push(deploy_data.node_name);
+                }
+            }
+            None => targets.push((
+                ssh_addr,
+                vec![deploy_data.node_name],
+                &deploy_data.merged_settings.ssh_opts,
+            )),
+        }
+    }
+
+    info!("Checking that {} node(s) are reachable", targets.len());
+
+    let checks = targets
         .iter()
-        .zip(&data)
-        .map(|(deploy_flake, data)| {
-            let to_deploys: ToDeploy = match (&deploy_flake.node, &deploy_flake.profile) {
-                (Some(node_name), Some(profile_name)) => {
-                    let node = match data.nodes.get(node_name) {
+        .map(|(ssh_addr, nodes, ssh_opts)| async move {
+            debug!("Checking that {} is reachable", ssh_addr);
+
+            let output = deploy::ssh_command()
+                .arg("-o")
+                .arg(format!("ConnectTimeout={}", timeout_connect))
+                .arg(ssh_addr)
+                .args(ssh_opts.iter())
+                .arg("true")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .output()
+                .await;
+
+            let reason = match output {
+                Ok(output) if output.status.success() => return None,
+                Ok(output) => unreachable_reason(
+                    &String::from_utf8_lossy(&output.stderr),
+                    output.status.code(),
+                ),
+                Err(e) => format!("failed to run ssh: {}", e),
+            };
+
+            Some(format!("  {} ({}): {}", nodes.join(", "), ssh_addr, reason))
+        });
+
+    let unreachable: Vec<String> = futures_util::future::join_all(checks)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+
+    if !unreachable.is_empty() {
+        return Err(RunDeployError::Unreachable(unreachable));
+    }
+
+    Ok(())
+}
+
+/// Where on which host a profile gets deployed, and what it gets
+#[derive(Debug)]
+struct ProfileLocation<'a> {
+    host: String,
+    /// Path of the profile, or its user and name
+    profile: String,
+    node: &'a str,
+    path: &'a str,
+}
+
+/// Fails if one profile of a host would get two different closures, which would race each other.
+/// Deploying the same closure twice only wastes time.
+fn check_profile_locations(locations: &[ProfileLocation]) -> Result<(), RunDeployError> {
+    for (i, a) in locations.iter().enumerate() {
+        for b in &locations[..i] {
+            if a.host != b.host || a.profile != b.profile {
+                continue;
+            }
+
+            if a.path != b.path {
+                return Err(RunDeployError::ConflictingProfiles(
+                    a.host.clone(),
+                    a.profile.clone(),
+                    b.node.to_string(),
+                    a.node.to_string(),
+                ));
+            }
+
+            warn!(
+                "Profile {} of host {} is deployed twice (as node `{}` and `{}`)",
+                a.profile, a.host, b.node, a.node
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check_profile_locations() {
+    let location = |host: &str, node: &'static str, path: &'static str| ProfileLocation {
+        host: host.to_string(),
+        profile: "root/system".to_string(),
+        node,
+        path,
+    };
+
+    assert!(check_profile_locations(&[
+        location("web.example.com", "web", "/nix/store/a-system"),
+        location("db.example.com", "db", "/nix/store/b-system"),
+        location("web.example.com", "web-alias", "/nix/store/a-system"),
+    ])
+    .is_ok());
+    assert!(matches!(
+        check_profile_locations(&[
+            location("web.example.com", "web", "/nix/store/a-system"),
+            location("web.example.com", "web-alias", "/nix/store/b-system"),
+        ]),
+        Err(RunDeployError::ConflictingProfiles(_, _, ref a, ref b)) if a == "web" && b == "web-alias"
+    ));
+}
+
+type ToDeploy<'a> = Vec<(
+    &'a deploy::DeployFlake<'a>,
+    &'a deploy::data::Data,
+    &'a deploy::CmdOverrides,
+    (&'a str, &'a deploy::data::Node),
+    (&'a str, &'a deploy::data::Profile),
+)>;
+
+type SelectedProfiles<'a> = Vec<(
+    (&'a str, &'a deploy::data::Node),
+    (&'a str, &'a deploy::data::Profile),
+)>;
+
+/// Orders the profiles of a node so that each comes after the profiles it `dependsOn`, keeping
+/// their order (from `profilesOrder`) where the dependencies allow it
+fn order_by_dependencies<'a>(
+    node_name: &str,
+    profiles: Vec<(&'a str, &'a deploy::data::Profile)>,
+) -> Result<Vec<(&'a str, &'a deploy::data::Profile)>, RunDeployError> {
+    for (profile_name, profile) in &profiles {
+        if let Some(dependency) = profile
+            .profile_settings
+            .depends_on
+            .iter()
+            .find(|d| !profiles.iter().any(|(n, _)| n == d))
+        {
+            return Err(RunDeployError::UnknownDependency(
+                node_name.to_string(),
+                profile_name.to_string(),
+                dependency.clone(),
+            ));
+        }
+    }
+
+    let mut remaining = profiles;
+    let mut ordered: Vec<(&str, &deploy::data::Profile)> = Vec::new();
+
+    while !remaining.is_empty() {
+        let ready = remaining.iter().position(|(_, profile)| {
+            profile
+                .profile_settings
+                .depends_on
+                .iter()
+                .all(|d| ordered.iter().any(|(n, _)| n == d))
+        });
+
+        match ready {
+            Some(i) => ordered.push(remaining.remove(i)),
+            None => {
+                return Err(RunDeployError::DependencyCycle(
+                    node_name.to_string(),
+                    remaining.iter().map(|(n, _)| n.to_string()).collect(),
+                ))
+            }
+        }
+    }
+
+    Ok(ordered)
+}
+
+#[test]
+fn test_order_by_dependencies() {
+    let profile = |depends_on: &[&str]| -> deploy::data::Profile {
+        serde_json::from_value(serde_json::json!({
+            "path": "/nix/store/blah",
+            "dependsOn": depends_on,
+        }))
+        .unwrap()
+    };
+    let (system, app, monitoring) = (profile(&[]), profile(&["db", "system"]), profile(&[]));
+    let db = profile(&["system"]);
+
+    let order = |profiles| {
+        order_by_dependencies("node", profiles).map(|o| {
+            o.into_iter()
+                .map(|(n, _)| n.to_string())
+                .collect::<Vec<String>>()
+        })
+    };
+
+    assert_eq!(
+        order(vec![
+            ("app", &app),
+            ("monitoring", &monitoring),
+            ("db", &db),
+            ("system", &system)
+        ])
+        .unwrap(),
+        ["monitoring", "system", "db", "app"]
+    );
+
+    let cyclic = profile(&["app"]);
+    assert!(matches!(
+        order(vec![("system", &system), ("db", &cyclic), ("app", &app)]),
+        Err(RunDeployError::DependencyCycle(_, ref p)) if p == &["db", "app"]
+    ));
+    assert!(matches!(
+        order(vec![("app", &app), ("system", &system)]),
+        Err(RunDeployError::UnknownDependency(_, _, ref d)) if d == "db"
+    ));
+}
+
+/// Fails for a profile that `dependsOn` others, as `--reverse-profiles` would activate it before them
+fn check_reversible(
+    node_name: &str,
+    profile_name: &str,
+    profile: &deploy::data::Profile,
+) -> Result<(), RunDeployError> {
+    if profile.profile_settings.depends_on.is_empty() {
+        Ok(())
+    } else {
+        Err(RunDeployError::ReverseDependencies(
+            node_name.to_string(),
+            profile_name.to_string(),
+        ))
+    }
+}
+
+#[test]
+fn test_check_reversible() {
+    let profile = |depends_on: &[&str]| -> deploy::data::Profile {
+        serde_json::from_value(serde_json::json!({
+            "path": "/nix/store/blah",
+            "dependsOn": depends_on,
+        }))
+        .unwrap()
+    };
+
+    assert!(check_reversible("node", "system", &profile(&[])).is_ok());
+    assert!(matches!(
+        check_reversible("node", "app", &profile(&["system"])),
+        Err(RunDeployError::ReverseDependencies(_, ref p)) if p == "app"
+    ));
+}
+
+/// Looks up the node named in a flake fragment, expanding it against all nodes if it is a glob
+fn matching_nodes<'a>(
+    nodes: &'a HashMap<String, deploy::data::Node>,
+    node_name: &str,
+) -> Result<Vec<(&'a String, &'a deploy::data::Node)>, RunDeployError> {
+    let mut matches: Vec<_> = if deploy::is_node_glob(node_name) {
+        nodes
+            .iter()
+            .filter(|(name, _)| deploy::node_matches_glob(node_name, name))
+            .collect()
+    } else {
+        nodes.get_key_value(node_name).into_iter().collect()
+    };
+
+    if matches.is_empty() {
+        return Err(RunDeployError::NodeNotFound(node_name.to_string()));
+    }
+
+    matches.sort_by_key(|(name, _)| *name);
+    Ok(matches)
+}
+
+/// The nodes and profiles a flake fragment selects from the evaluated deploy data, in the order
+/// they get deployed in
+fn select_profiles<'a>(
+    deploy_flake: &deploy::DeployFlake<'_>,
+    data: &'a deploy::data::Data,
+    exclude_nodes: &[String],
+) -> Result<SelectedProfiles<'a>, RunDeployError> {
+    let selected = match (&deploy_flake.node, &deploy_flake.profile) {
+        (Some(node_name), Some(profile_name)) => {
+            let mut l = Vec::new();
+
+            for (node_name, node) in matching_nodes(&data.nodes, node_name)? {
+                // The name from the data, which outlives the flake fragment
+                let (profile_name, profile) =
+                    match node.node_settings.profiles.get_key_value(profile_name) {
                         Some(x) => x,
-                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
+                        None => return Err(RunDeployError::ProfileNotFound(profile_name.clone())),
                     };
+
+                l.push(((node_name.as_str(), node), (profile_name.as_str(), profile)));
+            }
+
+            l
+        }
+        (Some(node_name), None) => {
+            let mut l = Vec::new();
+
+            for (node_name, node) in matching_nodes(&data.nodes, node_name)? {
+                let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
+
+                for profile_name in [
+                    node.node_settings.profiles_order.iter().collect(),
+                    node.node_settings.profiles.keys().collect::<Vec<&String>>(),
+                ]
+                .concat()
+                {
                     let profile = match node.node_settings.profiles.get(profile_name) {
                         Some(x) => x,
                         None => return Err(RunDeployError::ProfileNotFound(profile_name.clone())),
                     };
 
-                    vec![(
-                        deploy_flake,
-                        data,
-                        (node_name.as_str(), node),
-                        (profile_name.as_str(), profile),
-                    )]
+                    if !profiles_list.iter().any(|(n, _)| n == profile_name) {
+                        profiles_list.push((profile_name, profile));
+                    }
+                }
+
+                l.extend(
+                    order_by_dependencies(node_name, profiles_list)?
+                        .into_iter()
+                        .map(|x| ((node_name.as_str(), node), x)),
+                );
+            }
+
+            l
+        }
+        (None, None) => {
+            let mut l = Vec::new();
+
+            for (node_name, node) in &data.nodes {
+                if exclude_nodes.contains(node_name) {
+                    info!("Excluding node `{}` from the deployment", node_name);
+                    continue;
                 }
-                (Some(node_name), None) => {
-                    let node = match data.nodes.get(node_name) {
+
+                let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
+
+                for profile_name in [
+                    node.node_settings.profiles_order.iter().collect(),
+                    node.node_settings.profiles.keys().collect::<Vec<&String>>(),
+                ]
+                .concat()
+                {
+                    let profile = match node.node_settings.profiles.get(profile_name) {
                         Some(x) => x,
-                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
+                        None => return Err(RunDeployError::ProfileNotFound(profile_name.clone())),
                     };
 
-                    let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
-
-                    for profile_name in [
-                        node.node_settings.profiles_order.iter().collect(),
-                        node.node_settings.profiles.keys().collect::<Vec<&String>>(),
-                    ]
-                    .concat()
-                    {
-                        let profile = match node.node_settings.profiles.get(profile_name) {
-                            Some(x) => x,
-                            None => {
-                                return Err(RunDeployError::ProfileNotFound(profile_name.clone()))
-                            }
-                        };
-
-                        if !profiles_list.iter().any(|(n, _)| n == profile_name) {
-                            profiles_list.push((profile_name, profile));
-                        }
+                    if !profiles_list.iter().any(|(n, _)| n == profile_name) {
+                        profiles_list.push((profile_name, profile));
                     }
+                }
 
-                    profiles_list
+                l.extend(
+                    order_by_dependencies(node_name, profiles_list)?
                         .into_iter()
-                        .map(|x| (deploy_flake, data, (node_name.as_str(), node), x))
-                        .collect()
-                }
-                (None, None) => {
-                    let mut l = Vec::new();
-
-                    for (node_name, node) in &data.nodes {
-                        let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
-
-                        for profile_name in [
-                            node.node_settings.profiles_order.iter().collect(),
-                            node.node_settings.profiles.keys().collect::<Vec<&String>>(),
-                        ]
-                        .concat()
-                        {
-                            let profile = match node.node_settings.profiles.get(profile_name) {
-                                Some(x) => x,
-                                None => {
-                                    return Err(RunDeployError::ProfileNotFound(
-                                        profile_name.clone(),
-                                    ))
-                                }
-                            };
+                        .map(|x| ((node_name.as_str(), node), x)),
+                );
+            }
 
-                            if !profiles_list.iter().any(|(n, _)| n == profile_name) {
-                                profiles_list.push((profile_name, profile));
-                            }
-                        }
+            l
+        }
+        (None, Some(_)) => return Err(RunDeployError::ProfileWithoutNode),
+    };
+    Ok(selected)
+}
 
-                        let ll: ToDeploy = profiles_list
-                            .into_iter()
-                            .map(|x| (deploy_flake, data, (node_name.as_str(), node), x))
-                            .collect();
+/// Makes sure every node given with `--nodes` is one of the targets, rather than deploying nothing
+/// for a node that is only in the flake
+fn check_only_nodes(selected_nodes: &[&str], only_nodes: &[String]) -> Result<(), RunDeployError> {
+    match only_nodes
+        .iter()
+        .find(|node_name| !selected_nodes.contains(&node_name.as_str()))
+    {
+        Some(node_name) => Err(RunDeployError::NodeNotFound(node_name.clone())),
+        None => Ok(()),
+    }
+}
 
-                        l.extend(ll);
-                    }
+#[test]
+fn test_check_only_nodes() {
+    let data: deploy::data::Data = serde_json::from_value(serde_json::json!({
+        "nodes": {
+            "web": { "hostname": "web", "profiles": { "system": { "path": "/nix/store/aaaa-web" } } },
+            "db": { "hostname": "db", "profiles": { "system": { "path": "/nix/store/bbbb-db" } } }
+        }
+    }))
+    .unwrap();
+
+    let flake = deploy::parse_flake(".#db").unwrap();
+    let selected: Vec<&str> = select_profiles(&flake, &data, &[])
+        .unwrap()
+        .into_iter()
+        .map(|((node_name, _), _)| node_name)
+        .collect();
+
+    assert!(check_only_nodes(&selected, &["db".to_string()]).is_ok());
+    // In the flake, but not a target
+    assert!(matches!(
+        check_only_nodes(&selected, &["web".to_string()]),
+        Err(RunDeployError::NodeNotFound(node)) if node == "web"
+    ));
+}
+
+/// The store path each profile of the targets resolves to, as `(node, profile, path)`, without
+/// building or deploying anything, e.g. to register GC roots for them or record what was deployed.
+/// `data` is the evaluated deploy output of each of the `deploy_flakes`.
+pub fn profile_paths(
+    deploy_flakes: &[deploy::DeployFlake<'_>],
+    data: &[deploy::data::Data],
+) -> Result<Vec<(String, String, String)>, RunDeployError> {
+    let mut paths = Vec::new();
+
+    for (deploy_flake, data) in deploy_flakes.iter().zip(data) {
+        for ((node_name, _), (profile_name, profile)) in select_profiles(deploy_flake, data, &[])? {
+            paths.push((
+                node_name.to_string(),
+                profile_name.to_string(),
+                profile.profile_settings.path.clone(),
+            ));
+        }
+    }
+
+    Ok(paths)
+}
 
-                    l
+#[test]
+fn test_profile_paths() {
+    let data: deploy::data::Data = serde_json::from_value(serde_json::json!({
+        "nodes": {
+            "web": {
+                "hostname": "web.example.com",
+                "profilesOrder": ["system", "app"],
+                "profiles": {
+                    "app": { "path": "/nix/store/aaaa-app" },
+                    "system": { "path": "/nix/store/bbbb-system" }
                 }
-                (None, Some(_)) => return Err(RunDeployError::ProfileWithoutNode),
-            };
-            Ok(to_deploys)
+            }
+        }
+    }))
+    .unwrap();
+
+    assert_eq!(
+        profile_paths(
+            &[deploy::parse_flake(".#web").unwrap()],
+            std::slice::from_ref(&data)
+        )
+        .unwrap(),
+        [
+            (
+                "web".to_string(),
+                "system".to_string(),
+                "/nix/store/bbbb-system".to_string()
+            ),
+            (
+                "web".to_string(),
+                "app".to_string(),
+                "/nix/store/aaaa-app".to_string()
+            ),
+        ]
+    );
+    assert_eq!(
+        profile_paths(&[deploy::parse_flake(".#web.app").unwrap()], &[data]).unwrap(),
+        [(
+            "web".to_string(),
+            "app".to_string(),
+            "/nix/store/aaaa-app".to_string()
+        )]
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_deploy(
+    deploy_flakes: Vec<deploy::DeployFlake<'_>>,
+    data: Vec<deploy::data::Data>,
+    supports_flakes: bool,
+    check_sigs: bool,
+    interactive: bool,
+    assume_yes: bool,
+    cmd_overrides: &[deploy::CmdOverrides],
+    keep_result: bool,
+    result_path: Option<&str>,
+    extra_build_args: &[String],
+    debug_logs: bool,
+    dry_activate: bool,
+    boot: bool,
+    log_dir: &Option<String>,
+    rollback_succeeded: bool,
+    activate_only_if_push_changed: bool,
+    plan: bool,
+    check_config: bool,
+    build_only: bool,
+    list: bool,
+    confirm_all: bool,
+    exclude_nodes: &[String],
+    auto_verbose_on_failure: bool,
+    gc_after: bool,
+    manifest: Option<(&Path, &serde_json::Value)>,
+    time_limit: Option<Duration>,
+    reverse_profiles: bool,
+    ssh_masters: Option<&mut Vec<SshMaster>>,
+    keep_going: bool,
+    parallel: usize,
+    only_nodes: &[String],
+    plan_only: bool,
+    plan_format: OutputFormat,
+    prebuilt: bool,
+    skip_push: bool,
+    rollback: bool,
+    rollback_on_interrupt: bool,
+    ask_sudo_password: bool,
+    system_check: bool,
+    timeout_connect: Option<u16>,
+    report: &DeployReport,
+) -> Result<(), RunDeployError> {
+    let time_limit = time_limit.map(|limit| (limit, tokio::time::Instant::now() + limit));
+
+    let to_deploy: ToDeploy = deploy_flakes
+        .iter()
+        .zip(&data)
+        .zip(cmd_overrides)
+        .map(|((deploy_flake, data), cmd_overrides)| {
+            Ok(select_profiles(deploy_flake, data, exclude_nodes)?
+                .into_iter()
+                .map(|(node, profile)| (deploy_flake, data, cmd_overrides, node, profile))
+                .collect::<ToDeploy>())
         })
         .collect::<Result<Vec<ToDeploy>, RunDeployError>>()?
         .into_iter()
         .flatten()
         .collect();
 
+    check_only_nodes(
+        &to_deploy
+            .iter()
+            .map(|(_, _, _, (node_name, _), _)| *node_name)
+            .collect::<Vec<_>>(),
+        only_nodes,
+    )?;
+
+    let to_deploy: ToDeploy = to_deploy
+        .into_iter()
+        .filter(|(_, _, _, (node_name, _), _)| {
+            only_nodes.is_empty() || only_nodes.iter().any(|n| n == node_name)
+        })
+        .collect();
+
     let mut parts: Vec<(
         &deploy::DeployFlake<'_>,
         deploy::DeployData,
         deploy::DeployDefs,
     )> = Vec::new();
 
-    for (deploy_flake, data, (node_name, node), (profile_name, profile)) in to_deploy {
-        let deploy_data = deploy::make_deploy_data(
+    // Only building or listing the profiles never connects to the nodes
+    let connecting = !check_config && !build_only && !list;
+
+    // Asked for with the first profile using sudo, before anything is deployed, so that the rest
+    // of the deployment can go unattended
+    let mut shared_sudo_password: Option<zeroize::Zeroizing<String>> = None;
+
+    // Sudo passwords are asked for once per SSH user and host, not once per profile
+    let mut sudo_passwords: HashMap<String, zeroize::Zeroizing<String>> = HashMap::new();
+    let mut sudo_secrets: HashMap<(PathBuf, String), zeroize::Zeroizing<String>> = HashMap::new();
+    let mut resolved_hostnames: HashMap<(String, String), String> = HashMap::new();
+
+    for (deploy_flake, data, cmd_overrides, (node_name, node), (profile_name, profile)) in to_deploy
+    {
+        let mut deploy_data = deploy::make_deploy_data(
             &data.generic_settings,
             node,
             node_name,
@@ -541,12 +3278,48 @@ async fn run_deploy(
             cmd_overrides,
             debug_logs,
             log_dir.as_deref(),
-        );
+        )?;
 
         let mut deploy_defs = deploy_data.defs()?;
 
-        if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
-            warn!("Interactive sudo is enabled! Using a sudo password is less secure than correctly configured SSH keys.\nPlease use keys in production environments.");
+        if check_config {
+            continue;
+        }
+
+        // Nodes are only connected to after building, so only resolved when deploying
+        if let (Some(hostname_command), None, true) = (
+            &deploy_data.merged_settings.hostname_command,
+            &deploy_data.cmd_overrides.hostname,
+            connecting,
+        ) {
+            // A node with several profiles only resolves its hostname once
+            let key = (hostname_command.clone(), node_name.to_string());
+            let hostname = match resolved_hostnames.get(&key) {
+                Some(hostname) => hostname.clone(),
+                None => {
+                    let hostname = resolve_hostname(hostname_command, node_name)
+                        .await
+                        .map_err(|e| RunDeployError::HostnameCommand(node_name.to_string(), e))?;
+                    resolved_hostnames.insert(key, hostname.clone());
+                    hostname
+                }
+            };
+            deploy_data.resolved_hostname = Some(hostname);
+        }
+
+        let interactive_sudo = deploy_data
+            .merged_settings
+            .interactive_sudo
+            .unwrap_or(false);
+        // Profiles deployed as the SSH user don't use sudo, and don't need the shared password
+        let shared_sudo = ask_sudo_password && deploy_defs.sudo.is_some();
+
+        if connecting
+            && (interactive_sudo || deploy_data.merged_settings.sudo_file.is_some() || shared_sudo)
+        {
+            if interactive_sudo {
+                warn!("Interactive sudo is enabled! Using a sudo password is less secure than correctly configured SSH keys.\nPlease use keys in production environments.");
+            }
 
             if deploy_data.merged_settings.sudo.is_some() {
                 warn!("Custom sudo commands should be configured to accept password input from stdin when using the 'interactive sudo' option. Deployment may fail if the custom command ignores stdin.");
@@ -557,19 +3330,197 @@ async fn run_deploy(
                 deploy_defs.sudo = Some(format!("{} -S -p \"\"", original));
             }
 
-            info!("You will now be prompted for the sudo password for {}.", node.node_settings.hostname);
-            let sudo_password = rpassword::prompt_password(format!("(sudo for {}) Password: ", node.node_settings.hostname)).unwrap_or("".to_string());
+            let hostname = deploy_data.hostname();
+
+            let sudo_password = if shared_sudo {
+                match shared_sudo_password {
+                    Some(ref password) => password.clone(),
+                    None => {
+                        let password = zeroize::Zeroizing::new(
+                            rpassword::prompt_password("(sudo for all nodes) Password: ")
+                                .map_err(RunDeployError::AskSudoPassword)?,
+                        );
+                        shared_sudo_password = Some(password.clone());
+                        password
+                    }
+                }
+            } else if let Some(ref sudo_file) = deploy_data.merged_settings.sudo_file {
+                let sudo_secret = match deploy_data.merged_settings.sudo_secret {
+                    Some(ref x) => x,
+                    None => {
+                        return Err(RunDeployError::SudoSecret(
+                            node_name.to_string(),
+                            SudoSecretError::NoSecret(sudo_file.display().to_string()),
+                        ))
+                    }
+                };
+
+                // A node with several profiles only decrypts the file once
+                let key = (sudo_file.clone(), sudo_secret.clone());
+                match sudo_secrets.get(&key) {
+                    Some(password) => password.clone(),
+                    None => {
+                        let password = decrypt_sudo_secret(sudo_file, sudo_secret)
+                            .await
+                            .map_err(|e| RunDeployError::SudoSecret(node_name.to_string(), e))?;
+                        sudo_secrets.insert(key, password.clone());
+                        password
+                    }
+                }
+            } else {
+                let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+
+                sudo_passwords
+                    .entry(ssh_addr)
+                    .or_insert_with(|| {
+                        info!(
+                            "You will now be prompted for the sudo password for {}.",
+                            hostname
+                        );
+                        zeroize::Zeroizing::new(
+                            rpassword::prompt_password(format!(
+                                "(sudo for {}) Password: ",
+                                hostname
+                            ))
+                            .unwrap_or("".to_string()),
+                        )
+                    })
+                    .clone()
+            };
 
-            deploy_defs.sudo_password = Some(sudo_password);
+            deploy_defs.sudo_password = Some(deploy::SudoPassword::new(sudo_password));
         }
 
         parts.push((deploy_flake, deploy_data, deploy_defs));
     }
 
-    if interactive {
-        prompt_deployment(&parts[..])?;
+    // Profiles that are never reached show up in the report as pending
+    for (deploy_flake, deploy_data, _) in &parts {
+        report.add(deploy_flake.repo, deploy_data);
+    }
+
+    // Overlapping targets may deploy to the same machine under different node names
+    let profile_locations: Vec<ProfileLocation> = parts
+        .iter()
+        .map(|(_, deploy_data, deploy_defs)| ProfileLocation {
+            host: deploy_data.hostname().to_string(),
+            profile: match deploy_data.profile.profile_settings.profile_path {
+                Some(ref profile_path) => profile_path.clone(),
+                None => format!("{}/{}", deploy_defs.profile_user, deploy_data.profile_name),
+            },
+            node: deploy_data.node_name,
+            path: &deploy_data.profile.profile_settings.path,
+        })
+        .collect();
+    check_profile_locations(&profile_locations)?;
+
+    if reverse_profiles {
+        for (_, deploy_data, _) in &parts {
+            check_reversible(
+                deploy_data.node_name,
+                deploy_data.profile_name,
+                deploy_data.profile,
+            )?;
+        }
+    }
+
+    if let Some(ssh_masters) = ssh_masters {
+        for (_, deploy_data, deploy_defs) in &parts {
+            // Nodes deployed locally aren't connected to
+            if deploy_data.merged_settings.local_deploy.unwrap_or(false) {
+                continue;
+            }
+
+            let hostname = deploy_data.hostname();
+            let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+
+            if !ssh_masters.iter().any(|m| m.ssh_addr == ssh_addr) {
+                ssh_masters.push(SshMaster {
+                    ssh_addr,
+                    ssh_opts: deploy_data.merged_settings.ssh_opts.clone(),
+                });
+            }
+        }
+    }
+
+    if let Some((path, opts)) = manifest {
+        save_manifest(path, opts, &deploy_flakes, &data, &parts).await?;
+    }
+
+    if check_config {
+        info!("The deploy configuration is valid");
+        return Ok(());
+    }
+
+    if list {
+        return print_list(&parts[..], plan_format);
+    }
+
+    let show_deployment = || -> Result<(), RunDeployError> {
+        match plan_format {
+            OutputFormat::Human => print_deployment(&parts[..])?,
+            OutputFormat::Json => print_deployment_json(&parts[..])?,
+        }
+        Ok(())
+    };
+
+    if plan_only {
+        return show_deployment();
+    }
+
+    if plan {
+        show_deployment()?;
+
+        return print_plan(
+            &parts[..],
+            supports_flakes,
+            check_sigs,
+            keep_result,
+            result_path,
+            extra_build_args,
+            dry_activate,
+            boot,
+            activate_only_if_push_changed,
+            reverse_profiles,
+        )
+        .await;
+    }
+
+    if let (Some(timeout_connect), true) = (timeout_connect, connecting) {
+        check_reachable(&parts[..], timeout_connect).await?;
+    }
+
+    if interactive || assume_yes {
+        prompt_deployment(&parts[..], assume_yes, plan_format)?;
     } else {
-        print_deployment(&parts[..])?;
+        show_deployment()?;
+    }
+
+    if rollback {
+        // Later profiles may depend on earlier ones, like when rolling back a failed deployment
+        for (deploy_flake, deploy_data, deploy_defs) in parts.iter().rev() {
+            info!(
+                "Rolling back profile `{}` on node `{}`",
+                deploy_data.profile_name, deploy_data.node_name
+            );
+
+            for_profile(
+                deploy_data,
+                deploy::deploy::revoke(deploy_data, deploy_defs),
+            )
+            .await
+            .map_err(|e| {
+                RunDeployError::RevokeProfile(
+                    deploy_data.node_name.to_string(),
+                    deploy_data.profile_name.to_string(),
+                    e,
+                )
+            })?;
+
+            report.update(deploy_flake.repo, deploy_data, |p| p.rolled_back = true);
+        }
+
+        return Ok(());
     }
 
     let data_iter = || {
@@ -583,59 +3534,436 @@ async fn run_deploy(
                 keep_result,
                 result_path,
                 extra_build_args,
+                auto_verbose_on_failure,
+                keep_going,
+                check_present: activate_only_if_push_changed,
             },
         )
     };
 
-    for data in data_iter() {
-        let node_name: String = data.deploy_data.node_name.to_string();
-        deploy::push::build_profile(data).await.map_err(|e| {
-            RunDeployError::BuildProfile(node_name, e)
-        })?;
+    if skip_push {
+        // A closure that was copied before has been built here, if it is missing the flake
+        // has changed since and the node doesn't have the closure that would be activated
+        for (_, deploy_data, _) in &parts {
+            if !deploy_data.merged_settings.remote_build.unwrap_or(false)
+                && !Path::new(&deploy_data.profile.profile_settings.path).exists()
+            {
+                return Err(RunDeployError::SkipPushNotBuilt(
+                    deploy_data.node_name.to_string(),
+                    deploy_data.profile_name.to_string(),
+                    deploy_data.profile.profile_settings.path.clone(),
+                ));
+            }
+        }
     }
 
-    for data in data_iter() {
-        let node_name: String = data.deploy_data.node_name.to_string();
-        deploy::push::push_profile(data).await.map_err(|e| {
-            RunDeployError::PushProfile(node_name, e)
-        })?;
+    // A pre-built closure may have no derivation to build on this machine, and a closure
+    // that isn't copied doesn't need to be built
+    if !prebuilt && !skip_push {
+        let batch: Vec<deploy::push::PushProfileData> = data_iter()
+            .filter(|data| deploy::push::can_build_batched(data))
+            .collect();
+        let mut built_batch = false;
+
+        if batch.len() > 1 {
+            check_interrupted()?;
+
+            // Building one by one tells which profile fails to build
+            match within_time_limit(time_limit, deploy::push::build_profiles(&batch)).await? {
+                Ok(()) => {
+                    for data in &batch {
+                        report.update(data.repo, data.deploy_data, |p| p.build = StepStatus::Succeeded);
+                    }
+                    built_batch = true;
+                }
+                Err(e) => warn!("Building the profiles in a single Nix build failed: {}, building them one by one", e),
+            }
+        }
+
+        for data in
+            data_iter().filter(|data| !(built_batch && deploy::push::can_build_batched(data)))
+        {
+            check_interrupted()?;
+
+            if build_only
+                && data
+                    .deploy_data
+                    .merged_settings
+                    .remote_build
+                    .unwrap_or(false)
+            {
+                warn!(
+                    "Not building profile `{}` of node `{}`, it is built on the node",
+                    data.deploy_data.profile_name, data.deploy_data.node_name
+                );
+                continue;
+            }
+
+            let (repo, deploy_data) = (data.repo, data.deploy_data);
+            let node_name: String = data.deploy_data.node_name.to_string();
+            let profile_name: String = data.deploy_data.profile_name.to_string();
+            let built = within_time_limit(
+                time_limit,
+                for_profile(deploy_data, deploy::push::build_profile(data)),
+            )
+            .await
+            .and_then(|x| x.map_err(|e| RunDeployError::BuildProfile(node_name, profile_name, e)));
+
+            report.update(repo, deploy_data, |p| p.build = StepStatus::of(&built));
+            built?;
+        }
+    }
+
+    if build_only {
+        info!("All profiles were built, not deploying them");
+        return Ok(());
+    }
+
+    // Profiles of the same node are always deployed one after another, only separate nodes
+    // are deployed concurrently
+    let groups = if parallel > 1 {
+        group_per_node(data_iter().collect(), |data| {
+            (data.repo, data.deploy_data.node_name)
+        })
+    } else {
+        vec![data_iter().collect()]
+    };
+
+    let group_failed = AtomicBool::new(false);
+    let outcomes = deploy_groups(groups, parallel, &group_failed, |group| {
+        deploy_group(
+            group,
+            dry_activate,
+            boot,
+            confirm_all,
+            activate_only_if_push_changed,
+            time_limit,
+            reverse_profiles,
+            skip_push,
+            system_check,
+            report,
+            &group_failed,
+        )
+    })
+    .await;
+
+    let mut succeeded: Vec<(&str, &deploy::DeployData, &deploy::DeployDefs)> = vec![];
+    let mut unconfirmed: Vec<(&str, &deploy::DeployData, &deploy::DeployDefs, Instant)> = vec![];
+    let mut failure: Option<RunDeployError> = None;
+    let mut failed: Option<(&str, &deploy::DeployData)> = None;
+
+    for outcome in outcomes {
+        succeeded.extend(outcome.succeeded);
+        unconfirmed.extend(outcome.unconfirmed);
+
+        match (outcome.result, &failure) {
+            (Err(RunDeployError::OtherNodeFailed), _) => (),
+            (Err(e), None) => {
+                failure = Some(e);
+                failed = outcome.failed;
+            }
+            (Err(e), Some(_)) => error!("{}", e),
+            (Ok(()), _) => (),
+        }
     }
 
-    let mut succeeded: Vec<(&deploy::DeployData, &deploy::DeployDefs)> = vec![];
+    // Whatever failed because of the interruption (e.g. an SSH connection killed by the same
+    // Ctrl-C), the deployment as a whole was interrupted
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        if let Some(e) = failure.take() {
+            if !matches!(e, RunDeployError::Interrupted) {
+                error!("{}", e);
+            }
+        }
+        failure = Some(RunDeployError::Interrupted);
+    }
 
-    // Run all deployments
-    // In case of an error rollback any previoulsy made deployment.
+    // In case of an error rollback any previoulsy made deployment, on every node.
     // Rollbacks adhere to the global seeting to auto_rollback and secondary
     // the profile's configuration
-    for (_, deploy_data, deploy_defs) in &parts {
-        if let Err(e) = deploy::deploy::deploy_profile(deploy_data, deploy_defs, dry_activate, boot).await
-        {
+    if let Some(e) = failure {
+        if !unconfirmed.is_empty() {
+            info!("Unconfirmed deployments will roll back on their own");
+        }
+
+        if let RunDeployError::Interrupted = e {
+            if rollback_on_interrupt && !dry_activate && !succeeded.is_empty() {
+                info!("Revoking previous deploys");
+                revoke_all(&succeeded, report).await?;
+                return Err(RunDeployError::RolledBack(Box::new(e)));
+            } else if !succeeded.is_empty() {
+                info!("The profiles deployed before the interruption stay active");
+            }
+        } else if let RunDeployError::DeployProfile(ref node_name, ref profile_name, _) = e {
             error!("{}", e);
             if dry_activate {
                 info!("dry run, not rolling back");
             }
-            if rollback_succeeded && cmd_overrides.auto_rollback.unwrap_or(true) {
+
+            let auto_rollback = failed
+                .map(|(_, deploy_data)| deploy_data.cmd_overrides.auto_rollback.unwrap_or(true))
+                .unwrap_or(true);
+
+            if rollback_succeeded && auto_rollback {
                 info!("Revoking previous deploys");
                 // revoking all previous deploys
                 // (adheres to profile configuration if not set explicitely by
                 //  the command line)
-                for (deploy_data, deploy_defs) in &succeeded {
-                    if deploy_data.merged_settings.auto_rollback.unwrap_or(true) {
-                        deploy::deploy::revoke(*deploy_data, *deploy_defs).await.map_err(|e| {
-                            RunDeployError::RevokeProfile(deploy_data.node_name.to_string(), e)
-                        })?;
+                revoke_all(&succeeded, report).await?;
+                return Err(RunDeployError::Rollback(
+                    node_name.to_string(),
+                    profile_name.to_string(),
+                ));
+            }
+        } else if !dry_activate
+            && rollback_succeeded
+            // Like for a failed profile, profiles with auto rollback turned off stay deployed
+            && succeeded
+                .iter()
+                .any(|(_, deploy_data, _)| deploy_data.merged_settings.auto_rollback.unwrap_or(true))
+        {
+            error!("{}", e);
+            info!("Revoking previous deploys");
+            revoke_all(&succeeded, report).await?;
+            return Err(RunDeployError::RolledBack(Box::new(e)));
+        }
+
+        return Err(e);
+    }
+
+    if confirm_all {
+        confirm_all_deployments(&succeeded, &unconfirmed, time_limit, report).await?;
+    }
+
+    // Only now that every deployment is confirmed, so nothing can be rolled back anymore. They
+    // still count against `--time-limit`
+    if !dry_activate {
+        within_time_limit(time_limit, async {
+            for (_, deploy_data, _) in &parts {
+                if let Some(ref post_deploy) = deploy_data.merged_settings.post_deploy {
+                    if let Err(e) = for_profile(
+                        deploy_data,
+                        run_hook("postDeploy", post_deploy, deploy_data),
+                    )
+                    .await
+                    {
+                        warn!(
+                            "The postDeploy hook of profile `{}` for node `{}` failed: {}",
+                            deploy_data.profile_name, deploy_data.node_name, e
+                        );
                     }
                 }
-                return Err(RunDeployError::Rollback(deploy_data.node_name.to_string()));
             }
-            return Err(RunDeployError::DeployProfile(deploy_data.node_name.to_string(), e))
-        }
-        succeeded.push((deploy_data, deploy_defs))
+
+            let mut collected_nodes: Vec<&str> = Vec::new();
+
+            for (_, deploy_data, deploy_defs) in &parts {
+                if !deploy_data.merged_settings.gc_after.unwrap_or(gc_after)
+                    || collected_nodes.contains(&deploy_data.node_name)
+                {
+                    continue;
+                }
+
+                collected_nodes.push(deploy_data.node_name);
+
+                if let Err(e) = for_profile(
+                    deploy_data,
+                    deploy::deploy::collect_garbage(deploy_data, deploy_defs),
+                )
+                .await
+                {
+                    warn!(
+                        "Failed to collect garbage on node `{}`: {}",
+                        deploy_data.node_name, e
+                    );
+                }
+            }
+        })
+        .await?;
     }
 
     Ok(())
 }
 
+#[derive(Error, Debug)]
+pub enum ReadTargetsError {
+    #[error("Failed to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse the JSON list in {0}: {1}")]
+    Json(PathBuf, serde_json::Error),
+    #[error("{0} doesn't list any targets")]
+    Empty(PathBuf),
+}
+
+/// Parses the contents of a `--targets-file`: either a JSON list of flakes, or one flake per line,
+/// ignoring empty lines and comments starting with `#`
+fn parse_targets(contents: &str) -> Result<Vec<String>, serde_json::Error> {
+    if contents.trim_start().starts_with('[') {
+        return serde_json::from_str(contents);
+    }
+
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+#[test]
+fn test_parse_targets() {
+    assert_eq!(
+        parse_targets(".#web\n\n# the database\n  .#db.system  \n").unwrap(),
+        vec![".#web", ".#db.system"]
+    );
+    assert_eq!(
+        parse_targets(r#" [".#web", ".#\"db.example.com\""] "#).unwrap(),
+        vec![".#web", ".#\"db.example.com\""]
+    );
+    assert!(parse_targets("[.#web]").is_err());
+}
+
+fn read_targets(path: &Path) -> Result<Vec<String>, ReadTargetsError> {
+    let contents = if path == Path::new("-") {
+        let mut contents = String::new();
+        std::io::Read::read_to_string(&mut stdin(), &mut contents)
+            .map_err(|e| ReadTargetsError::Read(path.to_path_buf(), e))?;
+        contents
+    } else {
+        std::fs::read_to_string(path).map_err(|e| ReadTargetsError::Read(path.to_path_buf(), e))?
+    };
+
+    let targets =
+        parse_targets(&contents).map_err(|e| ReadTargetsError::Json(path.to_path_buf(), e))?;
+
+    // An empty inventory must not fall back to deploying the flake in the current directory
+    if targets.is_empty() {
+        return Err(ReadTargetsError::Empty(path.to_path_buf()));
+    }
+
+    Ok(targets)
+}
+
+#[derive(Error, Debug)]
+pub enum ParseTargetOptsError {
+    #[error("Expected `key=value` in target options, got `{0}`")]
+    NotKeyValue(String),
+    #[error("Unknown target option `{0}`, expected one of `target`, `sshUser`, `user`, `tempPath` or `checks`")]
+    UnknownKey(String),
+    #[error("Expected `true` or `false` for target option `{0}`, got `{1}`")]
+    NotBool(String, String),
+    #[error("Target options `{0}` don't specify a `target`")]
+    NoTarget(String),
+    #[error("Target options are given for `{0}`, which is not one of the deployed targets")]
+    UnknownTarget(String),
+}
+
+/// User overrides for a single entry of `--targets`
+#[derive(Debug, PartialEq)]
+struct TargetOpts {
+    target: String,
+    ssh_user: Option<String>,
+    profile_user: Option<String>,
+    temp_path: Option<PathBuf>,
+    /// Whether to run the flake checks of the target, unless `--skip-checks` skips them for all targets
+    checks: Option<bool>,
+}
+
+fn parse_target_opts(s: &str) -> Result<TargetOpts, ParseTargetOptsError> {
+    let mut target = None;
+    let mut ssh_user = None;
+    let mut profile_user = None;
+    let mut temp_path = None;
+    let mut checks = None;
+
+    for pair in s.split_whitespace() {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| ParseTargetOptsError::NotKeyValue(pair.to_string()))?;
+
+        match key {
+            "target" => target = Some(value.to_string()),
+            "sshUser" => ssh_user = Some(value.to_string()),
+            "user" => profile_user = Some(value.to_string()),
+            "tempPath" => temp_path = Some(PathBuf::from(value)),
+            "checks" => {
+                checks = Some(value.parse().map_err(|_| {
+                    ParseTargetOptsError::NotBool(key.to_string(), value.to_string())
+                })?)
+            }
+            _ => return Err(ParseTargetOptsError::UnknownKey(key.to_string())),
+        }
+    }
+
+    Ok(TargetOpts {
+        target: target.ok_or_else(|| ParseTargetOptsError::NoTarget(s.to_string()))?,
+        ssh_user,
+        profile_user,
+        temp_path,
+        checks,
+    })
+}
+
+#[test]
+fn test_parse_target_opts() {
+    assert_eq!(
+        parse_target_opts("target=.#server sshUser=admin user=root").unwrap(),
+        TargetOpts {
+            target: ".#server".to_string(),
+            ssh_user: Some("admin".to_string()),
+            profile_user: Some("root".to_string()),
+            temp_path: None,
+            checks: None,
+        }
+    );
+
+    assert_eq!(
+        parse_target_opts("user=alice target=github:org/repo?ref=main#\"example.com\"").unwrap(),
+        TargetOpts {
+            target: "github:org/repo?ref=main#\"example.com\"".to_string(),
+            ssh_user: None,
+            profile_user: Some("alice".to_string()),
+            temp_path: None,
+            checks: None,
+        }
+    );
+
+    assert_eq!(
+        parse_target_opts("target=.#kiosk tempPath=/run/deploy-rs").unwrap(),
+        TargetOpts {
+            target: ".#kiosk".to_string(),
+            ssh_user: None,
+            profile_user: None,
+            temp_path: Some(PathBuf::from("/run/deploy-rs")),
+            checks: None,
+        }
+    );
+
+    assert_eq!(
+        parse_target_opts("target=.#slow checks=false")
+            .unwrap()
+            .checks,
+        Some(false)
+    );
+
+    assert!(matches!(
+        parse_target_opts("sshUser=admin"),
+        Err(ParseTargetOptsError::NoTarget(_))
+    ));
+    assert!(matches!(
+        parse_target_opts("target=. port=22"),
+        Err(ParseTargetOptsError::UnknownKey(_))
+    ));
+    assert!(matches!(
+        parse_target_opts("target=. admin"),
+        Err(ParseTargetOptsError::NotKeyValue(_))
+    ));
+    assert!(matches!(
+        parse_target_opts("target=. checks=no"),
+        Err(ParseTargetOptsError::NotBool(..))
+    ));
+}
+
 #[derive(Error, Debug)]
 pub enum RunError {
     #[error("Failed to deploy profile: {0}")]
@@ -646,46 +3974,264 @@ pub enum RunError {
     FlakeTest(std::io::Error),
     #[error("Failed to check deployment: {0}")]
     CheckDeployment(#[from] CheckDeploymentError),
+    #[error("Refusing to deploy: {0}")]
+    RequireTag(#[from] RequireTagError),
+    #[error("Can't deploy closure: {0}")]
+    Closure(#[from] ClosureError),
+    #[error("{0}")]
+    WriteReport(#[from] WriteReportError),
     #[error("Failed to evaluate deployment data: {0}")]
     GetDeploymentData(#[from] GetDeploymentDataError),
     #[error("Error parsing flake: {0}")]
     ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Error parsing target options: {0}")]
+    ParseTargetOpts(#[from] ParseTargetOptsError),
+    #[error("Error reading targets: {0}")]
+    ReadTargets(#[from] ReadTargetsError),
     #[error("Error initiating logger: {0}")]
     Logger(#[from] flexi_logger::FlexiLoggerError),
     #[error("{0}")]
     RunDeploy(#[from] RunDeployError),
 }
 
+impl RunError {
+    /// Name of the (innermost deployment) error variant, for machine-readable error output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RunError::DeployProfile(_) => "DeployProfile",
+            RunError::PushProfile(_) => "PushProfile",
+            RunError::FlakeTest(_) => "FlakeTest",
+            RunError::CheckDeployment(_) => "CheckDeployment",
+            RunError::RequireTag(_) => "RequireTag",
+            RunError::Closure(_) => "Closure",
+            RunError::WriteReport(_) => "WriteReport",
+            RunError::GetDeploymentData(_) => "GetDeploymentData",
+            RunError::ParseFlake(_) => "ParseFlake",
+            RunError::ParseTargetOpts(_) => "ParseTargetOpts",
+            RunError::ReadTargets(_) => "ReadTargets",
+            RunError::Logger(_) => "Logger",
+            RunError::RunDeploy(e) => e.kind(),
+        }
+    }
+
+    /// Exit code of `deploy` for the error, see `RunDeployError::exit_code`
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunError::RunDeploy(e) => e.exit_code(),
+            _ => 1,
+        }
+    }
+
+    pub fn node(&self) -> Option<&str> {
+        match self {
+            RunError::RunDeploy(e) => e.node(),
+            _ => None,
+        }
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        match self {
+            RunError::RunDeploy(e) => e.profile(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError<'a> {
+    error: String,
+    kind: &'a str,
+    node: Option<&'a str>,
+    profile: Option<&'a str>,
+}
+
+/// Reports a failed run on stderr in the requested format
+pub fn report_error(err: &RunError, output_format: OutputFormat) {
+    match output_format {
+        OutputFormat::Human => error!("{}", err),
+        OutputFormat::Json => {
+            let json_error = JsonError {
+                error: err.to_string(),
+                kind: err.kind(),
+                node: err.node(),
+                profile: err.profile(),
+            };
+
+            match serde_json::to_string(&json_error) {
+                Ok(s) => eprintln!("{}", s),
+                Err(e) => error!("Failed to serialize error {} to JSON: {}", err, e),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_json_error() {
+    let err = RunError::RunDeploy(RunDeployError::Rollback(
+        "example".to_string(),
+        "system".to_string(),
+    ));
+
+    assert_eq!(
+        serde_json::to_string(&JsonError {
+            error: err.to_string(),
+            kind: err.kind(),
+            node: err.node(),
+            profile: err.profile(),
+        })
+        .unwrap(),
+        r#"{"error":"Deployment of profile `system` to node example failed, rolled back to previous generation","kind":"Rollback","node":"example","profile":"system"}"#
+    );
+}
+
 pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
     let opts = match args {
         Some(o) => <Opts as FromArgMatches>::from_arg_matches(o),
         None => Opts::parse(),
     };
 
+    run_opts(opts).await
+}
+
+/// Shows a desktop notification about the outcome of the deployment, if a notifier is available
+async fn notify(result: &Result<(), RunError>) {
+    let message = match result {
+        Ok(()) => "Deployment succeeded".to_string(),
+        Err(err) => format!("Deployment failed: {}", err),
+    };
+
+    let mut notify_command = if cfg!(target_os = "macos") {
+        let mut c = Command::new("osascript");
+        c.arg("-e").arg(format!(
+            "display notification \"{}\" with title \"deploy-rs\"",
+            message.replace('\\', "\\\\").replace('"', "\\\"")
+        ));
+        c
+    } else {
+        let mut c = Command::new("notify-send");
+        c.arg("deploy-rs").arg(&message);
+        c
+    };
+
+    match notify_command.status().await {
+        Ok(status) if status.success() => (),
+        Ok(status) => debug!(
+            "Notification command resulted in a bad exit code: {:?}",
+            status.code()
+        ),
+        Err(e) => debug!("Failed to run the notification command: {}", e),
+    }
+}
+
+pub async fn run_opts(opts: Opts) -> Result<(), RunError> {
+    let notify_on_completion = opts.notify;
+
+    let result = deploy_with_opts(opts).await;
+    deploy::remove_ssh_wrapper();
+
+    if notify_on_completion {
+        notify(&result).await;
+    }
+
+    result
+}
+
+async fn deploy_with_opts(opts: Opts) -> Result<(), RunError> {
+    let started = Instant::now();
+    let report_file = opts.report_file.clone();
+    let report = DeployReport::default();
+
+    // Also written when the evaluation or the checks fail, before any profile is known
+    let result = deploy_with_report(opts, &report).await;
+
+    if let Some(ref report_file) = report_file {
+        // The deployment's own error is more important than failing to write the report
+        match write_report(report_file, report, &result, started).await {
+            Err(e) if result.is_ok() => return Err(e.into()),
+            Err(e) => error!("{}", e),
+            Ok(()) => (),
+        }
+    }
+
+    result
+}
+
+async fn deploy_with_report(mut opts: Opts, report: &DeployReport) -> Result<(), RunError> {
     deploy::init_logger(
         opts.debug_logs,
         opts.log_dir.as_deref(),
         &deploy::LoggerType::Deploy,
+        opts.log_format,
     )?;
 
-    if opts.dry_activate && opts.boot {
-        error!("Cannot use both --dry-activate & --boot!");
+    // Used by `deploy::nix_command` and `deploy::ssh_command` wherever they run something
+    deploy::set_local_commands(deploy::LocalCommands {
+        nix_bin: opts
+            .nix_bin
+            .clone()
+            .or_else(|| std::env::var("DEPLOY_NIX").ok()),
+        ssh_bin: opts
+            .ssh_bin
+            .clone()
+            .or_else(|| std::env::var("DEPLOY_SSH").ok()),
+        verbose_nix: opts.verbose_nix || std::env::var_os("DEPLOY_NIX_VERBOSE").is_some(),
+    });
+
+    // Taken before the options are taken apart below
+    let opts_json = match opts.save_manifest {
+        Some(_) => Some(
+            serde_json::to_value(&opts)
+                .map_err(|e| RunDeployError::from(SaveManifestError::from(e)))?,
+        ),
+        None => None,
+    };
+
+    let mut deploys = Vec::new();
+    for target in opts.clone().targets.unwrap_or_default() {
+        // `--targets -` reads them from stdin, like `--targets-file -`
+        if target == "-" {
+            deploys.extend(read_targets(Path::new("-"))?);
+        } else {
+            deploys.push(target);
+        }
+    }
+
+    if let Some(ref targets_file) = opts.targets_file {
+        deploys.extend(read_targets(targets_file)?);
+    }
+
+    if deploys.is_empty() {
+        deploys.push(opts.clone().target.unwrap_or_else(|| ".".to_string()));
     }
 
-    let deploys = opts
-        .clone()
-        .targets
-        .unwrap_or_else(|| vec![opts.clone().target.unwrap_or_else(|| ".".to_string())]);
+    // A target listed both inline and in the file is only deployed once, where it first appears
+    let mut seen = std::collections::HashSet::new();
+    deploys.retain(|d| seen.insert(d.clone()));
 
-    let deploy_flakes: Vec<DeployFlake> = deploys
-        .iter()
-        .map(|f| deploy::parse_flake(f.as_str()))
-        .collect::<Result<Vec<DeployFlake>, ParseFlakeError>>()?;
+    let deploy_flakes: Vec<DeployFlake> = match opts.closure {
+        // The target names the node, there is no flake
+        Some(_) => vec![DeployFlake {
+            repo: "",
+            node: Some(opts.target.clone().ok_or(ClosureError::NoNode)?),
+            profile: Some("system".to_string()),
+        }],
+        None => deploys
+            .iter()
+            .map(|f| deploy::parse_flake(f.as_str()))
+            .collect::<Result<Vec<DeployFlake>, ParseFlakeError>>()?,
+    };
+
+    let closure_hostname = opts.hostname.clone();
 
     let cmd_overrides = deploy::CmdOverrides {
         ssh_user: opts.ssh_user,
         profile_user: opts.profile_user,
         ssh_opts: opts.ssh_opts,
+        ssh_connect_timeout: opts.ssh_connect_timeout,
+        ssh_keepalive: opts.ssh_keepalive,
+        ssh_control_path: opts.ssh_control_path,
+        ssh_multiplex: opts.ssh_multiplex || opts.keep_ssh_on_failure,
+        ssh_control_persist: opts.ssh_control_persist,
         fast_connection: opts.fast_connection,
         auto_rollback: opts.auto_rollback,
         hostname: opts.hostname,
@@ -693,32 +4239,122 @@ pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
         temp_path: opts.temp_path,
         confirm_timeout: opts.confirm_timeout,
         activation_timeout: opts.activation_timeout,
+        build_timeout: opts.build_timeout,
+        activation_retries: opts.activation_retries,
+        copy_retries: opts.copy_retries,
+        copy_retry_delay: opts.copy_retry_delay,
+        confirm_via: opts.confirm_via,
         dry_activate: opts.dry_activate,
+        diff: opts.diff,
         remote_build: opts.remote_build,
+        log_format: opts.log_format,
         sudo: opts.sudo,
-        interactive_sudo: opts.interactive_sudo
+        interactive_sudo: opts.interactive_sudo,
     };
 
+    let target_opts = opts
+        .target_opts
+        .iter()
+        .map(|s| parse_target_opts(s))
+        .collect::<Result<Vec<TargetOpts>, ParseTargetOptsError>>()?;
+
+    if let Some(t) = target_opts.iter().find(|t| !deploys.contains(&t.target)) {
+        return Err(ParseTargetOptsError::UnknownTarget(t.target.clone()).into());
+    }
+
+    // Per-target settings take precedence over the ones given for all targets
+    let target_cmd_overrides: Vec<deploy::CmdOverrides> = deploys
+        .iter()
+        .map(|d| {
+            let mut overrides = cmd_overrides.clone();
+            if let Some(t) = target_opts.iter().find(|t| &t.target == d) {
+                if t.ssh_user.is_some() {
+                    overrides.ssh_user = t.ssh_user.clone();
+                }
+                if t.profile_user.is_some() {
+                    overrides.profile_user = t.profile_user.clone();
+                }
+                if t.temp_path.is_some() {
+                    overrides.temp_path = t.temp_path.clone();
+                }
+            }
+            overrides
+        })
+        .collect();
+
+    if let Some(ref pattern) = opts.require_tag {
+        for deploy_flake in &deploy_flakes {
+            check_release_tag(deploy_flake.repo, pattern).await?;
+        }
+    }
+
     let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
 
     if !supports_flakes {
         warn!("A Nix version without flakes support was detected, support for this is work in progress");
     }
 
-    if !opts.skip_checks {
-        for deploy_flake in &deploy_flakes {
-            check_deployment(supports_flakes, deploy_flake.repo, &opts.extra_build_args).await?;
+    // Passed on like extra arguments, so that the evaluation, the checks and the builds all get it
+    if opts.impure && supports_flakes && !opts.extra_build_args.iter().any(|a| a == "--impure") {
+        opts.extra_build_args.push("--impure".to_string());
+    }
+
+    // Flake checks may need to build things, which a config check mustn't do
+    if !opts.skip_checks
+        && !opts.check_config
+        && !opts.list
+        && !opts.rollback
+        && opts.closure.is_none()
+    {
+        for (deploy_flake, target) in deploy_flakes.iter().zip(&deploys) {
+            if target_opts
+                .iter()
+                .any(|t| &t.target == target && t.checks == Some(false))
+            {
+                info!("Skipping the checks of {}", target);
+                continue;
+            }
+
+            check_deployment(
+                supports_flakes,
+                deploy_flake.repo,
+                &opts.extra_build_args,
+                opts.check_all_systems,
+            )
+            .await?;
         }
     }
     let result_path = opts.result_path.as_deref();
-    let data = get_deployment_data(supports_flakes, &deploy_flakes, &opts.extra_build_args).await?;
-    run_deploy(
+    let data = match opts.closure {
+        Some(ref closure) => vec![closure_deployment_data(
+            closure,
+            deploy_flakes[0].node.as_deref().unwrap_or_default(),
+            closure_hostname.as_deref(),
+        )?],
+        None => {
+            get_deployment_data(
+                supports_flakes,
+                &deploy_flakes,
+                &opts.extra_build_args,
+                opts.dump_nix_eval.as_deref(),
+                opts.abort_on_warnings,
+                opts.eval_per_target,
+            )
+            .await?
+        }
+    };
+
+    let ssh_multiplex = opts.ssh_multiplex || opts.keep_ssh_on_failure;
+    let mut ssh_masters: Vec<SshMaster> = Vec::new();
+
+    let deployment = run_deploy(
         deploy_flakes,
         data,
         supports_flakes,
         opts.checksigs,
         opts.interactive,
-        &cmd_overrides,
+        opts.yes,
+        &target_cmd_overrides,
         opts.keep_result,
         result_path,
         &opts.extra_build_args,
@@ -727,8 +4363,50 @@ pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
         opts.boot,
         &opts.log_dir,
         opts.rollback_succeeded.unwrap_or(true),
-    )
-    .await?;
+        opts.activate_only_if_push_changed,
+        opts.plan,
+        opts.check_config,
+        opts.build_only,
+        opts.list,
+        opts.copy_first_then_confirm_all,
+        &opts.exclude_node,
+        opts.auto_verbose_on_failure,
+        opts.gc_after,
+        opts.save_manifest.as_deref().zip(opts_json.as_ref()),
+        opts.time_limit,
+        opts.reverse_profiles,
+        ssh_multiplex.then_some(&mut ssh_masters),
+        opts.keep_going,
+        opts.parallel,
+        &opts.nodes,
+        opts.plan_only,
+        opts.plan_format,
+        opts.closure.is_some(),
+        opts.skip_push,
+        opts.rollback,
+        opts.rollback_on_interrupt,
+        opts.ask_sudo_password,
+        !opts.no_system_check,
+        opts.timeout_connect,
+        report,
+    );
+
+    // On a second Ctrl-C the deployment is dropped, so the SSH masters still get closed and the
+    // report written
+    let result = tokio::select! {
+        result = deployment => result,
+        _ = second_interrupt() => {
+            warn!("Aborted, cleaning up");
+            Err(RunDeployError::Interrupted)
+        }
+    };
+
+    if ssh_multiplex {
+        let failed = matches!(result, Err(ref e) if !matches!(e, RunDeployError::Interrupted));
+        finish_ssh_masters(&ssh_masters, opts.keep_ssh_on_failure && failed).await;
+    }
+
+    result?;
 
     Ok(())
 }
@@ -3,62 +3,130 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use merge::Merge;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
 
-#[derive(Deserialize, Debug, Clone, Merge)]
+#[derive(Serialize, Deserialize, Debug, Clone, Merge)]
 pub struct GenericSettings {
-    #[serde(rename(deserialize = "sshUser"))]
+    #[serde(rename = "sshUser")]
     pub ssh_user: Option<String>,
     pub user: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default, rename = "sshOpts")]
+    #[merge(strategy = merge::vec::append)]
+    pub ssh_opts: Vec<String>,
+    #[serde(rename = "sshOptsFile")]
+    pub ssh_opts_file: Option<PathBuf>,
     #[serde(
         skip_serializing_if = "Vec::is_empty",
         default,
-        rename(deserialize = "sshOpts")
+        rename = "identityFiles"
     )]
     #[merge(strategy = merge::vec::append)]
-    pub ssh_opts: Vec<String>,
-    #[serde(rename(deserialize = "fastConnection"))]
+    pub identity_files: Vec<PathBuf>,
+    #[serde(rename = "jumpHost")]
+    pub jump_host: Option<String>,
+    #[serde(rename = "fastConnection")]
     pub fast_connection: Option<bool>,
-    #[serde(rename(deserialize = "autoRollback"))]
+    #[serde(rename = "copyMaxConnections")]
+    pub copy_max_connections: Option<u16>,
+    #[serde(rename = "copyCompress")]
+    pub copy_compress: Option<bool>,
+    #[serde(rename = "autoRollback")]
     pub auto_rollback: Option<bool>,
-    #[serde(rename(deserialize = "confirmTimeout"))]
+    #[serde(rename = "confirmTimeout")]
     pub confirm_timeout: Option<u16>,
-    #[serde(rename(deserialize = "activationTimeout"))]
+    #[serde(rename = "activationTimeout")]
     pub activation_timeout: Option<u16>,
-    #[serde(rename(deserialize = "tempPath"))]
+    #[serde(rename = "healthCheckTimeout")]
+    pub health_check_timeout: Option<u16>,
+    #[serde(rename = "deployTimeout")]
+    pub deploy_timeout: Option<u16>,
+    #[serde(rename = "buildTimeout")]
+    pub build_timeout: Option<u16>,
+    #[serde(rename = "tempPath")]
     pub temp_path: Option<PathBuf>,
-    #[serde(rename(deserialize = "magicRollback"))]
+    #[serde(rename = "magicRollback")]
     pub magic_rollback: Option<bool>,
-    #[serde(rename(deserialize = "sudo"))]
+    #[serde(rename = "sudo")]
     pub sudo: Option<String>,
-    #[serde(default,rename(deserialize = "remoteBuild"))]
+    #[serde(rename = "sudoPath")]
+    pub sudo_path: Option<String>,
+    #[serde(default, rename = "remoteBuild")]
     pub remote_build: Option<bool>,
-    #[serde(rename(deserialize = "interactiveSudo"))]
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        rename = "remoteBuildArgs"
+    )]
+    #[merge(strategy = merge::vec::append)]
+    pub remote_build_args: Vec<String>,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        rename = "extraBuildArgs"
+    )]
+    #[merge(strategy = merge::vec::append)]
+    pub extra_build_args: Vec<String>,
+    #[serde(rename = "interactiveSudo")]
     pub interactive_sudo: Option<bool>,
+    #[serde(rename = "sudoFile")]
+    pub sudo_file: Option<PathBuf>,
+    #[serde(rename = "sudoSecret")]
+    pub sudo_secret: Option<String>,
+    #[serde(rename = "gcAfter")]
+    pub gc_after: Option<bool>,
+    #[serde(rename = "preDeploy")]
+    pub pre_deploy: Option<String>,
+    #[serde(rename = "postDeploy")]
+    pub post_deploy: Option<String>,
+    #[serde(rename = "hostnameCommand")]
+    pub hostname_command: Option<String>,
+    #[serde(rename = "localDeploy")]
+    pub local_deploy: Option<bool>,
+    #[serde(
+        skip_serializing_if = "HashMap::is_empty",
+        default,
+        rename = "activationSecrets"
+    )]
+    #[merge(strategy = merge_missing)]
+    pub activation_secrets: HashMap<String, String>,
+}
+
+/// Adds the entries of `right` which aren't in `left` yet, so more specific settings win
+fn merge_missing(left: &mut HashMap<String, String>, right: HashMap<String, String>) {
+    for (k, v) in right {
+        left.entry(k).or_insert(v);
+    }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NodeSettings {
     pub hostname: String,
     pub profiles: HashMap<String, Profile>,
     #[serde(
         skip_serializing_if = "Vec::is_empty",
         default,
-        rename(deserialize = "profilesOrder")
+        rename = "profilesOrder"
     )]
     pub profiles_order: Vec<String>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ProfileSettings {
     pub path: String,
-    #[serde(rename(deserialize = "profilePath"))]
+    #[serde(rename = "profilePath")]
     pub profile_path: Option<String>,
+    #[serde(rename = "smokeTest")]
+    pub smoke_test: Option<String>,
+    #[serde(rename = "healthCheck")]
+    pub health_check: Option<String>,
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default, rename = "dependsOn")]
+    pub depends_on: Vec<String>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Profile {
     #[serde(flatten)]
     pub profile_settings: ProfileSettings,
@@ -66,7 +134,7 @@ pub struct Profile {
     pub generic_settings: GenericSettings,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Node {
     #[serde(flatten)]
     pub generic_settings: GenericSettings,
@@ -74,7 +142,7 @@ pub struct Node {
     pub node_settings: NodeSettings,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Data {
     #[serde(flatten)]
     pub generic_settings: GenericSettings,
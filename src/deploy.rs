@@ -4,12 +4,17 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info, trace};
-use std::path::Path;
+use log::{debug, error, info, trace, warn};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
 use thiserror::Error;
-use tokio::{io::AsyncWriteExt, process::Command};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::Command,
+};
 
-use crate::{DeployDataDefsError, DeployDefs, ProfileInfo};
+use crate::{DeployDataDefsError, DeployDefs, LogFormat, ProfileInfo};
 
 struct ActivateCommandData<'a> {
     sudo: &'a Option<String>,
@@ -17,12 +22,17 @@ struct ActivateCommandData<'a> {
     closure: &'a str,
     auto_rollback: bool,
     temp_path: &'a Path,
+    lock_id: &'a str,
     confirm_timeout: u16,
+    activation_timeout: u16,
     magic_rollback: bool,
     debug_logs: bool,
     log_dir: Option<&'a str>,
+    log_format: LogFormat,
     dry_activate: bool,
     boot: bool,
+    activation_retries: u16,
+    secrets_from_stdin: bool,
 }
 
 fn build_activate_command(data: &ActivateCommandData) -> String {
@@ -36,8 +46,12 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
         self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
     }
 
+    if data.log_format == LogFormat::Json {
+        self_activate_command = format!("{} --log-format json", self_activate_command);
+    }
+
     self_activate_command = format!(
-        "{} activate '{}' {} --temp-path '{}'",
+        "{} activate '{}' {} --temp-path '{}' --lock-id '{}'",
         self_activate_command,
         data.closure,
         match data.profile_info {
@@ -51,7 +65,8 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
                 profile_user, profile_name
             ),
         },
-        data.temp_path.display()
+        data.temp_path.display(),
+        data.lock_id
     );
 
     self_activate_command = format!(
@@ -59,6 +74,14 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
         self_activate_command, data.confirm_timeout
     );
 
+    // Left out by default, so profiles with an older activate-rs still understand the command
+    if data.activation_timeout != crate::DEFAULT_ACTIVATION_TIMEOUT {
+        self_activate_command = format!(
+            "{} --activation-timeout {}",
+            self_activate_command, data.activation_timeout
+        );
+    }
+
     if data.magic_rollback {
         self_activate_command = format!("{} --magic-rollback", self_activate_command);
     }
@@ -75,6 +98,17 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
         self_activate_command = format!("{} --boot", self_activate_command);
     }
 
+    if data.activation_retries > 0 {
+        self_activate_command = format!(
+            "{} --activation-retries {}",
+            self_activate_command, data.activation_retries
+        );
+    }
+
+    if data.secrets_from_stdin {
+        self_activate_command = format!("{} --secrets-from-stdin", self_activate_command);
+    }
+
     if let Some(sudo_cmd) = &data.sudo {
         self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
     }
@@ -93,10 +127,15 @@ fn test_activation_command_builder() {
     let dry_activate = false;
     let boot = false;
     let temp_path = Path::new("/tmp");
+    let lock_id = "test-0123abcd";
     let confirm_timeout = 30;
+    let activation_timeout = 240;
     let magic_rollback = true;
     let debug_logs = true;
     let log_dir = Some("/tmp/something.txt");
+    let log_format = LogFormat::Json;
+    let activation_retries = 0;
+    let secrets_from_stdin = false;
 
     assert_eq!(
         build_activate_command(&ActivateCommandData {
@@ -105,14 +144,42 @@ fn test_activation_command_builder() {
             closure,
             auto_rollback,
             temp_path,
+            lock_id,
             confirm_timeout,
+            activation_timeout,
             magic_rollback,
             debug_logs,
             log_dir,
+            log_format,
             dry_activate,
             boot,
+            activation_retries,
+            secrets_from_stdin,
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt --log-format json activate '/nix/store/blah/etc' --profile-path '/blah/profiles/test' --temp-path '/tmp' --lock-id 'test-0123abcd' --confirm-timeout 30 --magic-rollback --auto-rollback"
+            .to_string(),
+    );
+
+    assert_eq!(
+        build_activate_command(&ActivateCommandData {
+            sudo: &None,
+            profile_info,
+            closure,
+            auto_rollback,
+            temp_path,
+            lock_id,
+            confirm_timeout,
+            activation_timeout: 600,
+            magic_rollback,
+            debug_logs: false,
+            log_dir: None,
+            log_format: LogFormat::Emoji,
+            dry_activate,
+            boot: true,
+            activation_retries,
+            secrets_from_stdin,
         }),
-        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt activate '/nix/store/blah/etc' --profile-path '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --magic-rollback --auto-rollback"
+        "/nix/store/blah/etc/activate-rs activate '/nix/store/blah/etc' --profile-path '/blah/profiles/test' --temp-path '/tmp' --lock-id 'test-0123abcd' --confirm-timeout 30 --activation-timeout 600 --magic-rollback --auto-rollback --boot"
             .to_string(),
     );
 }
@@ -121,9 +188,11 @@ struct WaitCommandData<'a> {
     sudo: &'a Option<String>,
     closure: &'a str,
     temp_path: &'a Path,
-    activation_timeout: Option<u16>,
+    lock_id: &'a str,
+    activation_timeout: u16,
     debug_logs: bool,
     log_dir: Option<&'a str>,
+    log_format: LogFormat,
 }
 
 fn build_wait_command(data: &WaitCommandData) -> String {
@@ -137,15 +206,18 @@ fn build_wait_command(data: &WaitCommandData) -> String {
         self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
     }
 
+    if data.log_format == LogFormat::Json {
+        self_activate_command = format!("{} --log-format json", self_activate_command);
+    }
+
     self_activate_command = format!(
-        "{} wait '{}' --temp-path '{}'",
+        "{} wait '{}' --temp-path '{}' --lock-id '{}' --activation-timeout {}",
         self_activate_command,
         data.closure,
         data.temp_path.display(),
+        data.lock_id,
+        data.activation_timeout,
     );
-    if let Some(activation_timeout) = data.activation_timeout {
-        self_activate_command = format!("{} --activation-timeout {}", self_activate_command, activation_timeout);
-    }
 
     if let Some(sudo_cmd) = &data.sudo {
         self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
@@ -159,30 +231,74 @@ fn test_wait_command_builder() {
     let sudo = Some("sudo -u test".to_string());
     let closure = "/nix/store/blah/etc";
     let temp_path = Path::new("/tmp");
-    let activation_timeout = Some(600);
+    let lock_id = "test-0123abcd";
+    let activation_timeout = 600;
     let debug_logs = true;
     let log_dir = Some("/tmp/something.txt");
+    let log_format = LogFormat::Emoji;
 
     assert_eq!(
         build_wait_command(&WaitCommandData {
             sudo: &sudo,
             closure,
             temp_path,
+            lock_id,
             activation_timeout,
             debug_logs,
-            log_dir
+            log_dir,
+            log_format,
         }),
-        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt wait '/nix/store/blah/etc' --temp-path '/tmp' --activation-timeout 600"
+        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt wait '/nix/store/blah/etc' --temp-path '/tmp' --lock-id 'test-0123abcd' --activation-timeout 600"
             .to_string(),
     );
 }
 
+struct ConfirmCommandData<'a> {
+    sudo: &'a Option<String>,
+    lock_path: &'a Path,
+}
+
+fn build_confirm_command(data: &ConfirmCommandData) -> String {
+    let mut confirm_command = format!(
+        "rm {}",
+        shell_words::quote(&data.lock_path.display().to_string())
+    );
+
+    if let Some(sudo_cmd) = &data.sudo {
+        confirm_command = format!("{} {}", sudo_cmd, confirm_command);
+    }
+
+    confirm_command
+}
+
+#[test]
+fn test_confirm_command_builder() {
+    let sudo = Some("sudo -u test".to_string());
+    let lock_path = Path::new("/tmp/deploy-rs-canary-blah");
+
+    assert_eq!(
+        build_confirm_command(&ConfirmCommandData {
+            sudo: &sudo,
+            lock_path,
+        }),
+        "sudo -u test rm /tmp/deploy-rs-canary-blah".to_string(),
+    );
+    assert_eq!(
+        build_confirm_command(&ConfirmCommandData {
+            sudo: &None,
+            lock_path: Path::new("/home/o'brien/tmp/deploy-rs-canary-blah"),
+        }),
+        r#"rm '/home/o'\''brien/tmp/deploy-rs-canary-blah'"#.to_string(),
+    );
+}
+
 struct RevokeCommandData<'a> {
     sudo: &'a Option<String>,
     closure: &'a str,
     profile_info: ProfileInfo,
     debug_logs: bool,
     log_dir: Option<&'a str>,
+    log_format: LogFormat,
 }
 
 fn build_revoke_command(data: &RevokeCommandData) -> String {
@@ -196,6 +312,10 @@ fn build_revoke_command(data: &RevokeCommandData) -> String {
         self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
     }
 
+    if data.log_format == LogFormat::Json {
+        self_activate_command = format!("{} --log-format json", self_activate_command);
+    }
+
     self_activate_command = format!(
         "{} revoke {}",
         self_activate_command,
@@ -228,6 +348,7 @@ fn test_revoke_command_builder() {
     };
     let debug_logs = true;
     let log_dir = Some("/tmp/something.txt");
+    let log_format = LogFormat::Emoji;
 
     assert_eq!(
         build_revoke_command(&RevokeCommandData {
@@ -235,27 +356,186 @@ fn test_revoke_command_builder() {
             closure,
             profile_info,
             debug_logs,
-            log_dir
+            log_dir,
+            log_format,
         }),
         "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt revoke --profile-path '/nix/var/nix/per-user/user/profile'"
             .to_string(),
     );
 }
 
-async fn handle_sudo_stdin(ssh_activate_child: &mut tokio::process::Child, deploy_defs: &DeployDefs) -> Result<(), std::io::Error> {
+struct IsCurrentCommandData<'a> {
+    sudo: &'a Option<String>,
+    closure: &'a str,
+    profile_info: ProfileInfo,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+    log_format: LogFormat,
+}
+
+fn build_is_current_command(data: &IsCurrentCommandData) -> String {
+    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
+
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+    }
+
+    if data.log_format == LogFormat::Json {
+        self_activate_command = format!("{} --log-format json", self_activate_command);
+    }
+
+    self_activate_command = format!(
+        "{} is-current '{}' {}",
+        self_activate_command,
+        data.closure,
+        match &data.profile_info {
+            ProfileInfo::ProfilePath { profile_path } =>
+                format!("--profile-path '{}'", profile_path),
+            ProfileInfo::ProfileUserAndName {
+                profile_user,
+                profile_name,
+            } => format!(
+                "--profile-user {} --profile-name {}",
+                profile_user, profile_name
+            ),
+        }
+    );
+
+    if let Some(sudo_cmd) = &data.sudo {
+        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    }
+
+    self_activate_command
+}
+
+#[test]
+fn test_is_current_command_builder() {
+    let sudo = Some("sudo -u test".to_string());
+    let closure = "/nix/store/blah/etc";
+    let profile_info = ProfileInfo::ProfileUserAndName {
+        profile_user: "test".to_string(),
+        profile_name: "something".to_string(),
+    };
+    let debug_logs = true;
+    let log_dir = Some("/tmp/something.txt");
+    let log_format = LogFormat::Emoji;
+
+    assert_eq!(
+        build_is_current_command(&IsCurrentCommandData {
+            sudo: &sudo,
+            closure,
+            profile_info,
+            debug_logs,
+            log_dir,
+            log_format,
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt is-current '/nix/store/blah/etc' --profile-user test --profile-name something"
+            .to_string(),
+    );
+}
+
+/// Arguments for `sops` to decrypt a secret reference, either `<file>` or `<file>#<key>`
+fn sops_decrypt_args(reference: &str) -> Vec<String> {
+    match reference.split_once('#') {
+        Some((file, key)) => vec![
+            "--decrypt".to_string(),
+            "--extract".to_string(),
+            // Quotes and backslashes in the key are escaped like in JSON
+            format!("[{}]", serde_json::Value::from(key)),
+            file.to_string(),
+        ],
+        None => vec!["--decrypt".to_string(), reference.to_string()],
+    }
+}
+
+#[test]
+fn test_sops_decrypt_args() {
+    assert_eq!(
+        sops_decrypt_args("secrets/api.yaml#token"),
+        vec!["--decrypt", "--extract", "[\"token\"]", "secrets/api.yaml"]
+    );
+    assert_eq!(
+        sops_decrypt_args(r#"secrets/api.yaml#say "hi"\now"#),
+        vec![
+            "--decrypt",
+            "--extract",
+            r#"["say \"hi\"\\now"]"#,
+            "secrets/api.yaml"
+        ]
+    );
+    assert_eq!(
+        sops_decrypt_args("secrets/token.txt"),
+        vec!["--decrypt", "secrets/token.txt"]
+    );
+}
+
+/// Decrypts the `activationSecrets` of a profile locally with sops
+async fn resolve_activation_secrets(
+    activation_secrets: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, DeployProfileError> {
+    let mut resolved = HashMap::new();
+
+    for (name, reference) in activation_secrets {
+        debug!("Decrypting activation secret {} from {}", name, reference);
+
+        let output = Command::new("sops")
+            .args(sops_decrypt_args(reference))
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .await
+            .map_err(|e| DeployProfileError::SopsDecrypt(name.clone(), e))?;
+
+        match output.status.code() {
+            Some(0) => (),
+            a => return Err(DeployProfileError::SopsDecryptExit(name.clone(), a)),
+        };
+
+        let value = String::from_utf8(output.stdout)
+            .map_err(|_| DeployProfileError::SopsDecryptUtf8(name.clone()))?;
+
+        resolved.insert(name.clone(), value.trim_end_matches('\n').to_string());
+    }
+
+    Ok(resolved)
+}
+
+/// Passes the activation secrets to `activate-rs --secrets-from-stdin` as a single JSON line,
+/// keeping them out of command lines and logs
+async fn write_secrets_stdin(
+    ssh_activate_child: &mut tokio::process::Child,
+    secrets: &str,
+) -> Result<(), std::io::Error> {
+    match ssh_activate_child.stdin.as_mut() {
+        Some(stdin) => stdin.write_all(format!("{}\n", secrets).as_bytes()).await,
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "Failed to open stdin for activation secrets",
+        )),
+    }
+}
+
+async fn handle_sudo_stdin(
+    ssh_activate_child: &mut tokio::process::Child,
+    deploy_defs: &DeployDefs,
+) -> Result<(), std::io::Error> {
     match ssh_activate_child.stdin.as_mut() {
         Some(stdin) => {
-            let _ = stdin.write_all(format!("{}\n",deploy_defs.sudo_password.clone().unwrap_or("".to_string())).as_bytes()).await;
+            let password = deploy_defs
+                .sudo_password
+                .as_ref()
+                .map_or("", |p| p.as_str());
+            let _ = stdin.write_all(password.as_bytes()).await;
+            let _ = stdin.write_all(b"\n").await;
             Ok(())
         }
-        None => {
-            Err(
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to open stdin for sudo command",
-                )
-            )
-        }
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "Failed to open stdin for sudo command",
+        )),
     }
 }
 
@@ -267,6 +547,43 @@ pub enum ConfirmProfileError {
         "Confirming activation over SSH resulted in a bad exit code (the server should roll back): {0:?}"
     )]
     SSHConfirmExit(Option<i32>),
+
+    #[error("Failed to run the --confirm-via command (the server should roll back): {0}")]
+    ConfirmVia(std::io::Error),
+    #[error("The --confirm-via command resulted in a bad exit code (the server should roll back): {0:?}")]
+    ConfirmViaExit(Option<i32>),
+}
+
+/// Confirms the deployment with the user's `--confirm-via` command, for when the deployment
+/// breaks the SSH connection used to deploy it
+async fn confirm_profile_via(
+    deploy_data: &super::DeployData<'_>,
+    lock_path: &Path,
+    confirm_via: &str,
+) -> Result<(), ConfirmProfileError> {
+    debug!("Attempting to confirm deployment with: {}", confirm_via);
+
+    let hostname = deploy_data.hostname();
+
+    let confirm_exit_status = Command::new("sh")
+        .arg("-c")
+        .arg(confirm_via)
+        .env("DEPLOY_RS_NODE", deploy_data.node_name)
+        .env("DEPLOY_RS_PROFILE", deploy_data.profile_name)
+        .env("DEPLOY_RS_HOSTNAME", hostname)
+        .env("DEPLOY_RS_LOCK_PATH", lock_path)
+        .status()
+        .await
+        .map_err(ConfirmProfileError::ConfirmVia)?;
+
+    match confirm_exit_status.code() {
+        Some(0) => (),
+        a => return Err(ConfirmProfileError::ConfirmViaExit(a)),
+    };
+
+    info!("Deployment confirmed.");
+
+    Ok(())
 }
 
 pub async fn confirm_profile(
@@ -275,21 +592,33 @@ pub async fn confirm_profile(
     temp_path: &Path,
     ssh_addr: &str,
 ) -> Result<(), ConfirmProfileError> {
-    let mut ssh_confirm_command = Command::new("ssh");
-    ssh_confirm_command
-        .arg(ssh_addr)
-        .stdin(std::process::Stdio::piped());
+    // `activate-rs` may have fallen back from the temp path to another directory, which can only
+    // be looked for without asking it on the machine `deploy` runs on
+    let lock_path = match deploy_defs.lock_path.get() {
+        Some(x) => x.clone(),
+        None if deploy_data.merged_settings.local_deploy.unwrap_or(false) => super::find_lock_path(
+            temp_path,
+            &deploy_data.profile.profile_settings.path,
+            Some(&deploy_defs.lock_id),
+        ),
+        None => super::make_lock_path(
+            temp_path,
+            &deploy_data.profile.profile_settings.path,
+            Some(&deploy_defs.lock_id),
+        ),
+    };
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_confirm_command.arg(ssh_opt);
+    if let Some(ref confirm_via) = deploy_data.cmd_overrides.confirm_via {
+        return confirm_profile_via(deploy_data, &lock_path, confirm_via).await;
     }
 
-    let lock_path = super::make_lock_path(temp_path, &deploy_data.profile.profile_settings.path);
+    let mut ssh_confirm_command = crate::node_command(deploy_data, ssh_addr);
+    ssh_confirm_command.stdin(std::process::Stdio::piped());
 
-    let mut confirm_command = format!("rm {}", lock_path.display());
-    if let Some(sudo_cmd) = &deploy_defs.sudo {
-        confirm_command = format!("{} {}", sudo_cmd, confirm_command);
-    }
+    let confirm_command = build_confirm_command(&ConfirmCommandData {
+        sudo: &deploy_defs.sudo,
+        lock_path: &lock_path,
+    });
 
     debug!(
         "Attempting to run command to confirm deployment: {}",
@@ -300,8 +629,8 @@ pub async fn confirm_profile(
         .arg(confirm_command)
         .spawn()
         .map_err(ConfirmProfileError::SSHConfirm)?;
-    
-    if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
+
+    if deploy_defs.sudo_password.is_some() {
         trace!("[confirm] Piping in sudo password");
         handle_sudo_stdin(&mut ssh_confirm_child, deploy_defs)
             .await
@@ -311,7 +640,7 @@ pub async fn confirm_profile(
     let ssh_confirm_exit_status = ssh_confirm_child
         .wait()
         .await
-        .map_err(ConfirmProfileError::SSHConfirm)?; 
+        .map_err(ConfirmProfileError::SSHConfirm)?;
 
     match ssh_confirm_exit_status.code() {
         Some(0) => (),
@@ -323,6 +652,23 @@ pub async fn confirm_profile(
     Ok(())
 }
 
+/// Confirms a deployment that was activated by `deploy_profile` without confirmation
+pub async fn confirm_deployment(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<(), ConfirmProfileError> {
+    let temp_path: &Path = match &deploy_data.merged_settings.temp_path {
+        Some(x) => x,
+        None => Path::new("/tmp"),
+    };
+
+    let hostname = deploy_data.hostname();
+
+    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+
+    confirm_profile(deploy_data, deploy_defs, temp_path, &ssh_addr).await
+}
+
 #[derive(Error, Debug)]
 pub enum DeployProfileError {
     #[error("Failed to spawn activation command over SSH: {0}")]
@@ -341,95 +687,665 @@ pub enum DeployProfileError {
     #[error("Failed to pipe to child stdin: {0}")]
     SSHActivatePipe(std::io::Error),
 
+    #[error("Activating over SSH didn't finish within the deploy timeout of {0} seconds")]
+    Timeout(u16),
+
+    #[error("Failed to run sops to decrypt activation secret {0}: {1}")]
+    SopsDecrypt(String, std::io::Error),
+    #[error("Decrypting activation secret {0} with sops resulted in a bad exit code: {1:?}")]
+    SopsDecryptExit(String, Option<i32>),
+    #[error("Activation secret {0} is not valid UTF-8")]
+    SopsDecryptUtf8(String),
+    #[error("Failed to serialize the activation secrets: {0}")]
+    SerializeSecrets(serde_json::Error),
+
+    #[error("Failed to compare the closure with the profile over SSH: {0}")]
+    SSHDiff(std::io::Error),
+
+    #[error("Failed to run the smoke test over SSH: {0}")]
+    SSHSmokeTest(std::io::Error),
+    #[error("The smoke test failed with exit code {0:?}")]
+    SmokeTestExit(Option<i32>),
+
+    #[error("Failed to run the health check over SSH: {0}")]
+    SSHHealthCheck(std::io::Error),
+    #[error("The health check failed with exit code {0:?}, the profile was rolled back")]
+    HealthCheckExit(Option<i32>),
+    #[error("The health check didn't finish within {0} seconds, the profile was rolled back")]
+    HealthCheckTimeout(u16),
+
+    #[error("Failed to run activate-rs on the target over SSH: {0}")]
+    SSHCheckActivateRs(std::io::Error),
+    #[error("activate-rs can't be run on the target, resulting in exit code {0:?}. Is the profile built for the architecture of the target?\n{1}")]
+    CheckActivateRsExit(Option<i32>, String),
+    #[error("activate-rs {0} on the target speaks protocol version {1}, but deploy-rs {2} needs protocol version {3}. Use the same deploy-rs in the flake and for the deploy command")]
+    ActivateRsIncompatible(String, u32, String, u32),
+
     #[error("Error confirming deployment: {0}")]
     Confirm(#[from] ConfirmProfileError),
     #[error("Deployment data invalid: {0}")]
     InvalidDeployDataDefs(#[from] DeployDataDefsError),
 }
 
-pub async fn deploy_profile(
+impl DeployProfileError {
+    /// Whether the profile was deactivated again after its health check failed
+    pub fn deactivated_profile(&self) -> bool {
+        matches!(
+            self,
+            DeployProfileError::SSHHealthCheck(_)
+                | DeployProfileError::HealthCheckExit(_)
+                | DeployProfileError::HealthCheckTimeout(_)
+        )
+    }
+}
+
+/// `activate-rs` binaries that passed `check_activate_rs` on a node (by SSH address). Profiles
+/// built with the same deploy-rs link to the same one, which only needs checking once.
+static CHECKED_ACTIVATE_RS: OnceLock<Mutex<HashSet<(String, PathBuf)>>> = OnceLock::new();
+
+/// Checks that `activate-rs` of the profile can be executed on the target, which catches profiles
+/// built for the wrong architecture before activation fails with a cryptic `Exec format error`,
+/// and that it is compatible with this `deploy`
+async fn check_activate_rs(
     deploy_data: &super::DeployData<'_>,
-    deploy_defs: &super::DeployDefs,
-    dry_activate: bool,
-    boot: bool,
+    ssh_addr: &str,
 ) -> Result<(), DeployProfileError> {
-    if !dry_activate {
-        info!(
-            "Activating profile `{}` for node `{}`",
-            deploy_data.profile_name, deploy_data.node_name
+    let activate_rs = Path::new(&deploy_data.profile.profile_settings.path).join("activate-rs");
+    let checked_key = (
+        ssh_addr.to_string(),
+        activate_rs
+            .canonicalize()
+            .unwrap_or_else(|_| activate_rs.clone()),
+    );
+    let checked = CHECKED_ACTIVATE_RS.get_or_init(Default::default);
+    if checked.lock().unwrap().contains(&checked_key) {
+        debug!(
+            "{} was already checked on the target",
+            checked_key.1.display()
         );
+        return Ok(());
     }
 
-    let temp_path: &Path = match &deploy_data.merged_settings.temp_path {
-        Some(x) => x,
-        None => Path::new("/tmp"),
-    };
-
-    let confirm_timeout = deploy_data.merged_settings.confirm_timeout.unwrap_or(30);
+    let check_command = format!("{} version-json", activate_rs.display());
 
-    let activation_timeout = deploy_data.merged_settings.activation_timeout;
-
-    let magic_rollback = deploy_data.merged_settings.magic_rollback.unwrap_or(true);
+    debug!(
+        "Checking that activate-rs runs on the target: {}",
+        check_command
+    );
 
-    let auto_rollback = deploy_data.merged_settings.auto_rollback.unwrap_or(true);
+    let mut ssh_check_command = crate::node_command(deploy_data, ssh_addr);
 
-    let self_activate_command = build_activate_command(&ActivateCommandData {
-        sudo: &deploy_defs.sudo,
-        profile_info: &deploy_data.get_profile_info()?,
-        closure: &deploy_data.profile.profile_settings.path,
-        auto_rollback,
-        temp_path: temp_path,
-        confirm_timeout,
-        magic_rollback,
-        debug_logs: deploy_data.debug_logs,
-        log_dir: deploy_data.log_dir,
-        dry_activate,
-        boot,
-    });
+    let output = ssh_check_command
+        .arg(check_command)
+        .output()
+        .await
+        .map_err(DeployProfileError::SSHCheckActivateRs)?;
 
-    debug!("Constructed activation command: {}", self_activate_command);
+    let local = crate::ActivateRsVersion::current();
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
+    match output.status.code() {
+        Some(0) => (),
+        // Usage error: activate-rs from before `version-json` existed, which rejects the options
+        // `deploy` passes to it nowadays (e.g. `--lock-id`)
+        Some(2) => {
+            return Err(DeployProfileError::ActivateRsIncompatible(
+                "from before `version-json`".to_string(),
+                1,
+                local.version,
+                local.protocol,
+            ))
+        }
+        a => {
+            return Err(DeployProfileError::CheckActivateRsExit(
+                a,
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ))
+        }
     };
 
-    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let remote: crate::ActivateRsVersion = match stdout
+        .lines()
+        .last()
+        .and_then(|l| serde_json::from_str(l).ok())
+    {
+        Some(x) => x,
+        None => {
+            warn!(
+                "Couldn't make sense of the version of activate-rs: {:?}",
+                stdout
+            );
+            checked.lock().unwrap().insert(checked_key);
+            return Ok(());
+        }
+    };
 
-    let mut ssh_activate_command = Command::new("ssh");
-    ssh_activate_command
-        .arg(&ssh_addr)
-        .stdin(std::process::Stdio::piped());
+    if remote.protocol != local.protocol {
+        return Err(DeployProfileError::ActivateRsIncompatible(
+            remote.version,
+            remote.protocol,
+            local.version,
+            local.protocol,
+        ));
+    }
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_activate_command.arg(&ssh_opt);
+    if remote.version != local.version {
+        warn!(
+            "activate-rs of profile `{}` is version {}, but this is deploy-rs {}. Consider updating the deploy-rs input of the flake to match the deploy command",
+            deploy_data.profile_name, remote.version, local.version
+        );
     }
 
-    if !magic_rollback || dry_activate || boot {
-        let mut ssh_activate_child = ssh_activate_command
-            .arg(self_activate_command)
-            .spawn()
-            .map_err(DeployProfileError::SSHSpawnActivate)?;
+    checked.lock().unwrap().insert(checked_key);
 
-        if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
-            trace!("[activate] Piping in sudo password");
-            handle_sudo_stdin(&mut ssh_activate_child, deploy_defs)
-                .await
-                .map_err(DeployProfileError::SSHActivatePipe)?;
-        }
+    Ok(())
+}
 
-        let ssh_activate_exit_status = ssh_activate_child
-            .wait()
-            .await
-            .map_err(DeployProfileError::SSHActivate)?;
+struct DiffClosuresCommandData<'a> {
+    sudo: &'a Option<String>,
+    closure: &'a str,
+    profile_info: ProfileInfo,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+    log_format: LogFormat,
+}
 
-        match ssh_activate_exit_status.code() {
-            Some(0) => (),
-            a => return Err(DeployProfileError::SSHActivateExit(a)),
-        };
+/// Compares the closure with the one the profile currently points at, which `activate-rs` finds
+/// the same way as when activating
+fn build_diff_closures_command(data: &DiffClosuresCommandData) -> String {
+    let mut self_activate_command = format!("{}/activate-rs", data.closure);
 
-        if dry_activate {
-            info!("Completed dry-activate!");
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
+
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+    }
+
+    if data.log_format == LogFormat::Json {
+        self_activate_command = format!("{} --log-format json", self_activate_command);
+    }
+
+    self_activate_command = format!(
+        "{} diff-closures '{}' {}",
+        self_activate_command,
+        data.closure,
+        match &data.profile_info {
+            ProfileInfo::ProfilePath { profile_path } =>
+                format!("--profile-path '{}'", profile_path),
+            ProfileInfo::ProfileUserAndName {
+                profile_user,
+                profile_name,
+            } => format!(
+                "--profile-user {} --profile-name {}",
+                profile_user, profile_name
+            ),
+        }
+    );
+
+    if let Some(sudo_cmd) = &data.sudo {
+        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    }
+
+    self_activate_command
+}
+
+#[test]
+fn test_diff_closures_command_builder() {
+    let sudo = Some("sudo -u test".to_string());
+    let closure = "/nix/store/blah/etc";
+    let profile_info = ProfileInfo::ProfileUserAndName {
+        profile_user: "test".to_string(),
+        profile_name: "something".to_string(),
+    };
+    let debug_logs = true;
+    let log_dir = Some("/tmp/something.txt");
+    let log_format = LogFormat::Emoji;
+
+    assert_eq!(
+        build_diff_closures_command(&DiffClosuresCommandData {
+            sudo: &sudo,
+            closure,
+            profile_info,
+            debug_logs,
+            log_dir,
+            log_format,
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt diff-closures '/nix/store/blah/etc' --profile-user test --profile-name something"
+            .to_string(),
+    );
+    assert_eq!(
+        build_diff_closures_command(&DiffClosuresCommandData {
+            sudo: &None,
+            closure,
+            profile_info: ProfileInfo::ProfilePath {
+                profile_path: "/home/alice/.local/state/nix/profiles/home".to_string(),
+            },
+            debug_logs: false,
+            log_dir: None,
+            log_format,
+        }),
+        "/nix/store/blah/etc/activate-rs diff-closures '/nix/store/blah/etc' --profile-path '/home/alice/.local/state/nix/profiles/home'"
+            .to_string(),
+    );
+}
+
+/// Shows what changes between the profile on the node and the closure being dry-activated
+async fn show_closure_diff(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    ssh_addr: &str,
+) -> Result<(), DeployProfileError> {
+    let diff_command = build_diff_closures_command(&DiffClosuresCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        profile_info: deploy_data.get_profile_info()?,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir,
+        log_format: deploy_data.cmd_overrides.log_format,
+    });
+
+    debug!("Constructed diff command: {}", diff_command);
+
+    let mut ssh_diff_command = crate::node_command(deploy_data, ssh_addr);
+    ssh_diff_command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+
+    let mut ssh_diff_child = ssh_diff_command
+        .arg(diff_command)
+        .spawn()
+        .map_err(DeployProfileError::SSHDiff)?;
+
+    if deploy_defs.sudo_password.is_some() {
+        trace!("[diff-closures] Piping in sudo password");
+        handle_sudo_stdin(&mut ssh_diff_child, deploy_defs)
+            .await
+            .map_err(DeployProfileError::SSHDiff)?;
+    }
+
+    let output = ssh_diff_child
+        .wait_with_output()
+        .await
+        .map_err(DeployProfileError::SSHDiff)?;
+
+    // Only informational, e.g. a profile that doesn't exist yet has nothing to compare with
+    match output.status.code() {
+        Some(0) => info!(
+            "Changes to profile `{}` on node `{}`:\n{}",
+            deploy_data.profile_name,
+            deploy_data.node_name,
+            String::from_utf8_lossy(&output.stdout).trim_end()
+        ),
+        a => warn!(
+            "Failed to compare profile `{}` with the new closure, exit code {:?}: {}",
+            deploy_data.profile_name,
+            a,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+
+    Ok(())
+}
+
+/// Runs the smoke test of the profile on the node after activation
+async fn run_smoke_test(
+    deploy_data: &super::DeployData<'_>,
+    ssh_addr: &str,
+    smoke_test: &str,
+) -> Result<(), DeployProfileError> {
+    info!("Running smoke test {}", smoke_test);
+
+    let mut ssh_smoke_test_command = crate::node_command(deploy_data, ssh_addr);
+
+    let smoke_test_exit_status = ssh_smoke_test_command
+        .arg(smoke_test)
+        .status()
+        .await
+        .map_err(DeployProfileError::SSHSmokeTest)?;
+
+    match smoke_test_exit_status.code() {
+        Some(0) => (),
+        a => return Err(DeployProfileError::SmokeTestExit(a)),
+    };
+
+    info!("Smoke test succeeded");
+
+    Ok(())
+}
+
+/// Runs the health check of the profile on the node once the deployment is confirmed,
+/// deactivating the profile again if it fails
+pub async fn run_health_check(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<(), DeployProfileError> {
+    let health_check = match deploy_data.profile.profile_settings.health_check {
+        Some(ref x) => x,
+        None => return Ok(()),
+    };
+
+    let health_check_timeout = deploy_data
+        .merged_settings
+        .health_check_timeout
+        .unwrap_or(crate::DEFAULT_HEALTH_CHECK_TIMEOUT);
+
+    let hostname = deploy_data.hostname();
+
+    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+
+    info!("Running health check {}", health_check);
+
+    let mut ssh_health_check_command = crate::node_command(deploy_data, &ssh_addr);
+
+    let health_check_status = tokio::time::timeout(
+        std::time::Duration::from_secs(health_check_timeout.into()),
+        ssh_health_check_command.arg(health_check).status(),
+    )
+    .await;
+
+    let err = match health_check_status {
+        Ok(Ok(status)) if status.success() => {
+            info!("Health check succeeded");
+            return Ok(());
+        }
+        Ok(Ok(status)) => DeployProfileError::HealthCheckExit(status.code()),
+        Ok(Err(e)) => DeployProfileError::SSHHealthCheck(e),
+        Err(_) => DeployProfileError::HealthCheckTimeout(health_check_timeout),
+    };
+
+    warn!(
+        "Health check failed, deactivating profile `{}` for node `{}`",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    if let Err(e) = revoke(deploy_data, deploy_defs).await {
+        warn!(
+            "Failed to deactivate the profile after the failed health check: {}",
+            e
+        );
+    }
+
+    Err(err)
+}
+
+/// Awaits `f`, giving up with `None` once `deployTimeout` seconds have passed
+async fn within_deploy_timeout<F: std::future::Future>(
+    deploy_timeout: Option<u16>,
+    f: F,
+) -> Option<F::Output> {
+    match deploy_timeout {
+        Some(secs) => tokio::time::timeout(std::time::Duration::from_secs(secs.into()), f)
+            .await
+            .ok(),
+        None => Some(f.await),
+    }
+}
+
+/// A line printed to stderr by a command run on the node
+#[derive(Debug, PartialEq)]
+enum RemoteLogLine {
+    /// A log line of activate-rs with `--log-format json`
+    Json(serde_json::Map<String, serde_json::Value>),
+    /// Any other line, with the level activate-rs logged it at (`info` if it isn't a log line of it)
+    Text(log::Level, String),
+}
+
+fn parse_remote_log_line(line: &str) -> RemoteLogLine {
+    if let Ok(serde_json::Value::Object(object)) = serde_json::from_str(line) {
+        if object.contains_key("level") && object.contains_key("msg") {
+            return RemoteLogLine::Json(object);
+        }
+    }
+
+    // The level is colored like `[\x1b[1;31mERROR\x1b[0m]`
+    let mut plain = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            plain.push(c);
+        }
+    }
+
+    let level = [
+        log::Level::Error,
+        log::Level::Warn,
+        log::Level::Info,
+        log::Level::Debug,
+        log::Level::Trace,
+    ]
+    .iter()
+    .copied()
+    .find(|level| plain.contains(&format!("[{}]", level)))
+    .unwrap_or(log::Level::Info);
+
+    RemoteLogLine::Text(level, line.to_string())
+}
+
+#[test]
+fn test_parse_remote_log_line() {
+    assert_eq!(
+        parse_remote_log_line(
+            "⭐ ❌ [activate] [\x1b[1;31mERROR\x1b[0m] The activation script failed"
+        ),
+        RemoteLogLine::Text(
+            log::Level::Error,
+            "⭐ ❌ [activate] [\x1b[1;31mERROR\x1b[0m] The activation script failed".to_string()
+        )
+    );
+    assert_eq!(
+        parse_remote_log_line("👀 ⚠️ [wait] [WARN] Waiting"),
+        RemoteLogLine::Text(log::Level::Warn, "👀 ⚠️ [wait] [WARN] Waiting".to_string())
+    );
+    assert_eq!(
+        parse_remote_log_line("stopping the following units: foo.service"),
+        RemoteLogLine::Text(
+            log::Level::Info,
+            "stopping the following units: foo.service".to_string()
+        )
+    );
+
+    match parse_remote_log_line(
+        r#"{"level":"error","stage":"activate","node":null,"profile":"system","msg":"failed","ts":"2024-01-01T00:00:00+00:00"}"#,
+    ) {
+        RemoteLogLine::Json(object) => assert_eq!(object["level"], "error"),
+        x => panic!("expected a JSON log line, got {:?}", x),
+    }
+}
+
+/// Logs what a command run on the node prints to stderr line by line, prefixed with the node and
+/// profile, so that the output of deployments running at the same time can be told apart. Log
+/// lines of activate-rs keep their level, and JSON ones are passed on as they are, with the node
+/// filled in. Those are written to stderr directly rather than logged again, but only if the logger
+/// lets their level through.
+fn forward_remote_logs(
+    child: &mut tokio::process::Child,
+    deploy_data: &super::DeployData<'_>,
+) -> Option<tokio::task::JoinHandle<()>> {
+    use tokio::io::AsyncBufReadExt;
+
+    let mut lines = tokio::io::BufReader::new(child.stderr.take()?).lines();
+    let node_name = deploy_data.node_name.to_string();
+    let profile_name = deploy_data.profile_name.to_string();
+
+    Some(tokio::spawn(async move {
+        let prefix = format!("[{}/{}]", node_name, profile_name);
+
+        super::with_log_context(Some(&node_name), Some(&profile_name), async {
+            while let Ok(Some(line)) = lines.next_line().await {
+                match parse_remote_log_line(&line) {
+                    RemoteLogLine::Json(mut object) => {
+                        // activate-rs doesn't know the name of the node
+                        if let None | Some(serde_json::Value::Null) = object.get("node") {
+                            object.insert("node".to_string(), node_name.clone().into());
+                        }
+                        let level = object
+                            .get("level")
+                            .and_then(|l| l.as_str())
+                            .and_then(|l| l.parse().ok())
+                            .unwrap_or(log::Level::Info);
+                        if log::log_enabled!(level) {
+                            eprintln!("{}", serde_json::Value::Object(object));
+                        }
+                    }
+                    RemoteLogLine::Text(level, line) => log::log!(level, "{} {}", prefix, line),
+                }
+            }
+        })
+        .await
+    }))
+}
+
+pub async fn deploy_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    dry_activate: bool,
+    boot: bool,
+    confirm: bool,
+    smoke_test: Option<&str>,
+) -> Result<(), DeployProfileError> {
+    if !dry_activate {
+        info!(
+            "Activating profile `{}` for node `{}`",
+            deploy_data.profile_name, deploy_data.node_name
+        );
+    }
+
+    let temp_path: &Path = match &deploy_data.merged_settings.temp_path {
+        Some(x) => x,
+        None => Path::new("/tmp"),
+    };
+
+    let confirm_timeout = deploy_data.confirm_timeout();
+
+    let mut activation_timeout = deploy_data
+        .merged_settings
+        .activation_timeout
+        .unwrap_or(crate::DEFAULT_ACTIVATION_TIMEOUT);
+
+    // The waiter must not give up before the activated profile would roll back on its own,
+    // otherwise the deployment is reported as failed while the node may still get confirmed
+    if activation_timeout < confirm_timeout {
+        warn!(
+            "Activation timeout ({}s) is lower than the confirm timeout ({}s), using {}s for both",
+            activation_timeout, confirm_timeout, confirm_timeout
+        );
+        activation_timeout = confirm_timeout;
+    }
+
+    let magic_rollback = deploy_data.merged_settings.magic_rollback.unwrap_or(true);
+
+    let auto_rollback = deploy_data.merged_settings.auto_rollback.unwrap_or(true);
+
+    let self_activate_command = build_activate_command(&ActivateCommandData {
+        sudo: &deploy_defs.sudo,
+        profile_info: &deploy_data.get_profile_info()?,
+        closure: &deploy_data.profile.profile_settings.path,
+        auto_rollback,
+        temp_path,
+        lock_id: &deploy_defs.lock_id,
+        confirm_timeout,
+        activation_timeout,
+        magic_rollback,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir,
+        log_format: deploy_data.cmd_overrides.log_format,
+        dry_activate,
+        boot,
+        activation_retries: deploy_data.cmd_overrides.activation_retries.unwrap_or(0),
+        secrets_from_stdin: !deploy_data.merged_settings.activation_secrets.is_empty(),
+    });
+
+    debug!("Constructed activation command: {}", self_activate_command);
+
+    let hostname = deploy_data.hostname();
+
+    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+
+    check_activate_rs(deploy_data, &ssh_addr).await?;
+
+    if dry_activate && deploy_data.cmd_overrides.diff {
+        show_closure_diff(deploy_data, deploy_defs, &ssh_addr).await?;
+    }
+
+    let activation_secrets = if deploy_data.merged_settings.activation_secrets.is_empty() {
+        None
+    } else {
+        let secrets =
+            resolve_activation_secrets(&deploy_data.merged_settings.activation_secrets).await?;
+
+        Some(serde_json::to_string(&secrets).map_err(DeployProfileError::SerializeSecrets)?)
+    };
+
+    let mut ssh_activate_command = crate::node_command(deploy_data, &ssh_addr);
+    ssh_activate_command
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+
+    let deploy_timeout = deploy_data.merged_settings.deploy_timeout;
+
+    if !magic_rollback || dry_activate || boot {
+        let mut ssh_activate_child = ssh_activate_command
+            .arg(self_activate_command)
+            .spawn()
+            .map_err(DeployProfileError::SSHSpawnActivate)?;
+        let activate_logs = forward_remote_logs(&mut ssh_activate_child, deploy_data);
+
+        if deploy_defs.sudo_password.is_some() {
+            trace!("[activate] Piping in sudo password");
+            handle_sudo_stdin(&mut ssh_activate_child, deploy_defs)
+                .await
+                .map_err(DeployProfileError::SSHActivatePipe)?;
+        }
+
+        if let Some(ref secrets) = activation_secrets {
+            trace!("[activate] Piping in activation secrets");
+            write_secrets_stdin(&mut ssh_activate_child, secrets)
+                .await
+                .map_err(DeployProfileError::SSHActivatePipe)?;
+        }
+
+        let ssh_activate_exit_status =
+            match within_deploy_timeout(deploy_timeout, ssh_activate_child.wait()).await {
+                Some(x) => x.map_err(DeployProfileError::SSHActivate)?,
+                None => {
+                    let _ = ssh_activate_child.kill().await;
+                    if let Some(activate_logs) = activate_logs {
+                        let _ = activate_logs.await;
+                    }
+                    let err = DeployProfileError::Timeout(deploy_timeout.unwrap_or_default());
+                    error!("{}", err);
+                    return Err(err);
+                }
+            };
+
+        // The last lines may still be on their way once ssh exits
+        if let Some(activate_logs) = activate_logs {
+            let _ = activate_logs.await;
+        }
+
+        match ssh_activate_exit_status.code() {
+            Some(0) => (),
+            a => return Err(DeployProfileError::SSHActivateExit(a)),
+        };
+
+        // Without magic rollback, a failing smoke test can only fail the deployment
+        if let (Some(smoke_test), false, false) = (smoke_test, dry_activate, boot) {
+            run_smoke_test(deploy_data, &ssh_addr, smoke_test).await?;
+        }
+
+        if !dry_activate && !boot {
+            run_health_check(deploy_data, deploy_defs).await?;
+        }
+
+        if dry_activate && boot {
+            info!("Completed dry-activate for next boot!");
+        } else if dry_activate {
+            info!("Completed dry-activate!");
         } else if boot {
             info!("Success activating for next boot, done!");
         } else {
@@ -439,10 +1355,12 @@ pub async fn deploy_profile(
         let self_wait_command = build_wait_command(&WaitCommandData {
             sudo: &deploy_defs.sudo,
             closure: &deploy_data.profile.profile_settings.path,
-            temp_path: temp_path,
-            activation_timeout: activation_timeout,
+            temp_path,
+            lock_id: &deploy_defs.lock_id,
+            activation_timeout,
             debug_logs: deploy_data.debug_logs,
             log_dir: deploy_data.log_dir,
+            log_format: deploy_data.cmd_overrides.log_format,
         });
 
         debug!("Constructed wait command: {}", self_wait_command);
@@ -451,70 +1369,136 @@ pub async fn deploy_profile(
             .arg(self_activate_command)
             .spawn()
             .map_err(DeployProfileError::SSHSpawnActivate)?;
+        // Keeps forwarding while the activation waits for its confirmation in the background
+        let activate_logs = forward_remote_logs(&mut ssh_activate_child, deploy_data);
 
-        if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
+        if deploy_defs.sudo_password.is_some() {
             trace!("[activate] Piping in sudo password");
             handle_sudo_stdin(&mut ssh_activate_child, deploy_defs)
                 .await
                 .map_err(DeployProfileError::SSHActivatePipe)?;
         }
 
+        if let Some(ref secrets) = activation_secrets {
+            trace!("[activate] Piping in activation secrets");
+            write_secrets_stdin(&mut ssh_activate_child, secrets)
+                .await
+                .map_err(DeployProfileError::SSHActivatePipe)?;
+        }
+
         info!("Creating activation waiter");
 
-        let mut ssh_wait_command = Command::new("ssh");
+        let mut ssh_wait_command = crate::node_command(deploy_data, &ssh_addr);
         ssh_wait_command
-            .arg(&ssh_addr)
-            .stdin(std::process::Stdio::piped());
-        
-        for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-            ssh_wait_command.arg(ssh_opt);
-        }
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
 
         let (send_activate, recv_activate) = tokio::sync::oneshot::channel();
         let (send_activated, recv_activated) = tokio::sync::oneshot::channel();
 
+        // Spawned tasks don't inherit the node and profile of the JSON log lines
+        let (node_name, profile_name) = (
+            deploy_data.node_name.to_string(),
+            deploy_data.profile_name.to_string(),
+        );
         let thread = tokio::spawn(async move {
-            let o = ssh_activate_child.wait_with_output().await;
-
-            let maybe_err = match o {
-                Err(x) => Some(DeployProfileError::SSHActivate(x)),
-                Ok(ref x) => match x.status.code() {
-                    Some(0) => None,
-                    a => Some(DeployProfileError::SSHActivateExit(a)),
-                },
-            };
+            crate::with_log_context(Some(&node_name), Some(&profile_name), async move {
+                let o = ssh_activate_child.wait_with_output().await;
+
+                // Its last lines are logged before anyone hears the activation ended
+                if let Some(activate_logs) = activate_logs {
+                    let _ = activate_logs.await;
+                }
+
+                let maybe_err = match o {
+                    Err(x) => Some(DeployProfileError::SSHActivate(x)),
+                    Ok(ref x) => match x.status.code() {
+                        Some(0) => None,
+                        a => Some(DeployProfileError::SSHActivateExit(a)),
+                    },
+                };
 
-            if let Some(err) = maybe_err {
-                send_activate.send(err).unwrap();
-            }
+                // The receivers are gone if the deployment is left unconfirmed for a later `confirm_deployment`
+                if let Some(err) = maybe_err {
+                    let _ = send_activate.send(err);
+                }
 
-            send_activated.send(()).unwrap();
+                let _ = send_activated.send(());
+            })
+            .await
         });
 
         let mut ssh_wait_child = ssh_wait_command
             .arg(self_wait_command)
             .spawn()
             .map_err(DeployProfileError::SSHWait)?;
+        let wait_logs = forward_remote_logs(&mut ssh_wait_child, deploy_data);
 
-        if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
+        if deploy_defs.sudo_password.is_some() {
             trace!("[wait] Piping in sudo password");
             handle_sudo_stdin(&mut ssh_wait_child, deploy_defs)
                 .await
                 .map_err(DeployProfileError::SSHActivatePipe)?;
         }
 
-        tokio::select! {
-            x = ssh_wait_child.wait() => {
-                debug!("Wait command ended");
-                match x.map_err(DeployProfileError::SSHWait)?.code() {
-                    Some(0) => (),
-                    a => return Err(DeployProfileError::SSHWaitExit(a)),
-                };
-            },
-            x = recv_activate => {
-                debug!("Activate command exited with an error");
-                return Err(x.unwrap());
-            },
+        let waited = within_deploy_timeout(deploy_timeout, async {
+            tokio::select! {
+                x = ssh_wait_child.wait() => {
+                    debug!("Wait command ended");
+                    match x.map_err(DeployProfileError::SSHWait)?.code() {
+                        Some(0) => Ok(()),
+                        a => Err(DeployProfileError::SSHWaitExit(a)),
+                    }
+                },
+                x = recv_activate => {
+                    debug!("Activate command exited with an error");
+                    Err(x.unwrap())
+                },
+            }
+        })
+        .await;
+
+        // The wait command is still waiting if the activation failed or it timed out
+        if !matches!(waited, Some(Ok(()))) {
+            let _ = ssh_wait_child.kill().await;
+        }
+        if let Some(wait_logs) = wait_logs {
+            let _ = wait_logs.await;
+        }
+
+        match waited {
+            Some(x) => x?,
+            None => {
+                // Drops the activation's ssh process, the profile rolls back as it isn't confirmed
+                thread.abort();
+                let err = DeployProfileError::Timeout(deploy_timeout.unwrap_or_default());
+                error!("{}", err);
+                return Err(err);
+            }
+        }
+
+        let mut wait_stdout = String::new();
+        if let Some(mut stdout) = ssh_wait_child.stdout.take() {
+            let _ = stdout.read_to_string(&mut wait_stdout).await;
+        }
+        if let Some(lock_path) = reported_lock_path(
+            &wait_stdout,
+            &deploy_data.profile.profile_settings.path,
+            &deploy_defs.lock_id,
+        ) {
+            let _ = deploy_defs.lock_path.set(lock_path);
+        }
+
+        // A failing smoke test leaves the activation unconfirmed, so that it rolls back
+        if let Some(smoke_test) = smoke_test {
+            run_smoke_test(deploy_data, &ssh_addr, smoke_test).await?;
+        }
+
+        if !confirm {
+            // The activation keeps waiting for the confirmation (and rolls back without it) in the background
+            info!("Success activating, leaving the activation unconfirmed for now");
+            return Ok(());
         }
 
         info!("Success activating, attempting to confirm activation");
@@ -526,11 +1510,47 @@ pub async fn deploy_profile(
         thread
             .await
             .map_err(|x| DeployProfileError::SSHActivate(x.into()))?;
+
+        run_health_check(deploy_data, deploy_defs).await?;
     }
 
     Ok(())
 }
 
+/// The canary file `activate-rs wait` found, which it prints last. Older versions print nothing
+/// but may print where they log to, and only ever use the temp path.
+fn reported_lock_path(wait_stdout: &str, closure: &str, lock_id: &str) -> Option<PathBuf> {
+    let canary = super::make_lock_path(Path::new("/"), closure, Some(lock_id));
+
+    match wait_stdout.lines().last().map(|l| Path::new(l.trim())) {
+        Some(path) if path.is_absolute() && path.file_name() == canary.file_name() => {
+            Some(path.to_path_buf())
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn test_reported_lock_path() {
+    let closure = "/nix/store/blah-system";
+
+    assert_eq!(
+        reported_lock_path(
+            "Logging to /var/log/deploy\n/run/user/1000/deploy-rs-canary-blah-system-0123abcd\n",
+            closure,
+            "system-0123abcd"
+        ),
+        Some(PathBuf::from(
+            "/run/user/1000/deploy-rs-canary-blah-system-0123abcd"
+        ))
+    );
+    assert_eq!(
+        reported_lock_path("Logging to /var/log/deploy\n", closure, "system-0123abcd"),
+        None
+    );
+    assert_eq!(reported_lock_path("", closure, "system-0123abcd"), None);
+}
+
 #[derive(Error, Debug)]
 pub enum RevokeProfileError {
     #[error("Failed to spawn revocation command over SSH: {0}")]
@@ -554,32 +1574,24 @@ pub async fn revoke(
         profile_info: deploy_data.get_profile_info()?,
         debug_logs: deploy_data.debug_logs,
         log_dir: deploy_data.log_dir,
+        log_format: deploy_data.cmd_overrides.log_format,
     });
 
     debug!("Constructed revoke command: {}", self_revoke_command);
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
-    };
+    let hostname = deploy_data.hostname();
 
     let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
 
-    let mut ssh_activate_command = Command::new("ssh");
-    ssh_activate_command
-        .arg(&ssh_addr)
-        .stdin(std::process::Stdio::piped());
-
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_activate_command.arg(&ssh_opt);
-    }
+    let mut ssh_activate_command = crate::node_command(deploy_data, &ssh_addr);
+    ssh_activate_command.stdin(std::process::Stdio::piped());
 
     let mut ssh_revoke_child = ssh_activate_command
         .arg(self_revoke_command)
         .spawn()
         .map_err(RevokeProfileError::SSHSpawnRevoke)?;
 
-    if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
+    if deploy_defs.sudo_password.is_some() {
         trace!("[revoke] Piping in sudo password");
         handle_sudo_stdin(&mut ssh_revoke_child, deploy_defs)
             .await
@@ -596,3 +1608,163 @@ pub async fn revoke(
         },
     }
 }
+
+fn build_gc_command(sudo: &Option<String>) -> String {
+    let gc_command = "nix --experimental-features nix-command store gc".to_string();
+
+    match sudo {
+        Some(sudo_cmd) => format!("{} {}", sudo_cmd, gc_command),
+        None => gc_command,
+    }
+}
+
+#[test]
+fn test_gc_command_builder() {
+    assert_eq!(
+        build_gc_command(&Some("sudo -u test".to_string())),
+        "sudo -u test nix --experimental-features nix-command store gc"
+    );
+    assert_eq!(
+        build_gc_command(&None),
+        "nix --experimental-features nix-command store gc"
+    );
+}
+
+#[derive(Error, Debug)]
+pub enum CollectGarbageError {
+    #[error("Failed to spawn garbage collection command over SSH: {0}")]
+    SSHSpawnGc(std::io::Error),
+
+    #[error("Error collecting garbage: {0}")]
+    SSHGc(std::io::Error),
+    #[error("Collecting garbage over SSH resulted in a bad exit code: {0:?}")]
+    SSHGcExit(Option<i32>),
+}
+
+/// Collects garbage in the store of the node, only meant to be run after a confirmed deployment
+pub async fn collect_garbage(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<(), CollectGarbageError> {
+    info!("Collecting garbage on node `{}`", deploy_data.node_name);
+
+    let gc_command = build_gc_command(&deploy_defs.sudo);
+
+    debug!("Constructed garbage collection command: {}", gc_command);
+
+    let hostname = deploy_data.hostname();
+
+    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+
+    let mut ssh_gc_command = crate::node_command(deploy_data, &ssh_addr);
+    ssh_gc_command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+
+    let mut ssh_gc_child = ssh_gc_command
+        .arg(gc_command)
+        .spawn()
+        .map_err(CollectGarbageError::SSHSpawnGc)?;
+
+    if deploy_defs.sudo_password.is_some() {
+        trace!("[gc] Piping in sudo password");
+        handle_sudo_stdin(&mut ssh_gc_child, deploy_defs)
+            .await
+            .map_err(CollectGarbageError::SSHGc)?;
+    }
+
+    let output = ssh_gc_child
+        .wait_with_output()
+        .await
+        .map_err(CollectGarbageError::SSHGc)?;
+
+    for line in String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .chain(String::from_utf8_lossy(&output.stderr).lines())
+    {
+        info!("[gc] {}", line);
+    }
+
+    match output.status.code() {
+        Some(0) => Ok(()),
+        a => Err(CollectGarbageError::SSHGcExit(a)),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CheckCurrentProfileError {
+    #[error("Failed to spawn profile check command over SSH: {0}")]
+    SSHSpawnCheck(std::io::Error),
+
+    #[error("Error checking the current profile: {0}")]
+    SSHCheck(std::io::Error),
+    #[error("Checking the current profile over SSH resulted in a bad exit code: {0:?}")]
+    SSHCheckExit(Option<i32>),
+    #[error("Unexpected output from profile check command: {0:?}")]
+    UnexpectedOutput(String),
+
+    #[error("Deployment data invalid: {0}")]
+    InvalidDeployDataDefs(#[from] DeployDataDefsError),
+}
+
+/// Returns whether the profile on the target already points at the closure being deployed
+pub async fn profile_is_current(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<bool, CheckCurrentProfileError> {
+    let self_is_current_command = build_is_current_command(&IsCurrentCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        profile_info: deploy_data.get_profile_info()?,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir,
+        log_format: deploy_data.cmd_overrides.log_format,
+    });
+
+    debug!(
+        "Constructed is-current command: {}",
+        self_is_current_command
+    );
+
+    let hostname = deploy_data.hostname();
+
+    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+
+    let mut ssh_is_current_command = crate::node_command(deploy_data, &ssh_addr);
+    ssh_is_current_command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped());
+
+    let mut ssh_is_current_child = ssh_is_current_command
+        .arg(self_is_current_command)
+        .spawn()
+        .map_err(CheckCurrentProfileError::SSHSpawnCheck)?;
+
+    if deploy_defs.sudo_password.is_some() {
+        trace!("[is-current] Piping in sudo password");
+        handle_sudo_stdin(&mut ssh_is_current_child, deploy_defs)
+            .await
+            .map_err(CheckCurrentProfileError::SSHCheck)?;
+    }
+
+    let output = ssh_is_current_child
+        .wait_with_output()
+        .await
+        .map_err(CheckCurrentProfileError::SSHCheck)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(CheckCurrentProfileError::SSHCheckExit(a)),
+    };
+
+    // activate-rs may print where it logs to before the answer when using `--log-dir`
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.lines().last().map(str::trim) {
+        Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        _ => Err(CheckCurrentProfileError::UnexpectedOutput(
+            stdout.into_owned(),
+        )),
+    }
+}
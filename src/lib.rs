@@ -4,20 +4,316 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use rnix::{types::*, SyntaxKind::*};
+use rnix::{types::*, value::StrPart, SyntaxKind::*};
 
 use merge::Merge;
+use serde::{Deserialize, Serialize};
 
 use thiserror::Error;
 
 use flexi_logger::*;
 
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-pub fn make_lock_path(temp_path: &Path, closure: &str) -> PathBuf {
-    let lock_hash =
-        &closure["/nix/store/".len()..closure.find('-').unwrap_or_else(|| closure.len())];
-    temp_path.join(format!("deploy-rs-canary-{}", lock_hash))
+/// Seconds the activated profile waits for confirmation before rolling back (magic rollback)
+pub const DEFAULT_CONFIRM_TIMEOUT: u16 = 30;
+/// Default confirmation timeout for nodes with `fastConnection`, whose confirmation gets through quickly
+pub const FAST_CONNECTION_CONFIRM_TIMEOUT: u16 = 10;
+/// Bounds of the confirmation window of magic rollback: a shorter one rolls back good deployments
+/// before their confirmation gets through, a longer one leaves a broken node waiting for ages
+pub const MIN_CONFIRM_TIMEOUT: u16 = 5;
+pub const MAX_CONFIRM_TIMEOUT: u16 = 3600;
+/// Seconds the waiter waits for activation to finish
+pub const DEFAULT_ACTIVATION_TIMEOUT: u16 = 240;
+/// Seconds the health check may run before it counts as failed
+pub const DEFAULT_HEALTH_CHECK_TIMEOUT: u16 = 60;
+/// Seconds before the first retry of a failed `nix copy`, doubling with every further retry
+pub const DEFAULT_COPY_RETRY_DELAY: u16 = 2;
+/// Seconds between SSH keepalive messages, so idle connections (e.g. while waiting for confirmation) aren't dropped
+pub const DEFAULT_SSH_KEEPALIVE_INTERVAL: u16 = 30;
+/// Unanswered SSH keepalive messages after which the connection is considered dead
+pub const DEFAULT_SSH_KEEPALIVE_COUNT_MAX: u16 = 3;
+
+/// Version of the command line interface between `deploy` and `activate-rs`, to be bumped
+/// whenever one of them changes in a way the other side's older versions can't handle
+pub const ACTIVATE_PROTOCOL_VERSION: u32 = 2;
+
+/// What `activate-rs version-json` prints, for `deploy` to compare against its own version
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ActivateRsVersion {
+    pub version: String,
+    pub protocol: u32,
+}
+
+impl ActivateRsVersion {
+    pub fn current() -> Self {
+        ActivateRsVersion {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: ACTIVATE_PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// How `deploy` runs Nix and SSH on its own machine, see `set_local_commands`
+#[derive(Debug, Default, Clone)]
+pub struct LocalCommands {
+    /// Replaces `nix`, e.g. with a wrapper (`--nix-bin`)
+    pub nix_bin: Option<String>,
+    /// Replaces `ssh` (`--ssh-bin`)
+    pub ssh_bin: Option<String>,
+    /// Passes `-v` to every Nix command (`--verbose-nix`)
+    pub verbose_nix: bool,
+}
+
+static LOCAL_COMMANDS: OnceLock<LocalCommands> = OnceLock::new();
+
+/// Directory put first on the `PATH` of Nix commands, with an `ssh` running the `ssh_bin` of the
+/// local commands, as Nix itself (e.g. `nix copy`) can only be made to use another SSH that way
+static SSH_WRAPPER_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets how `nix_command` and `ssh_command` run their binaries for the rest of the process, before
+/// they are first used. Only the first call has an effect.
+pub fn set_local_commands(local_commands: LocalCommands) {
+    if LOCAL_COMMANDS.get().is_some() {
+        return;
+    }
+
+    if let Some(ref ssh_bin) = local_commands.ssh_bin {
+        match write_ssh_wrapper(ssh_bin) {
+            Ok(dir) => {
+                let _ = SSH_WRAPPER_DIR.set(dir);
+            }
+            Err(e) => log::warn!(
+                "Failed to make Nix use `{}` for SSH, it uses the `ssh` on the PATH: {}",
+                ssh_bin,
+                e
+            ),
+        }
+    }
+
+    let _ = LOCAL_COMMANDS.set(local_commands);
+}
+
+/// Writes a script called `ssh` running `ssh_bin` into a new temporary directory, returning the directory
+fn write_ssh_wrapper(ssh_bin: &str) -> Result<PathBuf, std::io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("deploy-rs-ssh-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+
+    let wrapper = dir.join("ssh");
+    std::fs::write(
+        &wrapper,
+        format!("#!/bin/sh\nexec {} \"$@\"\n", shell_words::quote(ssh_bin)),
+    )?;
+    std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
+
+    Ok(dir)
+}
+
+#[test]
+fn test_write_ssh_wrapper() {
+    let dir = write_ssh_wrapper("/opt/my ssh/bin/ssh").unwrap();
+    let script = std::fs::read_to_string(dir.join("ssh")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(script, "#!/bin/sh\nexec '/opt/my ssh/bin/ssh' \"$@\"\n");
+}
+
+/// Removes what `set_local_commands` wrote for `ssh_bin`, once no more Nix commands run
+pub fn remove_ssh_wrapper() {
+    if let Some(dir) = SSH_WRAPPER_DIR.get() {
+        if let Err(e) = std::fs::remove_dir_all(dir) {
+            log::debug!("Failed to remove {}: {}", dir.display(), e);
+        }
+    }
+}
+
+/// Binary to run for `nix` or one of its legacy tools (e.g. `nix-env`). `nix` can be overridden,
+/// e.g. for a wrapper; the legacy tools are only looked up next to it if the override is a path
+/// to a binary called `nix`.
+fn nix_bin(nix_override: Option<&str>, name: &str) -> String {
+    match nix_override {
+        None => name.to_string(),
+        Some(nix) if name == "nix" => nix.to_string(),
+        Some(nix) => match Path::new(nix).parent() {
+            Some(dir) if Path::new(nix).file_name() == Some("nix".as_ref()) => {
+                dir.join(name).display().to_string()
+            }
+            _ => name.to_string(),
+        },
+    }
+}
+
+#[test]
+fn test_nix_bin() {
+    assert_eq!(nix_bin(None, "nix-env"), "nix-env");
+    assert_eq!(
+        nix_bin(Some("nix-with-extra-config"), "nix"),
+        "nix-with-extra-config"
+    );
+    assert_eq!(nix_bin(Some("nix-with-extra-config"), "nix-env"), "nix-env");
+    assert_eq!(
+        nix_bin(Some("/opt/nix/bin/nix"), "nix-env"),
+        "/opt/nix/bin/nix-env"
+    );
+}
+
+/// Command running `nix` or one of its legacy tools, see `nix_bin`. It logs verbosely if
+/// `verbose_nix` was set with `set_local_commands`.
+pub fn nix_command(name: &str) -> tokio::process::Command {
+    let nix_override = LOCAL_COMMANDS.get().and_then(|c| c.nix_bin.as_deref());
+    let mut command = tokio::process::Command::new(nix_bin(nix_override, name));
+    // Whatever gives up on the command, e.g. `--time-limit`, ends it too
+    command.kill_on_drop(true);
+
+    if matches!(LOCAL_COMMANDS.get(), Some(c) if c.verbose_nix) {
+        command.arg("-v");
+    }
+
+    if let Some(dir) = SSH_WRAPPER_DIR.get() {
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let paths = std::iter::once(dir.clone()).chain(std::env::split_paths(&path));
+        if let Ok(path) = std::env::join_paths(paths) {
+            command.env("PATH", path);
+        }
+    }
+
+    command
+}
+
+/// Command running `ssh`, or the binary set with `set_local_commands`
+pub fn ssh_command() -> tokio::process::Command {
+    let mut command = match LOCAL_COMMANDS.get().and_then(|c| c.ssh_bin.as_deref()) {
+        Some(ssh_bin) => tokio::process::Command::new(ssh_bin),
+        None => tokio::process::Command::new("ssh"),
+    };
+    // Whatever gives up on the command, e.g. `--time-limit`, ends it too
+    command.kill_on_drop(true);
+    command
+}
+
+/// Command running a shell command (given as its last argument) on the node of `deploy_data`: over
+/// SSH to `ssh_addr`, or in a local shell if the node is the machine `deploy` runs on (`localDeploy`)
+pub fn node_command(deploy_data: &DeployData<'_>, ssh_addr: &str) -> tokio::process::Command {
+    if deploy_data.merged_settings.local_deploy.unwrap_or(false) {
+        let mut command = tokio::process::Command::new("sh");
+        command.arg("-c").kill_on_drop(true);
+        return command;
+    }
+
+    let mut command = ssh_command();
+    command
+        .arg(ssh_addr)
+        .args(&deploy_data.merged_settings.ssh_opts);
+    command
+}
+
+/// Control path of the SSH masters opened by `--ssh-multiplex` and `--keep-ssh-on-failure`, `%C` is expanded by ssh
+pub fn ssh_master_control_path() -> PathBuf {
+    std::env::temp_dir().join("deploy-rs-%C")
+}
+
+/// Directories tried in order for the canary file of magic rollback, so nodes with a read-only
+/// `/tmp` fall back to the runtime directory of the user
+pub fn temp_path_candidates(temp_path: &Path) -> Vec<PathBuf> {
+    let mut candidates = vec![temp_path.to_path_buf()];
+
+    if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        candidates.push(PathBuf::from(runtime_dir));
+    }
+
+    // `/proc/self` is owned by the user the process runs as
+    if let Ok(metadata) = std::fs::metadata("/proc/self") {
+        use std::os::unix::fs::MetadataExt;
+        candidates.push(PathBuf::from(format!("/run/user/{}", metadata.uid())));
+    }
+
+    candidates.dedup();
+    candidates
+}
+
+/// Path of the canary file confirming an activation, unique per deployment if `lock_id` is given
+pub fn make_lock_path(temp_path: &Path, closure: &str, lock_id: Option<&str>) -> PathBuf {
+    let lock_hash = &closure["/nix/store/".len()..closure.find('-').unwrap_or(closure.len())];
+    match lock_id {
+        Some(lock_id) => temp_path.join(format!("deploy-rs-canary-{}-{}", lock_hash, lock_id)),
+        None => temp_path.join(format!("deploy-rs-canary-{}", lock_hash)),
+    }
+}
+
+/// Canary file of an activation on this machine when `activate-rs` didn't report it: in the first
+/// of the `temp_path_candidates` it exists in, like `activate-rs` picks the first writable one
+pub fn find_lock_path(temp_path: &Path, closure: &str, lock_id: Option<&str>) -> PathBuf {
+    temp_path_candidates(temp_path)
+        .iter()
+        .map(|candidate| make_lock_path(candidate, closure, lock_id))
+        .find(|lock_path| lock_path.exists())
+        .unwrap_or_else(|| make_lock_path(temp_path, closure, lock_id))
+}
+
+/// Identifies one deployment of a profile in its canary file: the profile name (for humans) and a
+/// random nonce, so that profiles or repeated deployments sharing a closure and temp path never
+/// confirm each other
+fn make_lock_id(profile_name: &str) -> Result<String, getrandom::Error> {
+    let mut nonce = [0u8; 4];
+    getrandom::getrandom(&mut nonce)?;
+
+    let profile_name: String = profile_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    Ok(format!(
+        "{}-{:08x}",
+        profile_name,
+        u32::from_ne_bytes(nonce)
+    ))
+}
+
+#[test]
+fn test_make_lock_path() {
+    let closure = "/nix/store/blah-system";
+
+    assert_eq!(
+        make_lock_path(Path::new("/tmp"), closure, None),
+        Path::new("/tmp/deploy-rs-canary-blah")
+    );
+    assert_eq!(
+        make_lock_path(Path::new("/tmp"), closure, Some("system-0123abcd")),
+        Path::new("/tmp/deploy-rs-canary-blah-system-0123abcd")
+    );
+
+    let lock_id = make_lock_id("my \"profile\"").unwrap();
+    assert!(lock_id.starts_with("my__profile_-"));
+    assert_ne!(lock_id, make_lock_id("my \"profile\"").unwrap());
+}
+
+#[test]
+fn test_find_lock_path() {
+    let closure = "/nix/store/blah-system";
+    let temp_path = std::env::temp_dir().join(format!("deploy-rs-test-{}", std::process::id()));
+
+    assert_eq!(
+        find_lock_path(&temp_path, closure, Some("system-0123abcd")),
+        make_lock_path(&temp_path, closure, Some("system-0123abcd"))
+    );
+
+    std::fs::create_dir_all(&temp_path).unwrap();
+    let lock_path = make_lock_path(&temp_path, closure, Some("system-0123abcd"));
+    std::fs::File::create(&lock_path).unwrap();
+    assert_eq!(
+        find_lock_path(&temp_path, closure, Some("system-0123abcd")),
+        lock_path
+    );
+    std::fs::remove_dir_all(&temp_path).unwrap();
 }
 
 const fn make_emoji(level: log::Level) -> &'static str {
@@ -78,6 +374,38 @@ pub fn logger_formatter_revoke(
     )
 }
 
+pub fn logger_formatter_is_current(
+    w: &mut dyn std::io::Write,
+    _now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    let level = record.level();
+
+    write!(
+        w,
+        "🔍 {} [is-current] [{}] {}",
+        make_emoji(level),
+        style(level, level.to_string()),
+        record.args()
+    )
+}
+
+pub fn logger_formatter_diff_closures(
+    w: &mut dyn std::io::Write,
+    _now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    let level = record.level();
+
+    write!(
+        w,
+        "🔀 {} [diff-closures] [{}] {}",
+        make_emoji(level),
+        style(level, level.to_string()),
+        record.args()
+    )
+}
+
 pub fn logger_formatter_deploy(
     w: &mut dyn std::io::Write,
     _now: &mut DeferredNow,
@@ -94,23 +422,141 @@ pub fn logger_formatter_deploy(
     )
 }
 
+/// Writes one JSON object per line, for log aggregators
+fn write_json_log(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+    stage: &str,
+) -> Result<(), std::io::Error> {
+    let (node, profile) = LOG_CONTEXT
+        .try_with(|c| (c.node.clone(), c.profile.clone()))
+        .unwrap_or_default();
+
+    write!(
+        w,
+        "{}",
+        serde_json::json!({
+            "level": record.level().to_string().to_lowercase(),
+            "stage": stage,
+            "node": node,
+            "profile": profile,
+            "msg": record.args().to_string(),
+            "ts": now.now().to_rfc3339(),
+        })
+    )
+}
+
+pub fn logger_formatter_json_activate(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write_json_log(w, now, record, "activate")
+}
+
+pub fn logger_formatter_json_wait(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write_json_log(w, now, record, "wait")
+}
+
+pub fn logger_formatter_json_revoke(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write_json_log(w, now, record, "revoke")
+}
+
+pub fn logger_formatter_json_is_current(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write_json_log(w, now, record, "is-current")
+}
+
+pub fn logger_formatter_json_diff_closures(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write_json_log(w, now, record, "diff-closures")
+}
+
+pub fn logger_formatter_json_deploy(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write_json_log(w, now, record, "deploy")
+}
+
 pub enum LoggerType {
     Deploy,
     Activate,
     Wait,
     Revoke,
+    IsCurrent,
+    DiffClosures,
+}
+
+#[derive(clap::ArgEnum, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Emoji,
+    Json,
+}
+
+/// Node and profile the messages logged in its scope are about, for JSON logs
+struct LogContext {
+    node: Option<String>,
+    profile: Option<String>,
+}
+
+tokio::task_local! {
+    static LOG_CONTEXT: LogContext;
+}
+
+/// Runs `f` with the given node and profile attached to the JSON lines it logs
+pub async fn with_log_context<F: std::future::Future>(
+    node: Option<&str>,
+    profile: Option<&str>,
+    f: F,
+) -> F::Output {
+    LOG_CONTEXT
+        .scope(
+            LogContext {
+                node: node.map(str::to_string),
+                profile: profile.map(str::to_string),
+            },
+            f,
+        )
+        .await
 }
 
 pub fn init_logger(
     debug_logs: bool,
     log_dir: Option<&str>,
     logger_type: &LoggerType,
+    log_format: LogFormat,
 ) -> Result<(), FlexiLoggerError> {
-    let logger_formatter = match &logger_type {
-        LoggerType::Deploy => logger_formatter_deploy,
-        LoggerType::Activate => logger_formatter_activate,
-        LoggerType::Wait => logger_formatter_wait,
-        LoggerType::Revoke => logger_formatter_revoke,
+    let logger_formatter: FormatFunction = match (log_format, &logger_type) {
+        (LogFormat::Emoji, LoggerType::Deploy) => logger_formatter_deploy,
+        (LogFormat::Emoji, LoggerType::Activate) => logger_formatter_activate,
+        (LogFormat::Emoji, LoggerType::Wait) => logger_formatter_wait,
+        (LogFormat::Emoji, LoggerType::Revoke) => logger_formatter_revoke,
+        (LogFormat::Emoji, LoggerType::IsCurrent) => logger_formatter_is_current,
+        (LogFormat::Emoji, LoggerType::DiffClosures) => logger_formatter_diff_closures,
+        (LogFormat::Json, LoggerType::Deploy) => logger_formatter_json_deploy,
+        (LogFormat::Json, LoggerType::Activate) => logger_formatter_json_activate,
+        (LogFormat::Json, LoggerType::Wait) => logger_formatter_json_wait,
+        (LogFormat::Json, LoggerType::Revoke) => logger_formatter_json_revoke,
+        (LogFormat::Json, LoggerType::IsCurrent) => logger_formatter_json_is_current,
+        (LogFormat::Json, LoggerType::DiffClosures) => logger_formatter_json_diff_closures,
     };
 
     if let Some(log_dir) = log_dir {
@@ -125,10 +571,17 @@ pub fn init_logger(
             })
             .print_message();
 
+        // The files get flexi_logger's own format unless they are meant for log aggregators
+        if log_format == LogFormat::Json {
+            logger = logger.format_for_files(logger_formatter);
+        }
+
         match logger_type {
             LoggerType::Activate => logger = logger.discriminant("activate"),
             LoggerType::Wait => logger = logger.discriminant("wait"),
             LoggerType::Revoke => logger = logger.discriminant("revoke"),
+            LoggerType::IsCurrent => logger = logger.discriminant("is-current"),
+            LoggerType::DiffClosures => logger = logger.discriminant("diff-closures"),
             LoggerType::Deploy => (),
         }
 
@@ -152,11 +605,16 @@ pub mod data;
 pub mod deploy;
 pub mod push;
 
-#[derive(Debug)]
+#[derive(Serialize, Debug, Clone, Default)]
 pub struct CmdOverrides {
     pub ssh_user: Option<String>,
     pub profile_user: Option<String>,
     pub ssh_opts: Option<String>,
+    pub ssh_connect_timeout: Option<u16>,
+    pub ssh_keepalive: Option<u16>,
+    pub ssh_control_path: Option<PathBuf>,
+    pub ssh_multiplex: bool,
+    pub ssh_control_persist: Option<u16>,
     pub fast_connection: Option<bool>,
     pub auto_rollback: Option<bool>,
     pub hostname: Option<String>,
@@ -164,13 +622,20 @@ pub struct CmdOverrides {
     pub temp_path: Option<PathBuf>,
     pub confirm_timeout: Option<u16>,
     pub activation_timeout: Option<u16>,
+    pub build_timeout: Option<u16>,
+    pub activation_retries: Option<u16>,
+    pub copy_retries: Option<u16>,
+    pub copy_retry_delay: Option<u16>,
+    pub confirm_via: Option<String>,
     pub sudo: Option<String>,
     pub interactive_sudo: Option<bool>,
     pub dry_activate: bool,
+    pub diff: bool,
     pub remote_build: bool,
+    pub log_format: LogFormat,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Serialize, PartialEq, Debug)]
 pub struct DeployFlake<'a> {
     pub repo: &'a str,
     pub node: Option<String>,
@@ -184,7 +649,45 @@ pub enum ParseFlakeError {
     #[error("Unrecognized node or token encountered")]
     Unrecognized,
 }
-pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
+
+/// Whether a node name from a flake fragment is a `*`/`?` glob rather than a literal name
+pub fn is_node_glob(node: &str) -> bool {
+    node.contains(['*', '?'])
+}
+
+/// Matches a node name against a glob where `*` is any run of characters and `?` any single one
+pub fn node_matches_glob(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    backtrack = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+pub fn parse_flake(flake: &str) -> Result<DeployFlake<'_>, ParseFlakeError> {
     let flake_fragment_start = flake.find('#');
     let (repo, maybe_fragment) = match flake_fragment_start {
         Some(s) => (&flake[..s], Some(&flake[s + 1..])),
@@ -195,6 +698,23 @@ pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
     let mut profile: Option<String> = None;
 
     if let Some(fragment) = maybe_fragment {
+        // Globs like `web-*` aren't valid Nix, so unquoted ones are split by hand
+        if is_node_glob(fragment) && !fragment.contains('"') {
+            let mut parts = fragment.split('.');
+            let node = parts.next().map(str::to_string);
+            let profile = parts.next().map(str::to_string);
+
+            if parts.next().is_some() {
+                return Err(ParseFlakeError::PathTooLong);
+            }
+
+            return Ok(DeployFlake {
+                repo,
+                node,
+                profile,
+            });
+        }
+
         let ast = rnix::parse(fragment);
 
         let first_child = match ast.root().node().first_child() {
@@ -222,14 +742,14 @@ pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
                 (NODE_IDENT, _) => Some(entry.into_node().unwrap().text().to_string()),
                 (TOKEN_IDENT, _) => Some(entry.into_token().unwrap().text().to_string()),
                 (NODE_STRING, _) => {
-                    let c = entry
-                        .into_node()
-                        .unwrap()
-                        .children_with_tokens()
-                        .nth(1)
-                        .unwrap();
-
-                    Some(c.into_token().unwrap().text().to_string())
+                    let s = Str::cast(entry.into_node().unwrap()).unwrap();
+
+                    // Only plain string literals make sense here, not interpolations
+                    match &s.parts()[..] {
+                        [] => Some(String::new()),
+                        [StrPart::Literal(l)] => Some(l.clone()),
+                        _ => return Err(ParseFlakeError::Unrecognized),
+                    }
                 }
                 _ => return Err(ParseFlakeError::Unrecognized),
             };
@@ -249,6 +769,18 @@ pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
     })
 }
 
+#[test]
+fn test_node_matches_glob() {
+    assert!(node_matches_glob("web-*", "web-1"));
+    assert!(node_matches_glob("web-*", "web-"));
+    assert!(node_matches_glob("*-db", "main-db"));
+    assert!(node_matches_glob("web-?", "web-2"));
+    assert!(node_matches_glob("w*b*", "web-backend"));
+    assert!(!node_matches_glob("web-?", "web-10"));
+    assert!(!node_matches_glob("web-*", "db-1"));
+    assert!(!node_matches_glob("*-db", "main-db-replica"));
+}
+
 #[test]
 fn test_parse_flake() {
     assert_eq!(
@@ -278,6 +810,26 @@ fn test_parse_flake() {
         }
     );
 
+    // Tarball flake refs are passed on to Nix as they are
+    assert_eq!(
+        parse_flake("https://example.com/source.tar.gz?narHash=sha256-abc#computer.system")
+            .unwrap(),
+        DeployFlake {
+            repo: "https://example.com/source.tar.gz?narHash=sha256-abc",
+            node: Some("computer".to_string()),
+            profile: Some("system".to_string()),
+        }
+    );
+
+    assert_eq!(
+        parse_flake("../deploy/examples/system#web-*.system").unwrap(),
+        DeployFlake {
+            repo: "../deploy/examples/system",
+            node: Some("web-*".to_string()),
+            profile: Some("system".to_string()),
+        }
+    );
+
     assert_eq!(
         parse_flake("../deploy/examples/system#\"example.com\".system").unwrap(),
         DeployFlake {
@@ -287,6 +839,15 @@ fn test_parse_flake() {
         }
     );
 
+    assert_eq!(
+        parse_flake("../deploy/examples/system#\"my \\\"node\\\"\".\"some.profile\"").unwrap(),
+        DeployFlake {
+            repo: "../deploy/examples/system",
+            node: Some("my \"node\"".to_string()),
+            profile: Some("some.profile".to_string()),
+        }
+    );
+
     assert_eq!(
         parse_flake("../deploy/examples/system#example").unwrap(),
         DeployFlake {
@@ -315,7 +876,7 @@ fn test_parse_flake() {
     );
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct DeployData<'a> {
     pub node_name: &'a str,
     pub node: &'a data::Node,
@@ -326,17 +887,53 @@ pub struct DeployData<'a> {
 
     pub merged_settings: data::GenericSettings,
 
+    /// Hostname printed by the `hostnameCommand` of the node, if it has one
+    pub resolved_hostname: Option<String>,
+
     pub debug_logs: bool,
     pub log_dir: Option<&'a str>,
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Debug)]
 pub struct DeployDefs {
     pub ssh_user: String,
     pub profile_user: String,
     pub sudo: Option<String>,
-    pub sudo_password: Option<String>,
+    #[serde(skip)]
+    pub sudo_password: Option<SudoPassword>,
+    #[serde(skip)]
+    pub lock_id: String,
+    /// Canary file removed to confirm the activation, once `activate-rs wait` told where it is
+    #[serde(skip)]
+    pub lock_path: OnceLock<PathBuf>,
+}
+
+/// Password piped to `sudo -S` on the node, wiped from memory once dropped and never printed,
+/// not even in debug logs
+#[derive(Clone)]
+pub struct SudoPassword(zeroize::Zeroizing<String>);
+
+impl SudoPassword {
+    pub fn new(password: zeroize::Zeroizing<String>) -> Self {
+        SudoPassword(password)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for SudoPassword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SudoPassword(<redacted>)")
+    }
 }
+
+/// Where the profile is installed on the node.
+///
+/// Unless `profilePath` is set explicitly, only the user and profile name are known here: the
+/// actual path depends on the Nix version and environment of the node, so `activate-rs`
+/// resolves it remotely.
 enum ProfileInfo {
     ProfilePath {
         profile_path: String,
@@ -351,9 +948,44 @@ enum ProfileInfo {
 pub enum DeployDataDefsError {
     #[error("Neither `user` nor `sshUser` are set for profile {0} of node {1}")]
     NoProfileUser(String, String),
+    #[error("Failed to read SSH options file {0}: {1}")]
+    SshOptsFile(PathBuf, std::io::Error),
+    #[error("Failed to parse --ssh-opts: {0}")]
+    SshOpts(#[from] shell_words::ParseError),
+    #[error(
+        "The confirmation timeout of profile {1} of node {0} is {2} seconds, it has to be between {} and {} seconds with magic rollback (e.g. the default of {})",
+        MIN_CONFIRM_TIMEOUT,
+        MAX_CONFIRM_TIMEOUT,
+        DEFAULT_CONFIRM_TIMEOUT
+    )]
+    ConfirmTimeout(String, String, u16),
+    #[error("Failed to get a random nonce for the canary file: {0}")]
+    LockId(getrandom::Error),
 }
 
 impl<'a> DeployData<'a> {
+    /// Hostname to connect to: the one given on the command line, the one printed by the
+    /// `hostnameCommand` of the node, or the one the node is configured with
+    pub fn hostname(&self) -> &str {
+        match (&self.cmd_overrides.hostname, &self.resolved_hostname) {
+            (Some(hostname), _) | (None, Some(hostname)) => hostname,
+            (None, None) => &self.node.node_settings.hostname,
+        }
+    }
+
+    /// Seconds the activated profile waits for confirmation before rolling back: `confirmTimeout`,
+    /// or a shorter default with `fastConnection`
+    pub fn confirm_timeout(&self) -> u16 {
+        match (
+            self.merged_settings.confirm_timeout,
+            self.merged_settings.fast_connection,
+        ) {
+            (Some(confirm_timeout), _) => confirm_timeout,
+            (None, Some(true)) => FAST_CONNECTION_CONFIRM_TIMEOUT,
+            (None, _) => DEFAULT_CONFIRM_TIMEOUT,
+        }
+    }
+
     pub fn defs(&'a self) -> Result<DeployDefs, DeployDataDefsError> {
         let ssh_user = match self.merged_settings.ssh_user {
             Some(ref u) => u.clone(),
@@ -372,6 +1004,8 @@ impl<'a> DeployData<'a> {
             profile_user,
             sudo,
             sudo_password: None,
+            lock_id: make_lock_id(self.profile_name).map_err(DeployDataDefsError::LockId)?,
+            lock_path: OnceLock::new(),
         })
     }
 
@@ -394,23 +1028,454 @@ impl<'a> DeployData<'a> {
     fn get_sudo(&'a self) -> String {
         match self.merged_settings.sudo {
             Some(ref x) => x.clone(),
-            None => "sudo -u".to_string(),
+            None => match self.merged_settings.sudo_path {
+                Some(ref sudo_path) => format!("{} -u", sudo_path),
+                None => "sudo -u".to_string(),
+            },
         }
     }
 
     fn get_profile_info(&'a self) -> Result<ProfileInfo, DeployDataDefsError> {
         match self.profile.profile_settings.profile_path {
-            Some(ref profile_path) => Ok(ProfileInfo::ProfilePath { profile_path: profile_path.to_string() }),
+            Some(ref profile_path) => Ok(ProfileInfo::ProfilePath {
+                profile_path: profile_path.to_string(),
+            }),
             None => {
                 let profile_user = self.get_profile_user()?;
-                Ok(ProfileInfo::ProfileUserAndName { profile_user, profile_name: self.profile_name.to_string() })
-            },
+                Ok(ProfileInfo::ProfileUserAndName {
+                    profile_user,
+                    profile_name: self.profile_name.to_string(),
+                })
+            }
         }
     }
 }
 
-pub fn make_deploy_data<'a, 's>(
-    top_settings: &'s data::GenericSettings,
+#[test]
+fn test_get_profile_info() {
+    let top_settings: data::GenericSettings = serde_json::from_str("{}").unwrap();
+    let node: data::Node = serde_json::from_str(
+        r#"{
+          "hostname": "example.com",
+          "sshUser": "admin",
+          "user": "alice",
+          "profiles": {
+            "home": { "path": "/nix/store/blah-home" },
+            "system": { "path": "/nix/store/blah-system", "profilePath": "/nix/var/nix/profiles/system" }
+          }
+        }"#,
+    )
+    .unwrap();
+    let cmd_overrides = CmdOverrides::default();
+
+    let home = make_deploy_data(
+        &top_settings,
+        &node,
+        "example",
+        &node.node_settings.profiles["home"],
+        "home",
+        &cmd_overrides,
+        false,
+        None,
+    )
+    .unwrap();
+
+    assert!(matches!(
+        home.get_profile_info().unwrap(),
+        ProfileInfo::ProfileUserAndName { profile_user, profile_name }
+            if profile_user == "alice" && profile_name == "home"
+    ));
+
+    let system = make_deploy_data(
+        &top_settings,
+        &node,
+        "example",
+        &node.node_settings.profiles["system"],
+        "system",
+        &cmd_overrides,
+        false,
+        None,
+    )
+    .unwrap();
+
+    assert!(matches!(
+        system.get_profile_info().unwrap(),
+        ProfileInfo::ProfilePath { profile_path } if profile_path == "/nix/var/nix/profiles/system"
+    ));
+}
+
+#[test]
+fn test_settings_precedence() {
+    let top_settings: data::GenericSettings =
+        serde_json::from_str(r#"{ "confirmTimeout": 10 }"#).unwrap();
+    let node: data::Node = serde_json::from_str(
+        r#"{
+          "hostname": "example.com",
+          "confirmTimeout": 20,
+          "profiles": {
+            "system": { "path": "/nix/store/blah-system", "confirmTimeout": 60 },
+            "home": { "path": "/nix/store/blah-home" }
+          }
+        }"#,
+    )
+    .unwrap();
+    let other_node: data::Node = serde_json::from_str(
+        r#"{
+          "hostname": "other.example.com",
+          "profiles": {
+            "system": { "path": "/nix/store/blah-system" }
+          }
+        }"#,
+    )
+    .unwrap();
+
+    let confirm_timeout = |node: &data::Node, profile_name: &str, cmd_overrides: &CmdOverrides| {
+        make_deploy_data(
+            &top_settings,
+            node,
+            "example",
+            &node.node_settings.profiles[profile_name],
+            profile_name,
+            cmd_overrides,
+            false,
+            None,
+        )
+        .unwrap()
+        .merged_settings
+        .confirm_timeout
+    };
+
+    let no_overrides = CmdOverrides::default();
+
+    assert_eq!(confirm_timeout(&node, "system", &no_overrides), Some(60));
+    assert_eq!(confirm_timeout(&node, "home", &no_overrides), Some(20));
+    assert_eq!(
+        confirm_timeout(&other_node, "system", &no_overrides),
+        Some(10)
+    );
+
+    let cli_overrides = CmdOverrides {
+        confirm_timeout: Some(5),
+        ..Default::default()
+    };
+
+    assert_eq!(confirm_timeout(&node, "system", &cli_overrides), Some(5));
+
+    let deploy_data = |cmd_overrides: &CmdOverrides| {
+        make_deploy_data(
+            &top_settings,
+            &node,
+            "example",
+            &node.node_settings.profiles["system"],
+            "system",
+            cmd_overrides,
+            false,
+            None,
+        )
+        .map(|_| ())
+    };
+
+    for confirm_timeout in [0, MAX_CONFIRM_TIMEOUT + 1] {
+        assert!(matches!(
+            deploy_data(&CmdOverrides {
+                confirm_timeout: Some(confirm_timeout),
+                ..Default::default()
+            }),
+            Err(DeployDataDefsError::ConfirmTimeout(..))
+        ));
+    }
+
+    // Without magic rollback nothing waits for the confirmation
+    assert!(deploy_data(&CmdOverrides {
+        confirm_timeout: Some(0),
+        magic_rollback: Some(false),
+        ..Default::default()
+    })
+    .is_ok());
+
+    // A fast connection only shortens the default
+    let no_settings: data::GenericSettings = serde_json::from_str("{}").unwrap();
+    let fast_confirm_timeout = |node: &data::Node, profile_name: &str| {
+        make_deploy_data(
+            &no_settings,
+            node,
+            "example",
+            &node.node_settings.profiles[profile_name],
+            profile_name,
+            &CmdOverrides {
+                fast_connection: Some(true),
+                ..Default::default()
+            },
+            false,
+            None,
+        )
+        .unwrap()
+        .confirm_timeout()
+    };
+
+    assert_eq!(fast_confirm_timeout(&node, "system"), 60);
+    assert_eq!(
+        fast_confirm_timeout(&other_node, "system"),
+        FAST_CONNECTION_CONFIRM_TIMEOUT
+    );
+}
+
+#[test]
+fn test_sudo() {
+    let top_settings: data::GenericSettings = serde_json::from_str("{}").unwrap();
+    let node: data::Node = serde_json::from_str(
+        r#"{
+          "hostname": "example.com",
+          "sshUser": "admin",
+          "user": "root",
+          "profiles": {
+            "system": { "path": "/nix/store/blah-system" },
+            "wrapped": { "path": "/nix/store/blah-system", "sudoPath": "/usr/bin/sudo" },
+            "doas": { "path": "/nix/store/blah-system", "sudo": "doas -u", "sudoPath": "/usr/bin/sudo" }
+          }
+        }"#,
+    )
+    .unwrap();
+    let cmd_overrides = CmdOverrides::default();
+
+    let sudo = |profile_name: &str| {
+        make_deploy_data(
+            &top_settings,
+            &node,
+            "example",
+            &node.node_settings.profiles[profile_name],
+            profile_name,
+            &cmd_overrides,
+            false,
+            None,
+        )
+        .unwrap()
+        .defs()
+        .unwrap()
+        .sudo
+    };
+
+    assert_eq!(sudo("system").as_deref(), Some("sudo -u root"));
+    assert_eq!(sudo("wrapped").as_deref(), Some("/usr/bin/sudo -u root"));
+    assert_eq!(sudo("doas").as_deref(), Some("doas -u root"));
+}
+
+#[test]
+fn test_ssh_keepalive() {
+    let top_settings: data::GenericSettings = serde_json::from_str("{}").unwrap();
+    let node: data::Node = serde_json::from_str(
+        r#"{
+          "hostname": "example.com",
+          "sshOpts": ["-o", "ServerAliveCountMax=10"],
+          "profiles": {
+            "system": { "path": "/nix/store/blah-system" }
+          }
+        }"#,
+    )
+    .unwrap();
+
+    let make_ssh_opts = |cmd_overrides: &CmdOverrides| {
+        make_deploy_data(
+            &top_settings,
+            &node,
+            "example",
+            &node.node_settings.profiles["system"],
+            "system",
+            cmd_overrides,
+            false,
+            None,
+        )
+        .unwrap()
+        .merged_settings
+        .ssh_opts
+    };
+
+    assert_eq!(
+        make_ssh_opts(&CmdOverrides::default()),
+        vec![
+            "-o",
+            "ServerAliveCountMax=10",
+            "-o",
+            "ServerAliveInterval=30",
+            "-o",
+            "ServerAliveCountMax=3",
+        ]
+    );
+
+    assert_eq!(
+        make_ssh_opts(&CmdOverrides {
+            ssh_keepalive: Some(0),
+            ..Default::default()
+        })[..2],
+        ["-o", "ServerAliveInterval=0"]
+    );
+}
+
+#[test]
+fn test_ssh_control_persist() {
+    let top_settings: data::GenericSettings = serde_json::from_str("{}").unwrap();
+    let node: data::Node = serde_json::from_str(
+        r#"{
+          "hostname": "example.com",
+          "profiles": {
+            "system": { "path": "/nix/store/blah-system" }
+          }
+        }"#,
+    )
+    .unwrap();
+
+    let make_ssh_opts = |cmd_overrides: &CmdOverrides| {
+        make_deploy_data(
+            &top_settings,
+            &node,
+            "example",
+            &node.node_settings.profiles["system"],
+            "system",
+            cmd_overrides,
+            false,
+            None,
+        )
+        .unwrap()
+        .merged_settings
+        .ssh_opts
+    };
+
+    assert!(make_ssh_opts(&CmdOverrides {
+        ssh_multiplex: true,
+        ..Default::default()
+    })
+    .windows(2)
+    .any(|o| o == ["-o", "ControlPersist=yes"]));
+    assert!(make_ssh_opts(&CmdOverrides {
+        ssh_multiplex: true,
+        ssh_control_persist: Some(60),
+        ..Default::default()
+    })
+    .windows(2)
+    .any(|o| o == ["-o", "ControlPersist=60"]));
+}
+
+#[test]
+fn test_ssh_opts_override() {
+    let top_settings: data::GenericSettings = serde_json::from_str("{}").unwrap();
+    let node: data::Node = serde_json::from_str(
+        r#"{
+          "hostname": "example.com",
+          "sshOpts": ["-o", "ProxyCommand=ssh bastion nc %h %p"],
+          "profiles": {
+            "system": { "path": "/nix/store/blah-system" }
+          }
+        }"#,
+    )
+    .unwrap();
+
+    let make_ssh_opts = |ssh_opts: &str| {
+        make_deploy_data(
+            &top_settings,
+            &node,
+            "example",
+            &node.node_settings.profiles["system"],
+            "system",
+            &CmdOverrides {
+                ssh_opts: Some(ssh_opts.to_string()),
+                ..Default::default()
+            },
+            false,
+            None,
+        )
+        .map(|d| d.merged_settings.ssh_opts)
+    };
+
+    assert_eq!(
+        make_ssh_opts(r#"-p 2222 -o "ProxyCommand=ssh other-bastion nc %h %p""#).unwrap()[..4],
+        [
+            "-p",
+            "2222",
+            "-o",
+            "ProxyCommand=ssh other-bastion nc %h %p"
+        ]
+    );
+    assert_eq!(
+        make_ssh_opts("-o 'ProxyCommand=ssh bastion nc %h %p'").unwrap()[..2],
+        ["-o", "ProxyCommand=ssh bastion nc %h %p"]
+    );
+    assert!(matches!(
+        make_ssh_opts(r#"-o "ProxyCommand=ssh bastion"#),
+        Err(DeployDataDefsError::SshOpts(_))
+    ));
+}
+
+#[test]
+fn test_jump_host() {
+    let top_settings: data::GenericSettings =
+        serde_json::from_str(r#"{ "jumpHost": "admin@bastion.example.com:2222" }"#).unwrap();
+    let node: data::Node = serde_json::from_str(
+        r#"{
+          "hostname": "10.0.0.2",
+          "sshOpts": ["-p", "2200"],
+          "profiles": {
+            "system": { "path": "/nix/store/blah-system" }
+          }
+        }"#,
+    )
+    .unwrap();
+    let cmd_overrides = CmdOverrides::default();
+
+    let deploy_data = make_deploy_data(
+        &top_settings,
+        &node,
+        "example",
+        &node.node_settings.profiles["system"],
+        "system",
+        &cmd_overrides,
+        false,
+        None,
+    )
+    .unwrap();
+
+    assert_eq!(
+        deploy_data.merged_settings.ssh_opts[..4],
+        ["-p", "2200", "-J", "admin@bastion.example.com:2222"]
+    );
+}
+
+/// Parses the contents of an `sshOptsFile`.
+///
+/// Every non-empty line that isn't a `#` comment holds a single option, which is split
+/// into the flag and its value at the first whitespace (e.g. `-o ProxyJump=bastion`).
+pub fn parse_ssh_opts_file(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .flat_map(|line| match line.split_once(char::is_whitespace) {
+            Some((flag, value)) => vec![flag.to_owned(), value.trim_start().to_owned()],
+            None => vec![line.to_owned()],
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_ssh_opts_file() {
+    assert_eq!(
+        parse_ssh_opts_file(
+            "# shared options\n\n-p 2121\n  -o ProxyCommand=ssh -W %h:%p bastion  \n-A\n"
+        ),
+        vec![
+            "-p".to_string(),
+            "2121".to_string(),
+            "-o".to_string(),
+            "ProxyCommand=ssh -W %h:%p bastion".to_string(),
+            "-A".to_string(),
+        ]
+    );
+
+    assert!(parse_ssh_opts_file("").is_empty());
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn make_deploy_data<'a>(
+    top_settings: &data::GenericSettings,
     node: &'a data::Node,
     node_name: &'a str,
     profile: &'a data::Profile,
@@ -418,7 +1483,9 @@ pub fn make_deploy_data<'a, 's>(
     cmd_overrides: &'a CmdOverrides,
     debug_logs: bool,
     log_dir: Option<&'a str>,
-) -> DeployData<'a> {
+) -> Result<DeployData<'a>, DeployDataDefsError> {
+    // `merge` only fills in unset values, so profile settings take precedence over node settings,
+    // which take precedence over top-level settings. Explicit command line flags override all of them.
     let mut merged_settings = profile.generic_settings.clone();
     merged_settings.merge(node.generic_settings.clone());
     merged_settings.merge(top_settings.clone());
@@ -434,7 +1501,8 @@ pub fn make_deploy_data<'a, 's>(
         merged_settings.user = cmd_overrides.profile_user.clone();
     }
     if let Some(ref ssh_opts) = cmd_overrides.ssh_opts {
-        merged_settings.ssh_opts = ssh_opts.split(' ').map(|x| x.to_owned()).collect();
+        // Quoted arguments stay together, like the elements of the `sshOpts` list
+        merged_settings.ssh_opts = shell_words::split(ssh_opts)?;
     }
     if let Some(fast_connection) = cmd_overrides.fast_connection {
         merged_settings.fast_connection = Some(fast_connection);
@@ -448,21 +1516,131 @@ pub fn make_deploy_data<'a, 's>(
     if let Some(confirm_timeout) = cmd_overrides.confirm_timeout {
         merged_settings.confirm_timeout = Some(confirm_timeout);
     }
+    // Building locally already builds in the store of a node deployed locally
+    if merged_settings.local_deploy == Some(true) {
+        merged_settings.remote_build = Some(false);
+    }
     if let Some(activation_timeout) = cmd_overrides.activation_timeout {
         merged_settings.activation_timeout = Some(activation_timeout);
     }
+    if let Some(build_timeout) = cmd_overrides.build_timeout {
+        merged_settings.build_timeout = Some(build_timeout);
+    }
     if let Some(interactive_sudo) = cmd_overrides.interactive_sudo {
         merged_settings.interactive_sudo = Some(interactive_sudo);
     }
+    if cmd_overrides.temp_path.is_some() {
+        merged_settings.temp_path = cmd_overrides.temp_path.clone();
+    }
 
-    DeployData {
+    if let (true, Some(confirm_timeout)) = (
+        merged_settings.magic_rollback.unwrap_or(true),
+        merged_settings.confirm_timeout,
+    ) {
+        if !(MIN_CONFIRM_TIMEOUT..=MAX_CONFIRM_TIMEOUT).contains(&confirm_timeout) {
+            return Err(DeployDataDefsError::ConfirmTimeout(
+                node_name.to_string(),
+                profile_name.to_string(),
+                confirm_timeout,
+            ));
+        }
+    }
+
+    if let Some(ref ssh_opts_file) = merged_settings.ssh_opts_file {
+        let contents = std::fs::read_to_string(ssh_opts_file)
+            .map_err(|e| DeployDataDefsError::SshOptsFile(ssh_opts_file.clone(), e))?;
+
+        let mut ssh_opts = parse_ssh_opts_file(&contents);
+        ssh_opts.append(&mut merged_settings.ssh_opts);
+        merged_settings.ssh_opts = ssh_opts;
+    }
+
+    if !merged_settings.identity_files.is_empty() {
+        // Only try the given keys (in order), not whatever the agent offers
+        merged_settings.ssh_opts.push("-o".to_string());
+        merged_settings
+            .ssh_opts
+            .push("IdentitiesOnly=yes".to_string());
+
+        for identity_file in &merged_settings.identity_files {
+            merged_settings.ssh_opts.push("-i".to_string());
+            merged_settings
+                .ssh_opts
+                .push(identity_file.display().to_string());
+        }
+    }
+
+    // Like all SSH options this also ends up in `NIX_SSHOPTS` for `nix copy` and in the SSH masters
+    if let Some(ref jump_host) = merged_settings.jump_host {
+        merged_settings.ssh_opts.push("-J".to_string());
+        merged_settings.ssh_opts.push(jump_host.clone());
+    }
+
+    // Reuse a control master managed by the user, without ever becoming one ourselves
+    if let Some(ref ssh_control_path) = cmd_overrides.ssh_control_path {
+        let mut ssh_opts = vec![
+            "-o".to_string(),
+            format!("ControlPath={}", ssh_control_path.display()),
+            "-o".to_string(),
+            "ControlMaster=no".to_string(),
+        ];
+        ssh_opts.append(&mut merged_settings.ssh_opts);
+        merged_settings.ssh_opts = ssh_opts;
+    } else if cmd_overrides.ssh_multiplex {
+        // Every SSH invocation for the node shares one master, which persists between them and is
+        // closed once the deployment finishes (or kept for debugging after a failure)
+        let mut ssh_opts = vec![
+            "-o".to_string(),
+            format!("ControlPath={}", ssh_master_control_path().display()),
+            "-o".to_string(),
+            "ControlMaster=auto".to_string(),
+            "-o".to_string(),
+            // Without a limit the master stays until it is closed explicitly
+            match cmd_overrides.ssh_control_persist {
+                Some(seconds) => format!("ControlPersist={}", seconds),
+                None => "ControlPersist=yes".to_string(),
+            },
+        ];
+        ssh_opts.append(&mut merged_settings.ssh_opts);
+        merged_settings.ssh_opts = ssh_opts;
+    }
+
+    // ssh uses the first value given for an option, so this takes precedence over `sshOpts`
+    if let Some(ssh_connect_timeout) = cmd_overrides.ssh_connect_timeout {
+        let mut ssh_opts = vec![
+            "-o".to_string(),
+            format!("ConnectTimeout={}", ssh_connect_timeout),
+        ];
+        ssh_opts.append(&mut merged_settings.ssh_opts);
+        merged_settings.ssh_opts = ssh_opts;
+    }
+
+    if let Some(ssh_keepalive) = cmd_overrides.ssh_keepalive {
+        let mut ssh_opts = vec![
+            "-o".to_string(),
+            format!("ServerAliveInterval={}", ssh_keepalive),
+        ];
+        ssh_opts.append(&mut merged_settings.ssh_opts);
+        merged_settings.ssh_opts = ssh_opts;
+    }
+
+    // Defaults go last, so that they don't take precedence over anything set by the user
+    merged_settings.ssh_opts.extend([
+        "-o".to_string(),
+        format!("ServerAliveInterval={}", DEFAULT_SSH_KEEPALIVE_INTERVAL),
+        "-o".to_string(),
+        format!("ServerAliveCountMax={}", DEFAULT_SSH_KEEPALIVE_COUNT_MAX),
+    ]);
+
+    Ok(DeployData {
         node_name,
         node,
         profile_name,
         profile,
         cmd_overrides,
         merged_settings,
+        resolved_hostname: None,
         debug_logs,
         log_dir,
-    }
+    })
 }
@@ -2,7 +2,7 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::collections::HashMap;
 use std::path::Path;
 use std::process::Stdio;
@@ -25,11 +25,14 @@ pub enum PushProfileError {
     Build(std::io::Error),
     #[error("Nix build command resulted in a bad exit code: {0:?}")]
     BuildExit(Option<i32>),
+    #[error("Nix build command didn't finish within {0} seconds and was killed")]
+    BuildTimeout(u16),
     #[error(
-        "Activation script deploy-rs-activate does not exist in profile.\n\
-             Did you forget to use deploy-rs#lib.<...>.activate.<...> on your profile path?"
+        "Activation script deploy-rs-activate does not exist in profile `{1}` of node `{0}`.\n\
+             Did you forget to use deploy-rs#lib.<...>.activate.<...> on your profile path? For example:\n    {}",
+        activate_example(.0, .1, .2)
     )]
-    DeployRsActivateDoesntExist,
+    DeployRsActivateDoesntExist(String, String, String),
     #[error("Activation script activate-rs does not exist in profile.\n\
              Is there a mismatch in deploy-rs used in the flake you're deploying and deploy-rs command you're running?")]
     ActivateRsDoesntExist,
@@ -41,11 +44,58 @@ pub enum PushProfileError {
     Copy(std::io::Error),
     #[error("Nix copy command resulted in a bad exit code: {0:?}")]
     CopyExit(Option<i32>),
+    #[error(
+        "The node refused `{0}` as it lacks a signature by a trusted key.\n\
+             Either add the SSH user to `trusted-users` in the node's Nix configuration, or sign the closure \
+             with a key from the node's `trusted-public-keys` by setting LOCAL_KEY and deploy with --checksigs"
+    )]
+    UnsignedPath(String),
+    #[error("Failed to run Nix copy command to copy the remotely built result back: {0}")]
+    CopyBack(std::io::Error),
+    #[error("Nix copy command to copy the remotely built result back resulted in a bad exit code: {0:?}")]
+    CopyBackExit(Option<i32>),
+    #[error("Failed to run Nix build command to link the kept result: {0}")]
+    ResultLink(std::io::Error),
+    #[error("Nix build command to link the kept result resulted in a bad exit code: {0:?}")]
+    ResultLinkExit(Option<i32>),
     #[error("The remote building option is not supported when using legacy nix")]
     RemoteBuildWithLegacyNix,
 
+    #[error("Smoke tests are not supported when using legacy nix")]
+    SmokeTestWithLegacyNix,
+    #[error("Failed to run Nix build command for the smoke test: {0}")]
+    SmokeTestBuild(std::io::Error),
+    #[error("Nix build command for the smoke test resulted in a bad exit code: {0:?}")]
+    SmokeTestBuildExit(Option<i32>),
+    #[error("Nix build command for the smoke test output an invalid UTF-8 sequence: {0}")]
+    SmokeTestBuildUtf8(std::string::FromUtf8Error),
+
     #[error("Failed to run Nix path-info command: {0}")]
     PathInfo(std::io::Error),
+
+    #[error("Failed to run uname on the node to find its system: {0}")]
+    Uname(std::io::Error),
+    #[error("uname on the node resulted in a bad exit code: {0:?}")]
+    UnameExit(Option<i32>),
+    #[error("Unknown system of the node, uname printed `{0}`")]
+    UnknownSystem(String),
+    #[error(
+        "The profile is for `{0}`, but the node is a `{1}` system.\n\
+             Deploy with --no-system-check if the node can run it anyway (e.g. through emulation)"
+    )]
+    SystemMismatch(String, String),
+
+    #[error("{0}\nOutput of the command re-run with verbose logging:\n{1}")]
+    Verbose(Box<PushProfileError>, String),
+}
+
+/// What `push_profile` had to do to get the profile closure onto the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushProfileOutcome {
+    /// The closure was copied (or built) on the target during this run, or presence wasn't checked
+    Copied,
+    /// The closure was already fully present on the target, nothing was transferred
+    AlreadyPresent,
 }
 
 pub struct PushProfileData<'a> {
@@ -57,18 +107,99 @@ pub struct PushProfileData<'a> {
     pub keep_result: bool,
     pub result_path: Option<&'a str>,
     pub extra_build_args: &'a [String],
+    pub auto_verbose_on_failure: bool,
+    pub keep_going: bool,
+    /// Ask the node whether it already has the closure before copying, so that the outcome
+    /// tells if anything changed
+    pub check_present: bool,
 }
 
-pub async fn build_profile_locally(data: &PushProfileData<'_>, derivation_name: &str) -> Result<(), PushProfileError> {
+/// Runs a Nix build command, killing it if it takes longer than the `buildTimeout` of the profile
+async fn run_build(
+    build_command: &mut Command,
+    build_timeout: Option<u16>,
+) -> Result<std::process::ExitStatus, PushProfileError> {
+    let mut build_child = build_command
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(PushProfileError::Build)?;
+
+    let build_timeout = match build_timeout {
+        Some(x) => x,
+        None => return build_child.wait().await.map_err(PushProfileError::Build),
+    };
+
+    match tokio::time::timeout(
+        std::time::Duration::from_secs(build_timeout.into()),
+        build_child.wait(),
+    )
+    .await
+    {
+        Ok(status) => status.map_err(PushProfileError::Build),
+        Err(_) => {
+            build_child.kill().await.map_err(PushProfileError::Build)?;
+
+            Err(PushProfileError::BuildTimeout(build_timeout))
+        }
+    }
+}
+
+/// Extra arguments to build the profile with: its `extraBuildArgs`, then the ones given to `deploy`
+fn build_args<'b>(data: &'b PushProfileData<'_>) -> Vec<&'b String> {
+    data.deploy_data
+        .merged_settings
+        .extra_build_args
+        .iter()
+        .chain(data.extra_build_args)
+        .collect()
+}
+
+/// Runs a failed Nix command once more with verbose logging, so that its logs can be reported
+/// along with the error. Succeeds if the command happens to succeed this time.
+async fn rerun_verbose(
+    command: &mut Command,
+    err: PushProfileError,
+    print_build_logs: bool,
+) -> Result<(), PushProfileError> {
+    warn!("{}, re-running the command with verbose logging", err);
+
+    if print_build_logs {
+        command.arg("--print-build-logs");
+    }
+
+    let output = match command.arg("-v").output().await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to re-run the command: {}", e);
+            return Err(err);
+        }
+    };
+
+    match output.status.code() {
+        Some(0) => {
+            warn!("The command succeeded when re-run, continuing");
+            Ok(())
+        }
+        _ => Err(PushProfileError::Verbose(
+            Box::new(err),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        )),
+    }
+}
+
+pub async fn build_profile_locally(
+    data: &PushProfileData<'_>,
+    derivation_name: &str,
+) -> Result<(), PushProfileError> {
     info!(
         "Building profile `{}` for node `{}`",
         data.deploy_data.profile_name, data.deploy_data.node_name
     );
 
     let mut build_command = if data.supports_flakes {
-        Command::new("nix")
+        crate::nix_command("nix")
     } else {
-        Command::new("nix-build")
+        crate::nix_command("nix-build")
     };
 
     if data.supports_flakes {
@@ -90,20 +221,104 @@ pub async fn build_profile_locally(data: &PushProfileData<'_>, derivation_name:
         (false, true) => build_command.arg("--no-link"),
     };
 
-    build_command.args(data.extra_build_args);
+    if data.keep_going {
+        build_command.arg("--keep-going");
+    }
 
-    let build_exit_status = build_command
-        // Logging should be in stderr, this just stops the store path from printing for no reason
-        .stdout(Stdio::null())
-        .status()
-        .await
-        .map_err(PushProfileError::Build)?;
+    build_command.args(build_args(data));
+
+    // Logging should be in stderr, this just stops the store path from printing for no reason
+    build_command.stdout(Stdio::null());
+
+    let build_exit_status = run_build(
+        &mut build_command,
+        data.deploy_data.merged_settings.build_timeout,
+    )
+    .await?;
 
     match build_exit_status.code() {
         Some(0) => (),
+        a if data.auto_verbose_on_failure => {
+            // Only the new CLI knows `--print-build-logs`, nix-build prints them anyway
+            rerun_verbose(
+                &mut build_command,
+                PushProfileError::BuildExit(a),
+                data.supports_flakes,
+            )
+            .await?
+        }
         a => return Err(PushProfileError::BuildExit(a)),
     };
 
+    check_built_profile(data).await
+}
+
+/// The Nix system the profile was built for: its `system` if set, otherwise the one `deploy` runs on
+fn profile_system(data: &PushProfileData<'_>) -> String {
+    match data.deploy_data.profile.profile_settings.system {
+        Some(ref system) => system.clone(),
+        None => {
+            let os = match std::env::consts::OS {
+                "macos" => "darwin",
+                os => os,
+            };
+            let arch = match std::env::consts::ARCH {
+                "x86" => "i686",
+                arch => arch,
+            };
+            format!("{}-{}", arch, os)
+        }
+    }
+}
+
+/// A copy-pasteable profile path wrapped with the activation of deploy-rs, for the error telling
+/// that it is missing
+fn activate_example(node: &str, profile: &str, system: &str) -> String {
+    // Attribute names that aren't plain identifiers have to be quoted
+    let attr = |name: &str| {
+        if !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '\'')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_-'".contains(c))
+        {
+            name.to_string()
+        } else {
+            format!("\"{}\"", name)
+        }
+    };
+
+    match profile {
+        "system" => format!(
+            "path = deploy-rs.lib.{}.activate.nixos self.nixosConfigurations.{};",
+            system,
+            attr(node)
+        ),
+        _ => format!(
+            "path = deploy-rs.lib.{}.activate.custom <derivation> \"<activation command>\";",
+            system
+        ),
+    }
+}
+
+#[test]
+fn test_activate_example() {
+    assert_eq!(
+        activate_example("web", "system", "x86_64-linux"),
+        "path = deploy-rs.lib.x86_64-linux.activate.nixos self.nixosConfigurations.web;"
+    );
+    assert_eq!(
+        activate_example("web.example.com", "system", "aarch64-linux"),
+        "path = deploy-rs.lib.aarch64-linux.activate.nixos self.nixosConfigurations.\"web.example.com\";"
+    );
+    assert_eq!(
+        activate_example("web", "hello", "x86_64-linux"),
+        "path = deploy-rs.lib.x86_64-linux.activate.custom <derivation> \"<activation command>\";"
+    );
+}
+
+/// Makes sure the built closure is an activatable profile, and signs it if a key is given
+async fn check_built_profile(data: &PushProfileData<'_>) -> Result<(), PushProfileError> {
     if !Path::new(
         format!(
             "{}/deploy-rs-activate",
@@ -113,7 +328,11 @@ pub async fn build_profile_locally(data: &PushProfileData<'_>, derivation_name:
     )
     .exists()
     {
-        return Err(PushProfileError::DeployRsActivateDoesntExist);
+        return Err(PushProfileError::DeployRsActivateDoesntExist(
+            data.deploy_data.node_name.to_string(),
+            data.deploy_data.profile_name.to_string(),
+            profile_system(data),
+        ));
     }
 
     if !Path::new(
@@ -134,7 +353,7 @@ pub async fn build_profile_locally(data: &PushProfileData<'_>, derivation_name:
             data.deploy_data.profile_name, data.deploy_data.node_name
         );
 
-        let sign_exit_status = Command::new("nix")
+        let sign_exit_status = crate::nix_command("nix")
             .arg("sign-paths")
             .arg("-r")
             .arg("-k")
@@ -152,97 +371,282 @@ pub async fn build_profile_locally(data: &PushProfileData<'_>, derivation_name:
     Ok(())
 }
 
-pub async fn build_profile_remotely(data: &PushProfileData<'_>, derivation_name: &str) -> Result<(), PushProfileError> {
+/// Address of the node's store for `nix copy` and remote builds, with the `nix copy` tuning of the
+/// node (`copyMaxConnections` and `copyCompress`) as store settings in the query
+fn store_address(scheme: &str, data: &PushProfileData<'_>) -> String {
+    build_store_address(
+        scheme,
+        &data.deploy_defs.ssh_user,
+        data.deploy_data.hostname(),
+        &data.deploy_data.merged_settings,
+    )
+}
+
+fn build_store_address(
+    scheme: &str,
+    ssh_user: &str,
+    hostname: &str,
+    settings: &crate::data::GenericSettings,
+) -> String {
+    let mut query = Vec::new();
+
+    if let Some(max_connections) = settings.copy_max_connections {
+        query.push(format!("max-connections={}", max_connections));
+    }
+
+    if let Some(compress) = settings.copy_compress {
+        query.push(format!("compress={}", compress));
+    }
+
+    let address = format!("{}://{}@{}", scheme, ssh_user, hostname);
+    if query.is_empty() {
+        address
+    } else {
+        format!("{}?{}", address, query.join("&"))
+    }
+}
+
+#[test]
+fn test_build_store_address() {
+    let mut settings: crate::data::GenericSettings = serde_json::from_str("{}").unwrap();
+
+    assert_eq!(
+        build_store_address("ssh", "admin", "web", &settings),
+        "ssh://admin@web"
+    );
+
+    settings.copy_max_connections = Some(4);
+    settings.copy_compress = Some(true);
+
+    assert_eq!(
+        build_store_address("ssh", "admin", "web", &settings),
+        "ssh://admin@web?max-connections=4&compress=true"
+    );
+    assert_eq!(
+        build_store_address("ssh-ng", "admin", "web", &settings),
+        "ssh-ng://admin@web?max-connections=4&compress=true"
+    );
+
+    settings.copy_max_connections = None;
+    settings.copy_compress = Some(false);
+
+    assert_eq!(
+        build_store_address("ssh-ng", "admin", "web", &settings),
+        "ssh-ng://admin@web?compress=false"
+    );
+}
+
+pub async fn build_profile_remotely(
+    data: &PushProfileData<'_>,
+    derivation_name: &str,
+) -> Result<(), PushProfileError> {
     info!(
         "Building profile `{}` for node `{}` on remote host",
         data.deploy_data.profile_name, data.deploy_data.node_name
     );
 
-    // TODO: this should probably be handled more nicely during 'data' construction
-    let hostname = match data.deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &data.deploy_data.node.node_settings.hostname,
-    };
-    let store_address = format!("ssh-ng://{}@{}", data.deploy_defs.ssh_user, hostname);
+    let store_address = store_address("ssh-ng", data);
 
     let ssh_opts_str = data.deploy_data.merged_settings.ssh_opts.join(" ");
 
-
     // copy the derivation to remote host so it can be built there
-    let copy_command_status = Command::new("nix").arg("copy")
-        .arg("-s")  // fetch dependencies from substitures, not localhost
-        .arg("--to").arg(&store_address)
-        .arg("--derivation").arg(derivation_name)
+    let mut copy_command = crate::nix_command("nix");
+    copy_command
+        .arg("copy")
+        .arg("-s") // fetch dependencies from substitures, not localhost
+        .arg("--to")
+        .arg(&store_address)
+        .arg("--derivation")
+        .arg(derivation_name)
+        // e.g. substituters given with `--option` are needed for fetching the dependencies too
+        .args(build_args(data))
         .env("NIX_SSHOPTS", ssh_opts_str.clone())
-        .stdout(Stdio::null())
+        .stdout(Stdio::null());
+
+    let copy_command_status = copy_command
         .status()
         .await
         .map_err(PushProfileError::Copy)?;
 
     match copy_command_status.code() {
         Some(0) => (),
+        a if data.auto_verbose_on_failure => {
+            rerun_verbose(&mut copy_command, PushProfileError::CopyExit(a), true).await?
+        }
         a => return Err(PushProfileError::CopyExit(a)),
     };
 
-    let mut build_command = Command::new("nix");
+    let mut build_command = crate::nix_command("nix");
     build_command
-        .arg("build").arg(derivation_name)
-        .arg("--eval-store").arg("auto")
-        .arg("--store").arg(&store_address)
-        .args(data.extra_build_args)
+        .arg("build")
+        .arg(derivation_name)
+        .arg("--eval-store")
+        .arg("auto")
+        .arg("--store")
+        .arg(&store_address);
+
+    if data.keep_going {
+        build_command.arg("--keep-going");
+    }
+
+    build_command
+        .args(build_args(data))
+        .args(&data.deploy_data.merged_settings.remote_build_args)
         .env("NIX_SSHOPTS", ssh_opts_str.clone());
 
     debug!("build command: {:?}", build_command);
 
-    let build_exit_status = build_command
-        // Logging should be in stderr, this just stops the store path from printing for no reason
-        .stdout(Stdio::null())
-        .status()
-        .await
-        .map_err(PushProfileError::Build)?;
+    // Logging should be in stderr, this just stops the store path from printing for no reason
+    build_command.stdout(Stdio::null());
+
+    let build_exit_status = run_build(
+        &mut build_command,
+        data.deploy_data.merged_settings.build_timeout,
+    )
+    .await?;
 
     match build_exit_status.code() {
         Some(0) => (),
+        a if data.auto_verbose_on_failure => {
+            rerun_verbose(&mut build_command, PushProfileError::BuildExit(a), true).await?
+        }
         a => return Err(PushProfileError::BuildExit(a)),
     };
 
+    // The result only exists on the node, it has to be here for the kept link to be a GC root
+    if data.keep_result {
+        info!(
+            "Copying the result of profile `{}` back from node `{}`",
+            data.deploy_data.profile_name, data.deploy_data.node_name
+        );
+
+        // Paths built on the node are usually unsigned, like local builds
+        let copy_back_exit_status = nix_copy_command(
+            data,
+            "--from",
+            &store_address,
+            &data.deploy_data.profile.profile_settings.path,
+        )
+        .stdout(Stdio::null())
+        .status()
+        .await
+        .map_err(PushProfileError::CopyBack)?;
+
+        match copy_back_exit_status.code() {
+            Some(0) => (),
+            a => return Err(PushProfileError::CopyBackExit(a)),
+        };
+
+        let result_path = data.result_path.unwrap_or("./.deploy-gc");
+
+        let link_exit_status = crate::nix_command("nix")
+            .arg("build")
+            .arg(&data.deploy_data.profile.profile_settings.path)
+            .arg("--out-link")
+            .arg(format!(
+                "{}/{}/{}",
+                result_path, data.deploy_data.node_name, data.deploy_data.profile_name
+            ))
+            .stdout(Stdio::null())
+            .status()
+            .await
+            .map_err(PushProfileError::ResultLink)?;
+
+        match link_exit_status.code() {
+            Some(0) => (),
+            a => return Err(PushProfileError::ResultLinkExit(a)),
+        };
+    }
 
     Ok(())
 }
 
-pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
-    debug!(
-        "Finding the deriver of store path for {}",
-        &data.deploy_data.profile.profile_settings.path
-    );
+/// Subcommands showing a derivation, in order of preference: `nix show-derivation` is deprecated
+/// since Nix 2.15 in favour of `nix derivation show`, which older Nix doesn't have
+const SHOW_DERIVATION_SUBCOMMANDS: [&[&str]; 2] = [&["derivation", "show"], &["show-derivation"]];
 
-    // `nix-store --query --deriver` doesn't work on invalid paths, so we parse output of show-derivation :(
-    let mut show_derivation_command = Command::new("nix");
+/// Runs the first subcommand of `SHOW_DERIVATION_SUBCOMMANDS` that works with the installed Nix on
+/// `path`, returning its output
+async fn show_derivation(path: &str) -> Result<Vec<u8>, PushProfileError> {
+    let mut exit_code = None;
 
-    show_derivation_command
-        .arg("show-derivation")
-        .arg(&data.deploy_data.profile.profile_settings.path);
+    for (i, subcommand) in SHOW_DERIVATION_SUBCOMMANDS.iter().enumerate() {
+        let last = i + 1 == SHOW_DERIVATION_SUBCOMMANDS.len();
 
-    let show_derivation_output = show_derivation_command
-        .output()
-        .await
-        .map_err(PushProfileError::ShowDerivation)?;
+        let output = crate::nix_command("nix")
+            .arg("--experimental-features")
+            .arg("nix-command")
+            .args(*subcommand)
+            .arg(path)
+            // Only the errors of the last resort are of interest
+            .stderr(if last {
+                Stdio::inherit()
+            } else {
+                Stdio::piped()
+            })
+            .output()
+            .await
+            .map_err(PushProfileError::ShowDerivation)?;
 
-    match show_derivation_output.status.code() {
-        Some(0) => (),
-        a => return Err(PushProfileError::ShowDerivationExit(a)),
-    };
+        match output.status.code() {
+            Some(0) => return Ok(output.stdout),
+            a => {
+                debug!("nix {} failed with {:?}", subcommand.join(" "), a);
+                exit_code = a;
+            }
+        }
+    }
+
+    Err(PushProfileError::ShowDerivationExit(exit_code))
+}
 
+/// Gets the derivation path out of the JSON output of `show_derivation`
+fn parse_show_derivation(output: &[u8]) -> Result<String, PushProfileError> {
     let derivation_info: HashMap<&str, serde_json::value::Value> = serde_json::from_str(
-        std::str::from_utf8(&show_derivation_output.stdout)
-            .map_err(PushProfileError::ShowDerivationUtf8)?,
+        std::str::from_utf8(output).map_err(PushProfileError::ShowDerivationUtf8)?,
     )
     .map_err(PushProfileError::ShowDerivationParse)?;
 
-    let &deriver = derivation_info
+    derivation_info
         .keys()
         .next()
-        .ok_or(PushProfileError::ShowDerivationEmpty)?;
+        .map(|deriver| deriver.to_string())
+        .ok_or(PushProfileError::ShowDerivationEmpty)
+}
+
+#[test]
+fn test_parse_show_derivation() {
+    let drv = "/nix/store/8kd8ajk0bh8lrr7dp0anxw7jzq1h4zhb-activatable-nixos-system-web.drv";
+
+    // `nix show-derivation`
+    let show_derivation = br#"{"/nix/store/8kd8ajk0bh8lrr7dp0anxw7jzq1h4zhb-activatable-nixos-system-web.drv":{"args":["-e","builder.sh"],"builder":"/nix/store/bash","env":{},"inputDrvs":{},"inputSrcs":[],"outputs":{"out":{"path":"/nix/store/y6kkrqz1yrs9jpyqh7yscxbi5b2wm71z-activatable-nixos-system-web"}},"system":"x86_64-linux"}}"#;
+    // `nix derivation show`, which adds the name
+    let derivation_show = br#"{"/nix/store/8kd8ajk0bh8lrr7dp0anxw7jzq1h4zhb-activatable-nixos-system-web.drv":{"args":["-e","builder.sh"],"builder":"/nix/store/bash","env":{},"inputDrvs":{},"inputSrcs":[],"name":"activatable-nixos-system-web","outputs":{"out":{"path":"/nix/store/y6kkrqz1yrs9jpyqh7yscxbi5b2wm71z-activatable-nixos-system-web"}},"system":"x86_64-linux"}}"#;
+
+    assert_eq!(parse_show_derivation(show_derivation).unwrap(), drv);
+    assert_eq!(parse_show_derivation(derivation_show).unwrap(), drv);
+    assert!(matches!(
+        parse_show_derivation(b"{}"),
+        Err(PushProfileError::ShowDerivationEmpty)
+    ));
+    assert!(matches!(
+        parse_show_derivation(b"error"),
+        Err(PushProfileError::ShowDerivationParse(_))
+    ));
+}
+
+/// Finds the derivation (or derivation output, on newer Nix) to build the closure of the profile from
+async fn profile_deriver(data: &PushProfileData<'_>) -> Result<String, PushProfileError> {
+    debug!(
+        "Finding the deriver of store path for {}",
+        &data.deploy_data.profile.profile_settings.path
+    );
+
+    // `nix-store --query --deriver` doesn't work on invalid paths, so we parse output of show-derivation :(
+    let show_derivation_output =
+        show_derivation(&data.deploy_data.profile.profile_settings.path).await?;
+
+    let deriver = &parse_show_derivation(&show_derivation_output)?;
 
     let new_deriver = &if data.supports_flakes {
         // Since nix 2.15.0 'nix build <path>.drv' will build only the .drv file itself, not the
@@ -252,14 +656,17 @@ pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileE
         deriver.to_owned()
     };
 
-    let path_info_output = Command::new("nix")
-        .arg("--experimental-features").arg("nix-command")
+    let path_info_output = crate::nix_command("nix")
+        .arg("--experimental-features")
+        .arg("nix-command")
         .arg("path-info")
-        .arg(&deriver)
-        .output().await
+        .arg(deriver)
+        .output()
+        .await
         .map_err(PushProfileError::PathInfo)?;
 
-    let deriver = if std::str::from_utf8(&path_info_output.stdout).map(|s| s.trim()) == Ok(deriver) {
+    let deriver = if std::str::from_utf8(&path_info_output.stdout).map(|s| s.trim()) == Ok(deriver)
+    {
         // In this case we're on 2.15.0 or newer, because 'nix path-infonix path-info <...>.drv'
         // returns the same '<...>.drv' path.
         // If 'nix path-info <...>.drv' returns a different path, then we're on pre 2.15.0 nix and
@@ -274,9 +681,21 @@ pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileE
         // 'error: path '...' is not valid'.
         deriver
     };
-    if data.deploy_data.merged_settings.remote_build.unwrap_or(false) {
+
+    Ok(deriver.to_string())
+}
+
+pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
+    let deriver = profile_deriver(&data).await?;
+
+    if data
+        .deploy_data
+        .merged_settings
+        .remote_build
+        .unwrap_or(false)
+    {
         if !data.supports_flakes {
-            return Err(PushProfileError::RemoteBuildWithLegacyNix)
+            return Err(PushProfileError::RemoteBuildWithLegacyNix);
         }
 
         build_profile_remotely(&data, &deriver).await?;
@@ -287,55 +706,585 @@ pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileE
     Ok(())
 }
 
-pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
-    let ssh_opts_str = data
+/// Whether the profile can be built along with others by `build_profiles`. Remote builds happen on
+/// their node, and results kept with `--keep-result` need a link named after each profile.
+pub fn can_build_batched(data: &PushProfileData<'_>) -> bool {
+    data.supports_flakes
+        && !data.keep_result
+        && !data.deploy_data.merged_settings.remote_build.unwrap_or(false)
+        // The batch is built with the arguments given to `deploy` only
+        && data.deploy_data.merged_settings.extra_build_args.is_empty()
+}
+
+/// Builds the closures of several profiles with a single `nix build`, so that Nix only starts up
+/// once. Failed builds aren't re-run verbosely, as they can't be attributed to a profile here.
+pub async fn build_profiles(datas: &[PushProfileData<'_>]) -> Result<(), PushProfileError> {
+    let first = match datas.first() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    info!("Building {} profiles in a single Nix build", datas.len());
+
+    let mut build_command = crate::nix_command("nix");
+    build_command.arg("build");
+
+    for data in datas {
+        build_command.arg(profile_deriver(data).await?);
+    }
+
+    build_command.arg("--no-link");
+
+    if first.keep_going {
+        build_command.arg("--keep-going");
+    }
+
+    build_command.args(build_args(first));
+
+    // Logging should be in stderr, this just stops the store paths from printing for no reason
+    build_command.stdout(Stdio::null());
+
+    // The batch may take as long as the slowest profile is allowed to, unless one isn't limited
+    let build_timeout = datas
+        .iter()
+        .map(|data| data.deploy_data.merged_settings.build_timeout)
+        .collect::<Option<Vec<u16>>>()
+        .and_then(|timeouts| timeouts.into_iter().max());
+
+    let build_exit_status = run_build(&mut build_command, build_timeout).await?;
+
+    match build_exit_status.code() {
+        Some(0) => (),
+        a => return Err(PushProfileError::BuildExit(a)),
+    };
+
+    for data in datas {
+        check_built_profile(data).await?;
+    }
+
+    Ok(())
+}
+
+/// Arguments of `nix copy` for copying `path` to or from (`direction` is `--to` or `--from`) the
+/// store at `store_address`. Unsigned paths, e.g. local builds, are only accepted without `check_sigs`.
+fn copy_args(check_sigs: bool, direction: &str, store_address: &str, path: &str) -> Vec<String> {
+    let mut args = vec!["copy".to_string()];
+
+    if !check_sigs {
+        args.push("--no-check-sigs".to_string());
+    }
+
+    args.extend([
+        direction.to_string(),
+        store_address.to_string(),
+        path.to_string(),
+    ]);
+    args
+}
+
+#[test]
+fn test_copy_args() {
+    assert_eq!(
+        copy_args(false, "--to", "ssh://admin@web", "/nix/store/blah-etc"),
+        [
+            "copy",
+            "--no-check-sigs",
+            "--to",
+            "ssh://admin@web",
+            "/nix/store/blah-etc"
+        ]
+    );
+    assert_eq!(
+        copy_args(true, "--to", "ssh://admin@web", "/nix/store/blah-etc"),
+        ["copy", "--to", "ssh://admin@web", "/nix/store/blah-etc"]
+    );
+    assert_eq!(
+        copy_args(false, "--from", "ssh-ng://admin@web", "/nix/store/blah-etc"),
+        [
+            "copy",
+            "--no-check-sigs",
+            "--from",
+            "ssh-ng://admin@web",
+            "/nix/store/blah-etc"
+        ]
+    );
+}
+
+/// `nix copy` between the local store and the one of the node, see `copy_args`
+fn nix_copy_command(
+    data: &PushProfileData<'_>,
+    direction: &str,
+    store_address: &str,
+    path: &str,
+) -> Command {
+    let mut command = crate::nix_command("nix");
+    command
+        .args(copy_args(data.check_sigs, direction, store_address, path))
+        .env(
+            "NIX_SSHOPTS",
+            data.deploy_data.merged_settings.ssh_opts.join(" "),
+        );
+    command
+}
+
+/// Builds the `smokeTest` flake attribute of the profile and copies it to the target,
+/// returning the store path of the smoke test to run there
+pub async fn push_smoke_test(
+    data: &PushProfileData<'_>,
+    smoke_test: &str,
+) -> Result<String, PushProfileError> {
+    if !data.supports_flakes {
+        return Err(PushProfileError::SmokeTestWithLegacyNix);
+    }
+
+    let remote_build = data
         .deploy_data
         .merged_settings
-        .ssh_opts
-        // This should provide some extra safety, but it also breaks for some reason, oh well
-        // .iter()
-        // .map(|x| format!("'{}'", x))
-        // .collect::<Vec<String>>()
-        .join(" ");
+        .remote_build
+        .unwrap_or(false);
+
+    info!(
+        "Building smoke test `{}` for profile `{}` of node `{}`{}",
+        smoke_test,
+        data.deploy_data.profile_name,
+        data.deploy_data.node_name,
+        if remote_build { " on remote host" } else { "" }
+    );
+
+    let mut build_command = crate::nix_command("nix");
+    build_command
+        .arg("build")
+        .arg("--no-link")
+        .arg("--print-out-paths")
+        .arg(format!("{}#{}", data.repo, smoke_test))
+        .args(build_args(data));
+
+    // Like the profile, the smoke test is built on the node, where it is then already present
+    if remote_build {
+        build_command
+            .arg("--eval-store")
+            .arg("auto")
+            .arg("--store")
+            .arg(store_address("ssh-ng", data))
+            .args(&data.deploy_data.merged_settings.remote_build_args)
+            .env(
+                "NIX_SSHOPTS",
+                data.deploy_data.merged_settings.ssh_opts.join(" "),
+            );
+    }
+
+    let build_output = build_command
+        .stderr(Stdio::inherit())
+        .output()
+        .await
+        .map_err(PushProfileError::SmokeTestBuild)?;
+
+    match build_output.status.code() {
+        Some(0) => (),
+        a => return Err(PushProfileError::SmokeTestBuildExit(a)),
+    };
+
+    let smoke_test_path = String::from_utf8(build_output.stdout)
+        .map_err(PushProfileError::SmokeTestBuildUtf8)?
+        .trim()
+        .to_string();
+
+    // The node shares the store the smoke test was built in
+    if remote_build
+        || data
+            .deploy_data
+            .merged_settings
+            .local_deploy
+            .unwrap_or(false)
+    {
+        return Ok(smoke_test_path);
+    }
+
+    let copy_exit_status =
+        nix_copy_command(data, "--to", &store_address("ssh", data), &smoke_test_path)
+            .status()
+            .await
+            .map_err(PushProfileError::Copy)?;
+
+    match copy_exit_status.code() {
+        Some(0) => (),
+        a => return Err(PushProfileError::CopyExit(a)),
+    };
+
+    Ok(smoke_test_path)
+}
+
+/// Returns whether the profile closure is already fully present in the target's store
+pub async fn profile_is_present(data: &PushProfileData<'_>) -> Result<bool, PushProfileError> {
+    // The node shares the store the profile was built in
+    if data
+        .deploy_data
+        .merged_settings
+        .local_deploy
+        .unwrap_or(false)
+    {
+        return Ok(true);
+    }
+
+    let hostname = data.deploy_data.hostname();
+
+    // A closure is only registered as valid once all of its references are, so if the
+    // query succeeds there is nothing left to copy
+    let path_info_exit_status = crate::nix_command("nix")
+        .arg("--experimental-features")
+        .arg("nix-command")
+        .arg("path-info")
+        .arg("--store")
+        .arg(format!("ssh://{}@{}", data.deploy_defs.ssh_user, hostname))
+        .arg(&data.deploy_data.profile.profile_settings.path)
+        .env(
+            "NIX_SSHOPTS",
+            data.deploy_data.merged_settings.ssh_opts.join(" "),
+        )
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .map_err(PushProfileError::PathInfo)?;
+
+    Ok(path_info_exit_status.success())
+}
+
+/// Nix system name (e.g. `aarch64-linux`) of a machine, from the output of `uname -sm` on it
+fn uname_to_system(uname: &str) -> Option<String> {
+    let (kernel, machine) = uname.trim().split_once(' ')?;
+
+    let kernel = match kernel {
+        "Linux" => "linux",
+        "Darwin" => "darwin",
+        _ => return None,
+    };
+    let machine = match machine {
+        "arm64" => "aarch64",
+        "i386" | "i586" => "i686",
+        x => x,
+    };
+
+    Some(format!("{}-{}", machine, kernel))
+}
+
+#[test]
+fn test_uname_to_system() {
+    assert_eq!(
+        uname_to_system("Linux x86_64\n").as_deref(),
+        Some("x86_64-linux")
+    );
+    assert_eq!(
+        uname_to_system("Linux aarch64").as_deref(),
+        Some("aarch64-linux")
+    );
+    assert_eq!(
+        uname_to_system("Darwin arm64").as_deref(),
+        Some("aarch64-darwin")
+    );
+    assert_eq!(uname_to_system("FreeBSD amd64"), None);
+    assert_eq!(uname_to_system(""), None);
+}
+
+/// Finds the Nix system of the node the profile is deployed to
+pub async fn node_system(data: &PushProfileData<'_>) -> Result<String, PushProfileError> {
+    let ssh_addr = format!(
+        "{}@{}",
+        data.deploy_defs.ssh_user,
+        data.deploy_data.hostname()
+    );
 
+    let uname_output = crate::node_command(data.deploy_data, &ssh_addr)
+        .arg("uname -sm")
+        .stderr(Stdio::inherit())
+        .output()
+        .await
+        .map_err(PushProfileError::Uname)?;
+
+    match uname_output.status.code() {
+        Some(0) => (),
+        a => return Err(PushProfileError::UnameExit(a)),
+    };
+
+    let uname = String::from_utf8_lossy(&uname_output.stdout);
+    uname_to_system(&uname).ok_or_else(|| PushProfileError::UnknownSystem(uname.trim().to_string()))
+}
+
+/// Refuses to copy a profile built for another `system` than `node_system`, the one of its node,
+/// as it would only fail once activated
+pub fn check_system(data: &PushProfileData<'_>, node_system: &str) -> Result<(), PushProfileError> {
+    match data.deploy_data.profile.profile_settings.system {
+        Some(ref system) if system != node_system => Err(PushProfileError::SystemMismatch(
+            system.clone(),
+            node_system.to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Seconds between the progress messages of copying a closure
+const COPY_PROGRESS_INTERVAL: u64 = 5;
+
+/// Activity type of `nix copy` copying all the paths missing on the node, `actCopyPaths` in Nix
+const ACT_COPY_PATHS: u64 = 103;
+/// Result type reporting the progress of an activity, `resProgress` in Nix
+const RES_PROGRESS: u64 = 105;
+/// Verbosity of Nix messages which are warnings, `lvlWarn` in Nix
+const LVL_WARN: u64 = 1;
+
+/// What a line of `nix copy`'s `internal-json` log tells
+#[derive(Debug, PartialEq)]
+enum CopyLogLine {
+    /// Started copying the paths missing on the node, as the activity with the given id
+    CopyPaths(u64),
+    /// The activity with the given id is done with the first number of the second number of items
+    Progress(u64, u64, u64),
+    /// A message for the user along with its Nix verbosity, lines that aren't JSON are notices
+    Message(u64, String),
+    /// Any other activity
+    Other,
+}
+
+fn parse_copy_log_line(line: &str) -> CopyLogLine {
+    let json = match line.strip_prefix("@nix ") {
+        Some(x) => x,
+        None => return CopyLogLine::Message(2, line.to_string()),
+    };
+
+    let entry: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(json) {
+        Ok(x) => x,
+        Err(_) => return CopyLogLine::Message(2, line.to_string()),
+    };
+
+    let number = |key: &str| entry.get(key).and_then(|v| v.as_u64());
+    let field = |i: usize| {
+        entry
+            .get("fields")
+            .and_then(|f| f.get(i))
+            .and_then(|v| v.as_u64())
+    };
+
+    match entry.get("action").and_then(|a| a.as_str()) {
+        Some("msg") => CopyLogLine::Message(
+            number("level").unwrap_or(0),
+            entry
+                .get("msg")
+                .and_then(|m| m.as_str())
+                .unwrap_or_default()
+                .to_string(),
+        ),
+        Some("start") if number("type") == Some(ACT_COPY_PATHS) => match number("id") {
+            Some(id) => CopyLogLine::CopyPaths(id),
+            None => CopyLogLine::Other,
+        },
+        Some("result") if number("type") == Some(RES_PROGRESS) => {
+            match (number("id"), field(0), field(1)) {
+                (Some(id), Some(done), Some(expected)) => CopyLogLine::Progress(id, done, expected),
+                _ => CopyLogLine::Other,
+            }
+        }
+        _ => CopyLogLine::Other,
+    }
+}
+
+#[test]
+fn test_parse_copy_log_line() {
+    assert_eq!(
+        parse_copy_log_line(
+            r#"@nix {"action":"start","id":42,"level":3,"parent":0,"text":"copying 130 paths","type":103,"fields":[]}"#
+        ),
+        CopyLogLine::CopyPaths(42)
+    );
+    assert_eq!(
+        parse_copy_log_line(r#"@nix {"action":"result","id":42,"type":105,"fields":[12,130,1,0]}"#),
+        CopyLogLine::Progress(42, 12, 130)
+    );
+    assert_eq!(
+        parse_copy_log_line(
+            r#"@nix {"action":"msg","level":1,"msg":"warning: ignoring untrusted substituter"}"#
+        ),
+        CopyLogLine::Message(1, "warning: ignoring untrusted substituter".to_string())
+    );
+    assert_eq!(
+        parse_copy_log_line(
+            r#"@nix {"action":"start","id":43,"level":4,"parent":42,"text":"copying path '/nix/store/blah-etc'","type":100,"fields":["/nix/store/blah-etc","local","ssh://admin@web"]}"#
+        ),
+        CopyLogLine::Other
+    );
+    assert_eq!(
+        parse_copy_log_line("Warning: Permanently added 'web' to the list of known hosts."),
+        CopyLogLine::Message(
+            2,
+            "Warning: Permanently added 'web' to the list of known hosts.".to_string()
+        )
+    );
+}
+
+/// Runs `nix copy` with an `internal-json` log, logging how many paths it copied now and then
+/// along with its warnings. Returns its exit status and the messages it printed to stderr, which
+/// are otherwise only logged as debug messages.
+async fn run_copy(
+    copy_command: &mut Command,
+    node_name: &str,
+) -> Result<(std::process::ExitStatus, String), std::io::Error> {
+    use tokio::io::AsyncBufReadExt;
+
+    let mut copy_child = copy_command
+        .arg("--log-format")
+        .arg("internal-json")
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut lines = match copy_child.stderr.take() {
+        Some(stderr) => tokio::io::BufReader::new(stderr).lines(),
+        None => return Ok((copy_child.wait().await?, String::new())),
+    };
+
+    let mut stderr = String::new();
+    let mut copy_paths = None;
+    let mut last_done = 0;
+    let mut last_progress = std::time::Instant::now();
+
+    while let Some(line) = lines.next_line().await? {
+        match parse_copy_log_line(&line) {
+            CopyLogLine::CopyPaths(id) => copy_paths = Some(id),
+            CopyLogLine::Progress(id, done, expected)
+                if Some(id) == copy_paths
+                    && done > last_done
+                    && (done == expected
+                        || last_progress.elapsed().as_secs() >= COPY_PROGRESS_INTERVAL) =>
+            {
+                info!("Copied {}/{} paths to node `{}`", done, expected, node_name);
+                last_done = done;
+                last_progress = std::time::Instant::now();
+            }
+            CopyLogLine::Message(level, msg) => {
+                // Errors are shown along with the failure
+                if level == LVL_WARN {
+                    warn!("{}", msg);
+                } else {
+                    debug!("{}", msg);
+                }
+
+                stderr.push_str(&msg);
+                stderr.push('\n');
+            }
+            _ => (),
+        }
+    }
+
+    Ok((copy_child.wait().await?, stderr))
+}
+
+/// Whether the stderr of `nix copy` tells that the node refused a path for missing a signature
+/// it trusts, which happens when the SSH user isn't trusted by the node's Nix daemon
+fn lacks_trusted_signature(stderr: &[u8]) -> bool {
+    String::from_utf8_lossy(stderr).contains("lacks a signature by a trusted key")
+}
+
+pub async fn push_profile(
+    data: PushProfileData<'_>,
+) -> Result<PushProfileOutcome, PushProfileError> {
     // remote building guarantees that the resulting derivation is stored on the target system
     // no need to copy after building
-    if !data.deploy_data.merged_settings.remote_build.unwrap_or(false) {
+    if !data
+        .deploy_data
+        .merged_settings
+        .remote_build
+        .unwrap_or(false)
+    {
+        let store_address = store_address("ssh", &data);
+
+        if data.check_present && profile_is_present(&data).await? {
+            info!(
+                "Profile `{}` is already present on node `{}`, not copying",
+                data.deploy_data.profile_name, data.deploy_data.node_name
+            );
+
+            return Ok(PushProfileOutcome::AlreadyPresent);
+        }
+
         info!(
             "Copying profile `{}` to node `{}`",
             data.deploy_data.profile_name, data.deploy_data.node_name
         );
 
-        let mut copy_command = Command::new("nix");
-        copy_command.arg("copy");
-
-        if data.deploy_data.merged_settings.fast_connection != Some(true) {
-            copy_command.arg("--substitute-on-destination");
-        }
+        // Every attempt gets a fresh command, as `run_copy` adds its log format to it
+        let copy_command = || {
+            let mut copy_command = nix_copy_command(
+                &data,
+                "--to",
+                &store_address,
+                &data.deploy_data.profile.profile_settings.path,
+            );
 
-        if !data.check_sigs {
-            copy_command.arg("--no-check-sigs");
-        }
+            if data.deploy_data.merged_settings.fast_connection != Some(true) {
+                copy_command.arg("--substitute-on-destination");
+            }
 
-        let hostname = match data.deploy_data.cmd_overrides.hostname {
-            Some(ref x) => x,
-            None => &data.deploy_data.node.node_settings.hostname,
+            copy_command
         };
 
-        let copy_exit_status = copy_command
-            .arg("--to")
-            .arg(format!("ssh://{}@{}", data.deploy_defs.ssh_user, hostname))
-            .arg(&data.deploy_data.profile.profile_settings.path)
-            .env("NIX_SSHOPTS", ssh_opts_str)
-            .status()
-            .await
-            .map_err(PushProfileError::Copy)?;
+        let copy_retries = data.deploy_data.cmd_overrides.copy_retries.unwrap_or(0);
+        let copy_retry_delay = data
+            .deploy_data
+            .cmd_overrides
+            .copy_retry_delay
+            .unwrap_or(crate::DEFAULT_COPY_RETRY_DELAY);
 
-        match copy_exit_status.code() {
-            Some(0) => (),
-            a => return Err(PushProfileError::CopyExit(a)),
-        };
+        let mut attempt: u16 = 0;
+
+        loop {
+            let copy_err = match run_copy(&mut copy_command(), data.deploy_data.node_name).await {
+                Ok((status, stderr)) => {
+                    // The messages are only shown along with debug logs, unless something went wrong
+                    if !status.success() && !log::log_enabled!(log::Level::Debug) {
+                        let _ =
+                            std::io::Write::write_all(&mut std::io::stderr(), stderr.as_bytes());
+                    }
+
+                    match status.code() {
+                        Some(0) => break,
+                        _ if lacks_trusted_signature(stderr.as_bytes()) => {
+                            PushProfileError::UnsignedPath(
+                                data.deploy_data.profile.profile_settings.path.clone(),
+                            )
+                        }
+                        a => PushProfileError::CopyExit(a),
+                    }
+                }
+                Err(e) => PushProfileError::Copy(e),
+            };
+
+            // Retrying can't make the node trust the path
+            if attempt < copy_retries && !matches!(copy_err, PushProfileError::UnsignedPath(_)) {
+                // Exponential backoff, the exponent is capped so the delay can't overflow
+                let delay = u64::from(copy_retry_delay) << attempt.min(16);
+
+                attempt += 1;
+                warn!(
+                    "Copying profile `{}` to node `{}` failed: {}. Retrying in {}s (attempt {} of {})",
+                    data.deploy_data.profile_name,
+                    data.deploy_data.node_name,
+                    copy_err,
+                    delay,
+                    attempt,
+                    copy_retries
+                );
+
+                tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+                continue;
+            }
+
+            match copy_err {
+                PushProfileError::CopyExit(_) if data.auto_verbose_on_failure => {
+                    rerun_verbose(&mut copy_command(), copy_err, true).await?;
+                    break;
+                }
+                e => return Err(e),
+            }
+        }
     }
 
-    Ok(())
+    Ok(PushProfileOutcome::Copied)
 }